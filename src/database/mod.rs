@@ -211,5 +211,57 @@ pub fn create_table() -> Result<()> {
     for s in sql.split(';').filter(|s| !s.trim().is_empty()) {
         conn.query_drop(s)?
     }
+    for &(table, column, definition) in ADD_COLUMNS {
+        let exist: Option<i32> = conn.exec_first(
+            "SELECT 1 FROM information_schema.COLUMNS
+            WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND COLUMN_NAME = ? LIMIT 1",
+            (table, column),
+        )?;
+        if exist.is_none() {
+            log!("为 {table} 添加列 {column}");
+            conn.query_drop(format!("ALTER TABLE {table} ADD COLUMN `{column}` {definition}"))?;
+        }
+    }
+    for &(table, index, definition) in ADD_INDEXES {
+        let exist: Option<i32> = conn.exec_first(
+            "SELECT 1 FROM information_schema.STATISTICS
+            WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND INDEX_NAME = ? LIMIT 1",
+            (table, index),
+        )?;
+        if exist.is_none() {
+            log!("为 {table} 添加索引 {index}");
+            // 已有重复数据时添加唯一索引会失败，不影响启动，清理后下次启动再添加
+            if let Err(e) = conn.query_drop(format!("ALTER TABLE {table} ADD {definition}")) {
+                log!("为 {table} 添加索引 {index} 失败，错误信息：{e}");
+            }
+        }
+    }
     Ok(())
 }
+
+/// 之后新增的索引，和ADD_COLUMNS一样在启动时补上
+const ADD_INDEXES: &[(&str, &str, &str)] = &[(
+    "appointment",
+    "series_time",
+    "UNIQUE KEY series_time (series, appointment)",
+)];
+
+/// 之后新增的列，已经存在的表不会被 CREATE TABLE IF NOT EXISTS 修改，启动时补上
+const ADD_COLUMNS: &[(&str, &str, &str)] = &[
+    ("extra_customer_data", "push_to_sea_date", "VARCHAR(25) NULL"),
+    ("extra_customer_data", "pop_from_sea_date", "VARCHAR(25) NULL"),
+    ("customer_share", "appoint", "INT NOT NULL DEFAULT 0"),
+    ("customer_share", "order", "INT NOT NULL DEFAULT 0"),
+    ("customer_share", "create_time", "VARCHAR(25) NOT NULL DEFAULT ''"),
+    ("product", "tracking", "INT NOT NULL DEFAULT 0"),
+    ("product", "parent", "VARCHAR(150) NULL"),
+    ("product", "attrs", "TEXT NULL"),
+    ("product", "bundle", "INT NOT NULL DEFAULT 0"),
+    ("appointment", "notify", "INT NOT NULL DEFAULT 0"),
+    ("appointment", "sequence", "INT NOT NULL DEFAULT 0"),
+    ("customer", "latitude", "DOUBLE NULL"),
+    ("customer", "longitude", "DOUBLE NULL"),
+    ("appointment", "series", "VARCHAR(150) NULL"),
+    ("appointment", "files", "TEXT NULL"),
+    ("appoint_comment", "files", "TEXT NULL"),
+];
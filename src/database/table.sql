@@ -127,12 +127,20 @@ CREATE TABLE IF NOT EXISTS customer (
     ty VARCHAR(30),
     -- 客户标签
     tag VARCHAR(30),
+    -- 客户位置，用于计算签到距离
+    latitude DOUBLE NULL,
+    longitude DOUBLE NULL,
     PRIMARY KEY (id)
 );
--- 客户共享，先不管，不一定会写
+-- 客户共享，被共享者拥有查看权限
 create table if not exists customer_share (
     customer varchar(150) not null,
     share_salesman varchar(150) not null,
+    -- 是否允许被共享者添加拜访
+    appoint int not null default 0,
+    -- 是否允许被共享者添加订单
+    `order` int not null default 0,
+    create_time varchar(25) not null default '',
     primary key (customer, share_salesman)
 );
 -- 客户额外的信息
@@ -147,11 +155,56 @@ CREATE TABLE IF NOT EXISTS extra_customer_data (
     -- visited_count INT NOT NULL,
     -- 上次成交时间 暂时的值，后面需要用联合查询替换，历史遗留
     last_transaction_time VARCHAR(25) NULL,
+    -- 最近一次被移入公海的时间
+    push_to_sea_date VARCHAR(25) NULL,
+    -- 最近一次从公海领取的时间
+    pop_from_sea_date VARCHAR(25) NULL,
     PRIMARY KEY (id),
     FOREIGN KEY (id) REFERENCES customer(id),
     FOREIGN KEY (salesman) REFERENCES user(id)
 );
 
+-- 公海客户，存在记录表示该客户当前处于公海中
+CREATE TABLE IF NOT EXISTS customer_sea (
+    id VARCHAR(150) NOT NULL,
+    -- 移入公海前的负责人
+    salesman VARCHAR(150) NULL,
+    push_to_sea_date VARCHAR(25) NOT NULL,
+    PRIMARY KEY (id)
+);
+
+-- 客户转移记录
+CREATE TABLE IF NOT EXISTS customer_transfer (
+    id VARCHAR(150) NOT NULL,
+    customer VARCHAR(150) NOT NULL,
+    -- 原负责人
+    from_salesman VARCHAR(150) NOT NULL,
+    -- 新负责人
+    to_salesman VARCHAR(150) NOT NULL,
+    -- 操作人
+    operator VARCHAR(150) NOT NULL,
+    transfer_time VARCHAR(25) NOT NULL,
+    -- 是否同时转移了未完成的拜访
+    appointment INT NOT NULL,
+    -- 是否同时转移了意向订单
+    `order` INT NOT NULL,
+    remark TEXT NOT NULL,
+    PRIMARY KEY (id)
+);
+
+-- 客户信息修改记录
+CREATE TABLE IF NOT EXISTS customer_change_log (
+    id VARCHAR(150) NOT NULL,
+    customer VARCHAR(150) NOT NULL,
+    operator VARCHAR(150) NOT NULL,
+    -- 客户表的字段名，自定义字段为其显示文本
+    field VARCHAR(30) NOT NULL,
+    old_value TEXT NOT NULL,
+    new_value TEXT NOT NULL,
+    change_time VARCHAR(25) NOT NULL,
+    PRIMARY KEY (id)
+);
+
 -- 客户同事表
 CREATE TABLE IF NOT EXISTS customer_colleague(
     id VARCHAR(150) NOT NULL,
@@ -172,7 +225,24 @@ CREATE TABLE IF NOT EXISTS appointment(
     finish_time VARCHAR(25),
     theme VARCHAR(30),
     content TEXT,
-    PRIMARY KEY (id)
+    -- 是否需要拜访提醒
+    notify INT NOT NULL DEFAULT 0,
+    -- 每次修改或完成拜访时加一，日历订阅中用于通知客户端更新
+    sequence INT NOT NULL DEFAULT 0,
+    -- 所属的重复拜访
+    series VARCHAR(150) NULL,
+    -- 附件链接，以&分隔
+    files TEXT NULL,
+    PRIMARY KEY (id),
+    -- 同一个重复拜访的同一时间只生成一次
+    UNIQUE KEY series_time (series, appointment)
+);
+-- 已经发送过的拜访提醒，minutes为提前的分钟数，防止重复提醒
+CREATE TABLE IF NOT EXISTS appoint_notify (
+    appoint VARCHAR(150) NOT NULL,
+    receiver VARCHAR(150) NOT NULL,
+    minutes INT NOT NULL,
+    PRIMARY KEY (appoint, receiver, minutes)
 );
 -- 预约评论，不用管
 CREATE TABLE IF NOT EXISTS appoint_comment (
@@ -181,6 +251,8 @@ CREATE TABLE IF NOT EXISTS appoint_comment (
     appoint VARCHAR(150) NOT NULL,
     create_time VARCHAR(25) NOT NULL,
     comment TEXT,
+    -- 附件链接，以&分隔
+    files TEXT NULL,
     PRIMARY KEY (id)
 );
 
@@ -213,9 +285,17 @@ CREATE TABLE IF NOT EXISTS product(
     barcode VARCHAR(50) NOT NULL,
     explanation TEXT,
     purchase_price FLOAT NOT NULL,
+    -- 0 不追踪，1 按批次追踪，2 按序列号追踪
+    tracking INT NOT NULL DEFAULT 0,
+    -- 规格产品所属的产品，订单和库存都使用规格产品的id
+    parent VARCHAR(150) NULL,
+    -- 规格产品的属性，例如[["尺码","M"],["颜色","红"]]
+    attrs TEXT NULL,
+    -- 1 组合产品，没有自己的库存，由product_bundle中的组件组成
+    bundle INT NOT NULL DEFAULT 0,
     PRIMARY KEY (id)
 );
--- 产品库存，之后会调整
+-- 产品库存，amount为stock_movement中delta之和，只能通过库存流水修改
 CREATE TABLE IF NOT EXISTS product_store(
     product VARCHAR(150) NOT NULL,
     storehouse VARCHAR(30) NOT NULL,
@@ -342,3 +422,224 @@ create table if not exists supper(
     PRIMARY KEY (id)
 );
 
+
+-- 拼音搜索索引，ty为customer、product或user，field为被索引的字段
+create table if not exists search_index(
+    ty VARCHAR(10) NOT NULL,
+    id VARCHAR(150) NOT NULL,
+    field VARCHAR(20) NOT NULL,
+    text VARCHAR(100) NOT NULL,
+    pinyin VARCHAR(600) NOT NULL,
+    initials VARCHAR(100) NOT NULL,
+    PRIMARY KEY (ty, id, field)
+);
+
+-- 站内通知
+create table if not exists notification(
+    id VARCHAR(150) NOT NULL,
+    receiver VARCHAR(150) NOT NULL,
+    -- appointment 拜访提醒
+    ty VARCHAR(30) NOT NULL,
+    title VARCHAR(100) NOT NULL,
+    content TEXT NOT NULL,
+    -- 关联数据的id，例如拜访id
+    link VARCHAR(150) NOT NULL,
+    create_time VARCHAR(25) NOT NULL,
+    -- 为NULL时表示未读
+    read_time VARCHAR(25) NULL,
+    PRIMARY KEY (id)
+);
+
+-- 日历订阅链接的token，每个用户一个
+CREATE TABLE IF NOT EXISTS calendar_token (
+    user VARCHAR(150) NOT NULL,
+    token VARCHAR(50) NOT NULL UNIQUE,
+    create_time VARCHAR(25) NOT NULL,
+    PRIMARY KEY (user)
+);
+
+-- 拜访签到记录
+CREATE TABLE IF NOT EXISTS sign_in (
+    id VARCHAR(150) NOT NULL,
+    appoint VARCHAR(150) NOT NULL,
+    signer VARCHAR(150) NOT NULL,
+    -- 0 签到，1 签退
+    ty INT NOT NULL,
+    latitude DOUBLE NOT NULL,
+    longitude DOUBLE NOT NULL,
+    address VARCHAR(150) NOT NULL,
+    -- 与客户位置的距离，单位为米，客户没有位置时为NULL
+    distance DOUBLE NULL,
+    -- 照片链接，以&分隔
+    photos TEXT NULL,
+    sign_time VARCHAR(25) NOT NULL,
+    PRIMARY KEY (id),
+    UNIQUE (appoint, ty)
+);
+
+-- 重复拜访规则，拜访按需生成到appointment中
+CREATE TABLE IF NOT EXISTS appoint_series (
+    id VARCHAR(150) NOT NULL,
+    applicant VARCHAR(150) NOT NULL,
+    salesman VARCHAR(150) NOT NULL,
+    customer VARCHAR(150) NOT NULL,
+    -- 第一次拜访的时间
+    start_time VARCHAR(25) NOT NULL,
+    -- daily、weekly、monthly
+    freq VARCHAR(10) NOT NULL,
+    -- 间隔，例如每2周
+    ival INT NOT NULL,
+    until_time VARCHAR(25) NULL,
+    count INT NULL,
+    -- 已经生成的次数
+    generated INT NOT NULL,
+    -- 下一次需要生成的拜访时间，为NULL时表示已经全部生成
+    next_time VARCHAR(25) NULL,
+    theme VARCHAR(30) NOT NULL,
+    content TEXT NOT NULL,
+    notify INT NOT NULL,
+    create_time VARCHAR(25) NOT NULL,
+    PRIMARY KEY (id)
+);
+
+-- 库存流水，只追加不修改
+CREATE TABLE IF NOT EXISTS stock_movement (
+    id VARCHAR(150) NOT NULL,
+    product VARCHAR(150) NOT NULL,
+    storehouse VARCHAR(30) NOT NULL,
+    delta INT NOT NULL,
+    -- 变动后的库存
+    balance INT NOT NULL,
+    -- manual、shipment、receipt、transfer、stocktake、opening
+    reason VARCHAR(20) NOT NULL,
+    -- 对应单据的id，例如订单id
+    source VARCHAR(150) NOT NULL,
+    operator VARCHAR(150) NOT NULL,
+    create_time VARCHAR(25) NOT NULL,
+    PRIMARY KEY (id)
+);
+
+-- 库房调拨单
+CREATE TABLE IF NOT EXISTS stock_transfer (
+    id VARCHAR(150) NOT NULL,
+    -- 调出库房
+    source VARCHAR(30) NOT NULL,
+    -- 调入库房
+    target VARCHAR(30) NOT NULL,
+    -- 0 草稿，1 已发出，2 已收货
+    status INT NOT NULL,
+    applicant VARCHAR(150) NOT NULL,
+    remark TEXT NOT NULL,
+    create_time VARCHAR(25) NOT NULL,
+    shipper VARCHAR(150) NULL,
+    ship_time VARCHAR(25) NULL,
+    receiver VARCHAR(150) NULL,
+    receive_time VARCHAR(25) NULL,
+    PRIMARY KEY (id)
+);
+
+-- 调拨单中的产品
+CREATE TABLE IF NOT EXISTS stock_transfer_product (
+    transfer VARCHAR(150) NOT NULL,
+    product VARCHAR(150) NOT NULL,
+    amount INT NOT NULL,
+    PRIMARY KEY (transfer, product)
+);
+
+-- 库存盘点单
+CREATE TABLE IF NOT EXISTS stocktake (
+    id VARCHAR(150) NOT NULL,
+    storehouse VARCHAR(30) NOT NULL,
+    -- 0 盘点中，1 已审核
+    status INT NOT NULL,
+    applicant VARCHAR(150) NOT NULL,
+    remark TEXT NOT NULL,
+    create_time VARCHAR(25) NOT NULL,
+    approver VARCHAR(150) NULL,
+    approve_time VARCHAR(25) NULL,
+    PRIMARY KEY (id)
+);
+
+-- 盘点明细，expected为创建盘点单时的库存快照
+CREATE TABLE IF NOT EXISTS stocktake_item (
+    stocktake VARCHAR(150) NOT NULL,
+    product VARCHAR(150) NOT NULL,
+    expected INT NOT NULL,
+    -- 实盘数量，为NULL时表示还没有盘点
+    counted INT NULL,
+    PRIMARY KEY (stocktake, product)
+);
+
+-- 库存上下限，storehouse为空时表示该产品在所有库房中的默认值
+CREATE TABLE IF NOT EXISTS stock_level (
+    product VARCHAR(150) NOT NULL,
+    storehouse VARCHAR(30) NOT NULL,
+    min_amount INT NULL,
+    max_amount INT NULL,
+    PRIMARY KEY (product, storehouse)
+);
+
+-- 已经发送过库存不足提醒的产品，库存恢复后删除
+CREATE TABLE IF NOT EXISTS stock_alert (
+    product VARCHAR(150) NOT NULL,
+    storehouse VARCHAR(30) NOT NULL,
+    PRIMARY KEY (product, storehouse)
+);
+
+-- 产品的供应商
+CREATE TABLE IF NOT EXISTS product_supplier (
+    product VARCHAR(150) NOT NULL,
+    supper VARCHAR(150) NOT NULL,
+    PRIMARY KEY (product, supper)
+);
+
+-- 在库的批次或者序列号，序列号的amount为1
+CREATE TABLE IF NOT EXISTS stock_lot (
+    product VARCHAR(150) NOT NULL,
+    storehouse VARCHAR(30) NOT NULL,
+    lot VARCHAR(100) NOT NULL,
+    amount INT NOT NULL,
+    expiry VARCHAR(25) NULL,
+    receive_time VARCHAR(25) NOT NULL,
+    PRIMARY KEY (product, storehouse, lot)
+);
+
+-- 单据出库的批次或者序列号，doc为订单、调拨单或者盘点单的id
+CREATE TABLE IF NOT EXISTS document_lot (
+    doc VARCHAR(150) NOT NULL,
+    product VARCHAR(150) NOT NULL,
+    storehouse VARCHAR(30) NOT NULL,
+    lot VARCHAR(100) NOT NULL,
+    amount INT NOT NULL,
+    expiry VARCHAR(25) NULL,
+    PRIMARY KEY (doc, product, lot)
+);
+
+-- 产品的规格属性，vals为属性值的json数组
+CREATE TABLE IF NOT EXISTS product_attr (
+    product VARCHAR(150) NOT NULL,
+    name VARCHAR(30) NOT NULL,
+    vals TEXT NOT NULL,
+    sort INT NOT NULL,
+    PRIMARY KEY (product, name)
+);
+
+-- 价格表，ty为level时scope为客户级别，为customer时scope为客户id，为空时对所有客户生效
+-- 同一产品同一范围可按起订量设置多个价格
+CREATE TABLE IF NOT EXISTS price_list (
+    product VARCHAR(150) NOT NULL,
+    ty VARCHAR(20) NOT NULL,
+    scope VARCHAR(150) NOT NULL,
+    min_amount INT NOT NULL,
+    price FLOAT NOT NULL,
+    create_time VARCHAR(25) NOT NULL,
+    PRIMARY KEY (product, ty, scope, min_amount)
+);
+
+-- 组合产品的组件，amount为一个组合产品包含的组件数量
+CREATE TABLE IF NOT EXISTS product_bundle (
+    bundle VARCHAR(150) NOT NULL,
+    product VARCHAR(150) NOT NULL,
+    amount INT NOT NULL,
+    PRIMARY KEY (bundle, product)
+);
@@ -61,8 +61,60 @@ pub struct ID {
 }
 
 pub static mut MYSQL_URI: String = String::new();
+/// 公海规则，超过该天数未成交的客户将被移入公海
 pub static mut SEA_MAX_DAY: u64 = 3;
+/// 公海规则，超过该天数未拜访的客户将被移入公海
 pub static mut SEA_MIN_DAY: u64 = 3;
+pub fn set_sea_day(max_day: u64, min_day: u64) -> std::io::Result<()> {
+    unsafe {
+        SEA_MAX_DAY = max_day;
+        SEA_MIN_DAY = min_day;
+    }
+    std::fs::write("data/sea", format!("{max_day}-{min_day}").as_bytes())
+}
+/// 拜访提醒，在拜访开始前多少分钟提醒，默认提前1天和1小时
+pub static mut NOTIFY_OFFSETS: Vec<u64> = Vec::new();
+pub fn get_notify_offsets() -> Vec<u64> {
+    unsafe { (*std::ptr::addr_of!(NOTIFY_OFFSETS)).clone() }
+}
+pub fn set_notify_offsets(mut offsets: Vec<u64>) -> std::io::Result<()> {
+    offsets.sort_unstable();
+    offsets.dedup();
+    let data: Vec<String> = offsets.iter().map(u64::to_string).collect();
+    unsafe {
+        NOTIFY_OFFSETS = offsets;
+    }
+    std::fs::write("data/notify", data.join(",").as_bytes())
+}
+/// 默认的拜访时长，单位为分钟，用于检测拜访时间冲突
+pub static mut VISIT_DURATION: u64 = 60;
+/// 拜访时长的上限，一天
+pub const MAX_VISIT_DURATION: u64 = 24 * 60;
+pub fn set_visit_duration(minutes: u64) -> std::io::Result<()> {
+    unsafe {
+        VISIT_DURATION = minutes;
+    }
+    std::fs::write("data/visit_duration", minutes.to_string().as_bytes())
+}
+/// 是否允许库存为负数，默认不允许，库存不足时无法发货
+pub static mut ALLOW_NEGATIVE_STOCK: bool = false;
+pub fn set_allow_negative_stock(allow: bool) -> std::io::Result<()> {
+    unsafe {
+        ALLOW_NEGATIVE_STOCK = allow;
+    }
+    std::fs::write("data/negative_stock", allow.to_string().as_bytes())
+}
+/// 订单的单价是否必须不低于价格表中的价格，默认只提示不强制
+pub static mut ENFORCE_LIST_PRICE: bool = false;
+/// 最低毛利率，低于该值的订单产品会出现在毛利报表中
+pub static mut MIN_MARGIN: f32 = 0.0;
+pub fn set_price_rule(enforce: bool, min_margin: f32) -> std::io::Result<()> {
+    unsafe {
+        ENFORCE_LIST_PRICE = enforce;
+        MIN_MARGIN = min_margin;
+    }
+    std::fs::write("data/price_rule", format!("{enforce},{min_margin}").as_bytes())
+}
 /// 提成
 pub static mut COMMISSION: i32 = -1;
 pub fn get_commission() -> std::io::Result<i32> {
@@ -114,6 +166,9 @@ lazy_static::lazy_static! {
 pub struct Config {
     port: u16,
     mysql: MYSQL,
+    /// 前端页面的地址，例如 https://crm.example.com ，用于在日历等外部应用中链接到客户
+    #[serde(default)]
+    frontend: String,
 }
 
 impl Default for Config {
@@ -127,6 +182,7 @@ impl Default for Config {
                 port: 3306,
                 database: "crm".to_owned(),
             },
+            frontend: String::new(),
         }
     }
 }
@@ -157,6 +213,9 @@ impl Config {
     pub fn mysql_addr(&self) -> String {
         self.mysql.uri()
     }
+    pub fn frontend(&self) -> &str {
+        self.frontend.trim_end_matches('/')
+    }
 }
 pub fn read_data() {
     use std::fs::read_to_string;
@@ -169,4 +228,32 @@ pub fn read_data() {
             SEA_MIN_DAY = min_day;
         }
     }
+    let offsets = match read_to_string("data/notify") {
+        Ok(v) => v.split(',').filter_map(|s| s.trim().parse().ok()).collect(),
+        Err(_) => vec![60, 1440],
+    };
+    unsafe {
+        NOTIFY_OFFSETS = offsets;
+    }
+    if let Some(minutes) = read_to_string("data/visit_duration")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|m| (1..=MAX_VISIT_DURATION).contains(m))
+    {
+        unsafe {
+            VISIT_DURATION = minutes;
+        }
+    }
+    if let Ok(allow) = read_to_string("data/negative_stock") {
+        unsafe {
+            ALLOW_NEGATIVE_STOCK = allow.trim().eq("true");
+        }
+    }
+    if let Ok(rule) = read_to_string("data/price_rule") {
+        let v: Vec<&str> = rule.trim().splitn(2, ',').collect();
+        unsafe {
+            ENFORCE_LIST_PRICE = v.first().is_some_and(|s| s.eq(&"true"));
+            MIN_MARGIN = v.get(1).and_then(|s| s.parse().ok()).unwrap_or(0.0);
+        }
+    }
 }
@@ -2,6 +2,7 @@ pub mod cache;
 pub mod dser;
 pub mod headers;
 pub mod lazy;
+pub mod pinyin;
 pub mod time;
 pub use dser::deserialize_any_to_bool;
 use axum::extract::Multipart;
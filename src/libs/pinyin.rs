@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+
+lazy_static::lazy_static! {
+    /// GB2312一级汉字的拼音表，每行为`拼音 汉字...`，多音字只取常用读音
+    static ref PINYIN_TABLE: HashMap<char, &'static str> = include_str!("./pinyin.txt")
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .flat_map(|(py, chars)| chars.chars().map(move |c| (c, py)))
+        .collect();
+}
+
+/// 将文本转换成(全拼, 首字母)，均为小写，字母和数字原样保留，其他符号忽略。
+/// 拼音表中不存在的汉字只能得到首字母，全拼中也使用首字母代替
+pub fn to_pinyin(text: &str) -> (String, String) {
+    let mut full = String::new();
+    let mut initials = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() {
+            full.push(c.to_ascii_lowercase());
+            initials.push(c.to_ascii_lowercase());
+        } else if let Some(py) = PINYIN_TABLE.get(&c) {
+            full.push_str(py);
+            initials.push_str(&py[..1]);
+        } else if !c.is_ascii() {
+            let py = rust_pinyin::get_pinyin(&c.to_string());
+            full.push_str(&py);
+            initials.push_str(&py);
+        }
+    }
+    (full, initials)
+}
+
+#[test]
+fn test_to_pinyin() {
+    assert_eq!(to_pinyin("张三"), ("zhangsan".into(), "zs".into()));
+    assert_eq!(
+        to_pinyin("北京ABC-123"),
+        ("beijingabc123".into(), "bjabc123".into())
+    );
+    assert_eq!(to_pinyin("吕布"), ("lvbu".into(), "lb".into()));
+}
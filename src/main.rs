@@ -4,7 +4,7 @@ use axum::{extract::DefaultBodyLimit, http::Method, Router};
 use crm_rust::{
     database::__get_conn,
     libs::cache::clear_cache,
-    pages::{DROP_DOWN_BOX, STATIC_CUSTOM_BOX_OPTIONS, STATIC_CUSTOM_FIELDS},
+    pages::{func::{auto_push_to_sea, notify_appointments, search::rebuild_search_index, store::{replenish::check_stock_levels, stock::sync_stock_ledger}}, DROP_DOWN_BOX, STATIC_CUSTOM_BOX_OPTIONS, STATIC_CUSTOM_FIELDS},
     perm::roles::ROLE_TABLES,
     read_data, CONFIG,
 };
@@ -26,15 +26,14 @@ async fn main() {
                 .allow_headers(Any),
         )
         .layer(DefaultBodyLimit::max(20 * 1024 * 1024));
-    std::thread::spawn(|| { // 定时任务，每过10分钟清空所有缓存
-        tokio::runtime::Runtime::new().unwrap().block_on(async {
-            let mut interval = tokio::time::interval(Duration::from_secs(600));
-            loop {
-                interval.tick().await;
-                clear_cache();
-            }
-        })
-    });
+    // 定时任务，每过10分钟清空所有缓存
+    _spawn_task(600, clear_cache);
+    // 定时任务，每过1小时将长期未跟进的客户移入公海
+    _spawn_task(3600, auto_push_to_sea);
+    // 定时任务，每分钟检查一次需要发送的拜访提醒
+    _spawn_task(60, notify_appointments);
+    // 定时任务，每过1小时检查一次库存下限
+    _spawn_task(3600, check_stock_levels);
     axum::serve(
         tokio::net::TcpListener::bind(format!("0.0.0.0:{}", CONFIG.port()))
             .await
@@ -44,6 +43,18 @@ async fn main() {
     .await
     .unwrap()
 }
+/// 开启一个定时任务，每过`secs`秒执行一次
+fn _spawn_task(secs: u64, task: fn()) {
+    std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut interval = tokio::time::interval(Duration::from_secs(secs));
+            loop {
+                interval.tick().await;
+                task();
+            }
+        })
+    });
+}
 /// 初始化静态数据
 unsafe fn init_static() {
     let mut conn = __get_conn().expect("初始化失败");
@@ -56,6 +67,8 @@ unsafe fn init_static() {
         .init(&mut conn, "custom_field_option")
         .expect("err code: 2");
     DROP_DOWN_BOX.init(&mut conn).expect("err code: 3");
+    rebuild_search_index(&mut conn).expect("err code: 4");
+    sync_stock_ledger(&mut conn).expect("err code: 5");
 }
 fn _create_all_dir() -> std::io::Result<()> {
     _create_dir("config")?;
@@ -65,6 +78,7 @@ fn _create_all_dir() -> std::io::Result<()> {
     _create_dir("resources/product/cover")?;
     _create_dir("resources/approval")?;
     _create_dir("resources/sign")?;
+    _create_dir("resources/appoint")?;
     _create_dir("resources/order")?;
     Ok(())
 }
@@ -3,6 +3,7 @@ use super::{get_user, User};
 use crate::database::get_db;
 use crate::libs::{dser::*, gen_id, TIME};
 use crate::pages::check_drop_down_box;
+use crate::pages::func::search::__index_user;
 use crate::perm::action::AccountGroup;
 use crate::perm::roles::ROLE_TABLES;
 use crate::{bearer, parse_jwt_macro, Response, ResponseResult};
@@ -50,7 +51,7 @@ pub async fn register_root(Json(value): Json<Value>) -> ResponseResult {
     __insert_user!(
         conn,
         params! {
-            "id" => root.id,
+            "id" => &root.id,
             "smartphone" => root.smartphone,
             "password" => md5::compute(root.password.as_bytes()).0,
             "name" => root.name,
@@ -59,6 +60,7 @@ pub async fn register_root(Json(value): Json<Value>) -> ResponseResult {
             "department" => "总经办",
         }
     )?;
+    __index_user(&mut conn, &root.id)?;
     Ok(Response::ok(json!({})))
 }
 
@@ -77,7 +79,7 @@ pub async fn register_user(headers: HeaderMap, Json(value): Json<Value>) -> Resp
     regis.id = gen_id(&TIME::now()?, &regis.name);
     if ver_user_perm(&adm, &regis).await {
         catch!(__insert_user!(conn, params! {
-            "id" => regis.id,
+            "id" => &regis.id,
             "password" => DEFAULT_PASSWORD,
             "name" => regis.name,
             "role" => regis.role,
@@ -85,6 +87,7 @@ pub async fn register_user(headers: HeaderMap, Json(value): Json<Value>) -> Resp
             "sex" => regis.sex,
             "smartphone" => regis.smartphone
         }) => dup)?;
+        __index_user(&mut conn, &regis.id)?;
         Ok(Response::ok(json!({})))
     } else {
         Err(Response::permission_denied())
@@ -1,4 +1,5 @@
 use axum::extract::Path;
+use chrono::Datelike;
 use axum::routing::{delete, get, post};
 use axum::{http::HeaderMap, Json, Router};
 use mysql::{prelude::Queryable, PooledConn};
@@ -6,15 +7,17 @@ use mysql_common::prelude::FromRow;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use crate::database::{get_db, DB};
-use crate::libs::dser::deser_yyyy_mm_dd_hh_mm_ss;
+use crate::database::{__get_conn, get_db, DB};
+use crate::libs::dser::{deser_yyyy_mm_dd_hh_mm_ss, split_files};
 use crate::libs::TimeFormat;
-use crate::perm::action::CustomerGroup;
+use crate::pages::account::get_user;
+use crate::perm::action::{CustomerGroup, OtherGroup};
+use crate::pages::notify::__send_notification;
 use crate::perm::get_role;
 use crate::{
     bearer,
     libs::{gen_id, TIME},
-    parse_jwt_macro, Response, ResponseResult,
+    log, parse_jwt_macro, Response, ResponseResult, MAX_VISIT_DURATION, VISIT_DURATION,
 };
 
 pub fn appointment_router() -> Router {
@@ -37,6 +40,15 @@ pub fn appointment_router() -> Router {
             delete(delete_comment),
         )
         .route("/customer/appoint/comment/query/:id", get(query_comment))
+        .route(
+            "/customer/appointment/availability",
+            post(query_availability),
+        )
+        .route("/customer/appointment/duration", get(get_visit_duration))
+        .route(
+            "/customer/appointment/duration/set",
+            post(set_visit_duration),
+        )
 }
 #[derive(Debug, Deserialize)]
 struct InsertParams {
@@ -47,9 +59,12 @@ struct InsertParams {
     appointment: String,
     theme: String,
     content: String,
-    #[allow(dead_code)]
+    /// 是否需要拜访提醒
     #[serde(default)]
     notify: bool,
+    /// 忽略时间冲突，需要安排拜访的权限
+    #[serde(default)]
+    force: bool,
 }
 
 // 安排业务员拜访客户需要验证权限
@@ -57,7 +72,13 @@ struct InsertParams {
 // 完成拜访需要拜访者
 use crate::{commit_or_rollback, verify_perms};
 
-use super::CUSTOMER_CACHE;
+use super::{
+    attachment::{__appoint_files, __comment_files},
+    index::check_share_right,
+    series::__materialize_default,
+    sign::__delete_sign,
+    CUSTOMER_CACHE,
+};
 async fn add_appointments(
     header: HeaderMap,
     Json(value): Json<serde_json::Value>,
@@ -78,44 +99,197 @@ async fn __add_appoint(
 ) -> Result<(), Response> {
     let role = get_role(uid, conn)?;
     let flag = verify_perms!(&role, CustomerGroup::NAME, CustomerGroup::ADD_APPOINT);
-    for param in params {
+    for (i, param) in params.iter().enumerate() {
         let time = TIME::now()?;
-        if !param.salesman.eq(uid) && !flag {
+        if (!param.salesman.eq(uid) || param.force) && !flag {
             return Err(Response::permission_denied());
         }
+        // 没有安排拜访的权限时，只能拜访自己负责或者共享了拜访权限的客户
+        if !flag {
+            check_share_right(uid, &param.customer, "appoint", conn)?;
+        }
+        // 同一批次中前面插入的拜访也会参与检测
+        if !param.force {
+            let conflicts = __find_conflicts(conn, &param.salesman, &param.appointment, None)?;
+            if !conflicts.is_empty() {
+                return Err(Response::conflict(json!({
+                    "index": i,
+                    "conflicts": conflicts
+                })));
+            }
+        }
         let id = gen_id(&time, &rand::random::<i32>().to_string());
         conn.query_drop(format!(
             "INSERT INTO appointment 
-            (id, customer, applicant, salesman, appointment, finish_time, theme, content) VALUES (
-                '{}', '{}', '{}', '{}', '{}', NULL, '{}', '{}'
+            (id, customer, applicant, salesman, appointment, finish_time, theme, content, notify) VALUES (
+                '{}', '{}', '{}', '{}', '{}', NULL, '{}', '{}', {}
             )",
-            id, param.customer, uid, param.salesman, param.appointment, param.theme, param.content
+            id, param.customer, uid, param.salesman, param.appointment, param.theme, param.content,
+            param.notify as i32
         ))?;
     }
     Ok(())
 }
 
+#[derive(Serialize, FromRow)]
+pub(super) struct BusyAppointment {
+    id: String,
+    salesman: String,
+    customer: Option<String>,
+    customer_name: Option<String>,
+    theme: Option<String>,
+    appointment: String,
+    finish_time: Option<String>,
+}
+
+pub(super) fn visit_duration() -> chrono::Duration {
+    chrono::Duration::minutes(unsafe { VISIT_DURATION }.min(MAX_VISIT_DURATION) as i64)
+}
+
+/// 查找拜访者在该时间段内的其他拜访，每次拜访都按照默认的拜访时长计算，
+/// 所以两次拜访的开始时间相差不到一个拜访时长即为冲突
+pub(super) fn __find_conflicts(
+    conn: &mut PooledConn,
+    salesman: &str,
+    appointment: &str,
+    exclude: Option<&str>,
+) -> Result<Vec<BusyAppointment>, Response> {
+    let fmt = "%Y-%m-%d %H:%M:%S";
+    let start = chrono::NaiveDateTime::parse_from_str(appointment, fmt)
+        .map_err(|_| Response::invalid_value("拜访时间格式错误"))?;
+    let duration = visit_duration();
+    let (begin, end) = op::some!(
+        start.checked_sub_signed(duration).zip(start.checked_add_signed(duration));
+        ret Err(Response::invalid_value("拜访时间超出范围")));
+    let conflicts = conn.exec(
+        "SELECT a.id, a.salesman, a.customer, c.name AS customer_name, a.theme,
+            a.appointment, a.finish_time
+        FROM appointment a LEFT JOIN customer c ON c.id = a.customer
+        WHERE a.salesman = ? AND a.id <> ?
+        AND a.appointment > ? AND a.appointment < ?
+        ORDER BY a.appointment",
+        (
+            salesman,
+            exclude.unwrap_or_default(),
+            begin.format(fmt).to_string(),
+            end.format(fmt).to_string(),
+        ),
+    )?;
+    Ok(conflicts)
+}
+
+/// 定时任务，为开启了提醒的拜访向拜访者和发起者发送提醒
+pub fn notify_appointments() {
+    let result = __get_conn()
+        .map_err(Response::from)
+        .and_then(|mut conn| __notify_appointments(&mut conn));
+    match result {
+        Ok(0) => (),
+        Ok(count) => log!("已发送 {count} 条拜访提醒"),
+        Err(e) => log!("发送拜访提醒失败，错误信息：{:?}", e),
+    }
+}
+
+#[derive(FromRow)]
+struct NotifyAppointment {
+    id: String,
+    applicant: String,
+    salesman: Option<String>,
+    appointment: String,
+    theme: Option<String>,
+    customer_name: Option<String>,
+}
+
+fn __notify_appointments(conn: &mut PooledConn) -> Result<usize, Response> {
+    // 重复拜访需要先生成，才能按时提醒
+    __materialize_default(conn)?;
+    let offsets = crate::get_notify_offsets();
+    let max = op::some!(offsets.iter().max(); ret Ok(0));
+    let fmt = "%Y-%m-%d %H:%M:%S";
+    let now = chrono::Local::now().naive_local();
+    let end = now + chrono::Duration::minutes(*max as i64);
+    let list: Vec<NotifyAppointment> = conn.exec(
+        "SELECT a.id, a.applicant, a.salesman, a.appointment, a.theme, c.name as customer_name
+        FROM appointment a LEFT JOIN customer c ON c.id = a.customer
+        WHERE a.notify = 1 AND a.finish_time IS NULL
+        AND a.appointment > ? AND a.appointment <= ?",
+        (now.format(fmt).to_string(), end.format(fmt).to_string()),
+    )?;
+    let mut count = 0;
+    for a in list {
+        let time = op::some!(chrono::NaiveDateTime::parse_from_str(&a.appointment, fmt).ok(); continue);
+        let left = (time - now).num_minutes();
+        // 只发送最近的一次提醒，新建的拜访不会同时收到多条提醒
+        let minutes = op::some!(offsets.iter().filter(|m| **m as i64 >= left).min(); continue);
+        let content = format!(
+            "{} 拜访客户 {}，主题：{}",
+            a.appointment,
+            a.customer_name.unwrap_or_default(),
+            a.theme.unwrap_or_default()
+        );
+        let mut receivers = vec![a.applicant];
+        if let Some(s) = a.salesman.filter(|s| !receivers.contains(s)) {
+            receivers.push(s);
+        }
+        count += commit_or_rollback!(
+            __notify_receivers,
+            conn,
+            (&a.id, &receivers, *minutes, &content)
+        )?;
+    }
+    Ok(count)
+}
+
+/// 记录提醒和发送通知在同一个事务中，发送失败时不会被记为已提醒
+fn __notify_receivers(
+    conn: &mut PooledConn,
+    (id, receivers, minutes, content): (&str, &[String], u64, &str),
+) -> Result<usize, Response> {
+    let mut count = 0;
+    for r in receivers {
+        conn.exec_drop(
+            "INSERT IGNORE INTO appoint_notify (appoint, receiver, minutes) VALUES (?, ?, ?)",
+            (id, r, minutes),
+        )?;
+        if conn.affected_rows() > 0 {
+            __send_notification(conn, r, "appointment", "拜访提醒", content, id)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
 async fn delete_appointment(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
     let bearer = bearer!(&header);
         let db = get_db().await?;
     let mut conn = db.lock().await;
     let uid = parse_jwt_macro!(&bearer, &mut conn => true);
-    commit_or_rollback!(async __delete_appointment, &mut conn, &id, &uid)?;
+    let photos = commit_or_rollback!(async __delete_appointment, &mut conn, &id, &uid)?;
+    for f in photos {
+        let _ = std::fs::remove_file(f);
+    }
     CUSTOMER_CACHE.clear();
     Ok(Response::empty())
 }
 
-async fn __delete_appointment<'err>(conn: &mut DB<'err>, id: &str, uid: &str) -> Result<(), Response> {
+async fn __delete_appointment<'err>(conn: &mut DB<'err>, id: &str, uid: &str) -> Result<Vec<String>, Response> {
     let _: String = op::some!(conn.query_first(
         format!("select 1 from appointment where id = '{id}' and applicant='{uid}' LIMIT 1"))?;
         ret Err(Response::permission_denied())
     );
+    __remove_appointment(conn, id)
+}
+
+/// 删除拜访以及评论、提醒和签到记录，返回需要在提交后删除的附件和签到照片
+pub(super) fn __remove_appointment(conn: &mut PooledConn, id: &str) -> Result<Vec<String>, Response> {
+    let mut files = __appoint_files(conn, id)?;
     conn.query_drop(format!("delete from appointment where id = '{id}' limit 1"))?;
     conn.query_drop(format!(
         "delete from appoint_comment where appoint = '{id}'"
     ))?;
-
-    Ok(())
+    conn.query_drop(format!("delete from appoint_notify where appoint = '{id}'"))?;
+    files.extend(__delete_sign(conn, id)?);
+    Ok(files)
 }
 
 async fn finish_appointment(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
@@ -130,7 +304,7 @@ async fn finish_appointment(header: HeaderMap, Path(id): Path<String>) -> Respon
     let time = TIME::now()?;
     let finish_time = time.format(TimeFormat::YYYYMMDD_HHMMSS);
     conn.query_drop(format!(
-        "UPDATE appointment SET finish_time = '{}' WHERE id = '{}' LIMIT 1",
+        "UPDATE appointment SET finish_time = '{}', sequence = sequence + 1 WHERE id = '{}' LIMIT 1",
         finish_time, id
     ))?;
     CUSTOMER_CACHE.clear();
@@ -143,9 +317,12 @@ struct UpdateParams {
     appointment: String,
     theme: String,
     content: String,
-    #[allow(dead_code)]
+    /// 是否需要拜访提醒
     #[serde(default)]
     notify: bool,
+    /// 忽略时间冲突，需要安排拜访的权限
+    #[serde(default)]
+    force: bool,
 }
 
 async fn update_appointment(
@@ -162,11 +339,27 @@ async fn update_appointment(
         format!("select 1 from appointment where id = '{}' and applicant='{uid}' LIMIT 1", data.id))?;
         ret Err(Response::permission_denied())
     );
+    if data.force {
+        let role = get_role(&uid, &mut conn)?;
+        if !verify_perms!(&role, CustomerGroup::NAME, CustomerGroup::ADD_APPOINT) {
+            return Err(Response::permission_denied());
+        }
+    } else {
+        let conflicts = __find_conflicts(&mut conn, &data.visitor, &data.appointment, Some(&data.id))?;
+        if !conflicts.is_empty() {
+            return Err(Response::conflict(json!({
+                "index": 0,
+                "conflicts": conflicts
+            })));
+        }
+    }
     conn.query_drop(format!(
-        "update appointment set salesman='{}', appointment='{}', theme='{}', content='{}' 
+        "update appointment set salesman='{}', appointment='{}', theme='{}', content='{}', notify={}, sequence=sequence+1 
         where id='{}' and applicant='{}' limit 1",
-        data.visitor, data.appointment, data.theme, data.content, data.id, uid
+        data.visitor, data.appointment, data.theme, data.content, data.notify as i32, data.id, uid
     ))?;
+    // 拜访时间或者拜访者可能改变，需要重新提醒
+    conn.query_drop(format!("delete from appoint_notify where appoint = '{}'", data.id))?;
     CUSTOMER_CACHE.clear();
     Ok(Response::empty())
 }
@@ -182,6 +375,9 @@ struct AppointmentResponse {
     finish_time: Option<String>,
     theme: String,
     content: String,
+    /// 所属的重复拜访
+    series: Option<String>,
+    files: Option<String>,
 }
 
 fn join_to_json(appoint: &AppointmentResponse, comments: &[Comment]) -> Value {
@@ -195,6 +391,8 @@ fn join_to_json(appoint: &AppointmentResponse, comments: &[Comment]) -> Value {
         "finish_time": appoint.finish_time,
         "theme": appoint.theme,
         "content": appoint.content,
+        "series": appoint.series,
+        "files": appoint.files.as_deref().map(|f| f.split('&').collect::<Vec<_>>()).unwrap_or_default(),
         "comments": comments
     })
 }
@@ -207,6 +405,8 @@ struct Comment {
     appoint: String,
     create_time: String,
     comment: String,
+    #[serde(serialize_with = "split_files")]
+    files: Option<String>,
 }
 
 async fn query_appointment(Path((id, limit)): Path<(String, usize)>) -> ResponseResult {
@@ -261,7 +461,8 @@ async fn insert_comment(header: HeaderMap, Json(value): Json<serde_json::Value>)
         "id": id,
         "appoint": data.appoint,
         "create_time": create_time,
-        "comment": data.comment
+        "comment": data.comment,
+        "files": []
     })))
 }
 #[derive(Deserialize)]
@@ -289,9 +490,17 @@ async fn delete_comment(header: HeaderMap, Path(id): Path<String>) -> ResponseRe
         let db = get_db().await?;
     let mut conn = db.lock().await;
     let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let _: String = op::some!(conn.query_first(
+        format!("select 1 from appoint_comment where id = '{id}' and applicant='{uid}' LIMIT 1"))?;
+        ret Err(Response::permission_denied())
+    );
+    let files = __comment_files(&mut conn, &id)?;
     conn.query_drop(format!(
         "DELETE FROM appoint_comment WHERE id = '{id}' AND applicant = '{uid}' LIMIT 1"
     ))?;
+    for f in files {
+        let _ = std::fs::remove_file(f);
+    }
     Ok(Response::empty())
 }
 async fn query_comment(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
@@ -308,3 +517,145 @@ async fn query_comment(header: HeaderMap, Path(id): Path<String>) -> ResponseRes
     ))?;
     Ok(Response::ok(json!(comments)))
 }
+
+#[derive(Deserialize)]
+struct AvailabilityParams {
+    /// 查询某个业务员，为空时查询部门
+    #[serde(default)]
+    salesman: String,
+    /// 部门，为空或者my时为自己的部门
+    #[serde(default)]
+    department: String,
+    /// YYYY-MM-DD
+    date: String,
+    /// day或者week，week从date所在周的周一开始
+    #[serde(default = "default_range")]
+    range: String,
+}
+
+fn default_range() -> String {
+    "day".to_owned()
+}
+
+#[derive(Serialize)]
+struct Availability {
+    id: String,
+    name: String,
+    busy: Vec<BusyAppointment>,
+}
+
+async fn query_availability(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: AvailabilityParams = serde_json::from_value(value)?;
+    let date = chrono::NaiveDate::parse_from_str(&params.date, "%Y-%m-%d")
+        .map_err(|_| Response::invalid_value("date格式错误"))?;
+    let (start, days) = match params.range.as_str() {
+        "day" => (date, 1),
+        "week" => (
+            date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64),
+            7,
+        ),
+        _ => return Err(Response::invalid_value("range必须是day或者week")),
+    };
+    let department = if params.salesman.is_empty() {
+        op::ternary!(params.department.is_empty() || params.department.eq("my")
+            => user.department.clone(), params.department.clone())
+    } else {
+        get_user(&params.salesman, &mut conn).await?.department.clone()
+    };
+    // 查看别人的日程需要安排拜访的权限，跨部门需要all
+    if !params.salesman.eq(&uid) {
+        let (depart, all) = verify_perms!(
+            &user.role,
+            CustomerGroup::NAME,
+            CustomerGroup::ADD_APPOINT,
+            None,
+            Some(["all"].as_slice())
+        );
+        if !(all || depart && department.eq(&user.department)) {
+            log!("{user} 查询{department}的拜访日程失败，原因权限不足");
+            return Err(Response::permission_denied());
+        }
+    }
+    let salesmen: Vec<(String, String)> = if params.salesman.is_empty() {
+        conn.exec(
+            "SELECT u.id, u.name FROM user u WHERE u.department = ?
+            AND NOT EXISTS (SELECT 1 FROM leaver l WHERE l.id = u.id) ORDER BY u.id",
+            (&department,),
+        )?
+    } else {
+        conn.exec(
+            "SELECT id, name FROM user WHERE id = ? LIMIT 1",
+            (&params.salesman,),
+        )?
+    };
+    let fmt = "%Y-%m-%d %H:%M:%S";
+    let begin = start.and_hms_opt(0, 0, 0).unwrap_or_default();
+    let (since, end) = op::some!(
+        begin.checked_sub_signed(visit_duration())
+            .zip(begin.checked_add_signed(chrono::Duration::days(days)));
+        ret Err(Response::invalid_value("date超出范围")));
+    // 只生成到固定的天数，查询更远的日期不会写入更多的拜访
+    __materialize_default(&mut conn)?;
+    let mut data = Vec::new();
+    for (id, name) in salesmen {
+        // 前一天最后的拜访可能会持续到查询范围内
+        let busy = conn.exec(
+            "SELECT a.id, a.salesman, a.customer, c.name AS customer_name, a.theme,
+                a.appointment, a.finish_time
+            FROM appointment a LEFT JOIN customer c ON c.id = a.customer
+            WHERE a.salesman = ? AND a.appointment > ? AND a.appointment < ?
+            ORDER BY a.appointment",
+            (
+                &id,
+                since.format(fmt).to_string(),
+                end.format(fmt).to_string(),
+            ),
+        )?;
+        data.push(Availability { id, name, busy });
+    }
+    log!("{user} 查询了{department}从{start}开始{days}天的拜访日程");
+    Ok(Response::ok(json!({
+        "duration": unsafe { VISIT_DURATION },
+        "data": data
+    })))
+}
+
+async fn get_visit_duration(header: HeaderMap) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    Ok(Response::ok(json!(unsafe { VISIT_DURATION })))
+}
+
+#[derive(Deserialize)]
+struct VisitDuration {
+    /// 单位为分钟
+    minutes: u64,
+}
+
+async fn set_visit_duration(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let rule: VisitDuration = serde_json::from_value(value)?;
+    if !verify_perms!(&user.role, OtherGroup::NAME, OtherGroup::VISIT_DURATION) {
+        log!("{user} 设置默认拜访时长失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    if rule.minutes == 0 || rule.minutes > MAX_VISIT_DURATION {
+        return Err(Response::invalid_value(format!(
+            "拜访时长必须大于0且不超过{MAX_VISIT_DURATION}分钟"
+        )));
+    }
+    crate::set_visit_duration(rule.minutes)?;
+    log!("{user} 已将默认拜访时长设置为{}分钟", rule.minutes);
+    Ok(Response::empty())
+}
@@ -0,0 +1,195 @@
+use axum::{
+    extract::{Multipart, Path},
+    http::{HeaderMap, StatusCode},
+    routing::{delete, get, post},
+    Router,
+};
+use mysql::{prelude::Queryable, PooledConn};
+use serde_json::json;
+
+use crate::{
+    bearer,
+    database::get_db,
+    libs::{gen_file_link, parse_multipart, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    response::BodyFile,
+    Response, ResponseResult,
+};
+
+pub fn attachment_router() -> Router {
+    Router::new()
+        .route(
+            "/customer/appointment/upload/:id",
+            post(upload_appoint_file),
+        )
+        .route(
+            "/customer/appoint/comment/upload/:id",
+            post(upload_comment_file),
+        )
+        .route("/customer/appoint/file/:url", get(get_appoint_file))
+        .route(
+            "/customer/appoint/file/delete/:url",
+            delete(delete_appoint_file),
+        )
+}
+
+/// 拜访和评论的附件保存在该目录，数据库中的files以&分隔
+const DIR: &str = "resources/appoint";
+
+fn to_paths(files: impl IntoIterator<Item = Option<String>>) -> Vec<String> {
+    files
+        .into_iter()
+        .flatten()
+        .flat_map(|f| {
+            f.split('&')
+                .filter(|s| !s.is_empty())
+                .map(|s| format!("{DIR}/{s}"))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// 拜访以及其评论的附件路径，删除拜访时使用
+pub(super) fn __appoint_files(
+    conn: &mut PooledConn,
+    appoint: &str,
+) -> Result<Vec<String>, Response> {
+    let mut files: Vec<Option<String>> =
+        conn.exec("SELECT files FROM appointment WHERE id = ?", (appoint,))?;
+    files.extend(conn.exec::<Option<String>, _, _>(
+        "SELECT files FROM appoint_comment WHERE appoint = ?",
+        (appoint,),
+    )?);
+    Ok(to_paths(files))
+}
+
+/// 评论的附件路径，删除评论时使用
+pub(super) fn __comment_files(
+    conn: &mut PooledConn,
+    comment: &str,
+) -> Result<Vec<String>, Response> {
+    let files: Vec<Option<String>> =
+        conn.exec("SELECT files FROM appoint_comment WHERE id = ?", (comment,))?;
+    Ok(to_paths(files))
+}
+
+fn save_files(part: &[crate::libs::FilePart]) -> Result<Vec<String>, Response> {
+    if part.is_empty() {
+        return Err(Response::invalid_value("没有上传文件"));
+    }
+    let time = TIME::now()?;
+    let mut links = Vec::new();
+    for f in part {
+        let link = gen_file_link(&time, f.filename());
+        std::fs::write(format!("{DIR}/{link}"), &f.bytes)?;
+        links.push(link);
+    }
+    Ok(links)
+}
+
+async fn upload_appoint_file(
+    header: HeaderMap,
+    Path(id): Path<String>,
+    part: Multipart,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let _: i32 = op::some!(conn.exec_first(
+        "SELECT 1 FROM appointment WHERE id = ? AND (applicant = ? OR salesman = ?) LIMIT 1",
+        (&id, &uid, &uid))?;
+        ret Err(Response::permission_denied())
+    );
+    let data = parse_multipart(part).await?;
+    let links = save_files(&data.files)?;
+    let joined = links.join("&");
+    conn.exec_drop(
+        "UPDATE appointment SET files = IF(files IS NULL OR files = '', ?, CONCAT(files, '&', ?))
+        WHERE id = ? LIMIT 1",
+        (&joined, &joined, &id),
+    )?;
+    log!("{user} 为拜访 {id} 上传了{}个附件", links.len());
+    Ok(Response::ok(json!(links)))
+}
+
+async fn upload_comment_file(
+    header: HeaderMap,
+    Path(id): Path<String>,
+    part: Multipart,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let _: i32 = op::some!(conn.exec_first(
+        "SELECT 1 FROM appoint_comment WHERE id = ? AND applicant = ? LIMIT 1",
+        (&id, &uid))?;
+        ret Err(Response::permission_denied())
+    );
+    let data = parse_multipart(part).await?;
+    let links = save_files(&data.files)?;
+    let joined = links.join("&");
+    conn.exec_drop(
+        "UPDATE appoint_comment SET files = IF(files IS NULL OR files = '', ?, CONCAT(files, '&', ?))
+        WHERE id = ? LIMIT 1",
+        (&joined, &joined, &id),
+    )?;
+    log!("{user} 为评论 {id} 上传了{}个附件", links.len());
+    Ok(Response::ok(json!(links)))
+}
+
+async fn get_appoint_file(Path(url): Path<String>) -> Result<BodyFile, (StatusCode, String)> {
+    BodyFile::new_with_base64_url(DIR, &url)
+}
+
+/// 删除单个附件，拜访的附件可以由发起者或者拜访者删除，评论的附件只能由评论者删除
+async fn delete_appoint_file(header: HeaderMap, Path(url): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    // 附件之间以&分隔，只匹配完整的附件名，base64中的_需要转义
+    let pattern = format!(
+        "%&{}&%",
+        url.replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+    let appoint: Option<(String, String)> = conn.exec_first(
+        "SELECT id, files FROM appointment
+        WHERE CONCAT('&', files, '&') LIKE ? AND (applicant = ? OR salesman = ?) LIMIT 1",
+        (&pattern, &uid, &uid),
+    )?;
+    let (table, id, files) = match appoint {
+        Some((id, files)) => ("appointment", id, files),
+        None => {
+            let comment: Option<(String, String)> = conn.exec_first(
+                "SELECT id, files FROM appoint_comment
+                WHERE CONCAT('&', files, '&') LIKE ? AND applicant = ? LIMIT 1",
+                (&pattern, &uid),
+            )?;
+            let (id, files) = op::some!(comment; ret Err(Response::permission_denied()));
+            ("appoint_comment", id, files)
+        }
+    };
+    let rest: Vec<&str> = files.split('&').filter(|f| !f.eq(&url)).collect();
+    if rest.len() == files.split('&').count() {
+        return Err(Response::not_exist("附件不存在"));
+    }
+    conn.exec_drop(
+        format!("UPDATE {table} SET files = ? WHERE id = ? LIMIT 1"),
+        (
+            op::ternary!(rest.is_empty() => None, Some(rest.join("&"))),
+            &id,
+        ),
+    )?;
+    std::fs::remove_file(format!("{DIR}/{url}")).unwrap_or_default();
+    log!("{user} 删除了附件 {url}");
+    Ok(Response::empty())
+}
@@ -0,0 +1,258 @@
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{get, post},
+    Router,
+};
+use chrono::{Duration, NaiveDateTime};
+use mysql::{params, prelude::Queryable};
+use mysql_common::prelude::FromRow;
+use serde_json::json;
+
+use crate::{
+    bearer,
+    database::get_db,
+    libs::{TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    response::BodyFile,
+    Response, ResponseResult, CONFIG,
+};
+
+use super::{appointment::visit_duration, series::__materialize_default};
+
+pub fn calendar_router() -> Router {
+    Router::new()
+        .route("/calendar/token", get(query_calendar_token))
+        .route("/calendar/token/reset", post(reset_calendar_token))
+        .route("/calendar/feed/:token", get(calendar_feed))
+}
+
+/// 日历中显示的时间范围，往前显示最近完成的拜访
+const PAST_DAYS: i64 = 30;
+
+fn gen_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+fn feed_url(header: &HeaderMap, token: &str) -> String {
+    let host = header
+        .get("host")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    format!("{}://{host}/calendar/feed/{token}.ics", scheme(header))
+}
+
+fn scheme(header: &HeaderMap) -> &str {
+    header
+        .get("x-forwarded-proto")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("http")
+}
+
+async fn query_calendar_token(header: HeaderMap) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let token: Option<String> = conn.exec_first(
+        "SELECT token FROM calendar_token WHERE user = ? LIMIT 1",
+        (&uid,),
+    )?;
+    let token = match token {
+        Some(token) => token,
+        None => {
+            let token = gen_token();
+            let time = TIME::now()?.format(TimeFormat::YYYYMMDD_HHMMSS);
+            conn.exec_drop(
+                "INSERT INTO calendar_token (user, token, create_time) VALUES (?, ?, ?)",
+                (&uid, &token, time),
+            )?;
+            token
+        }
+    };
+    Ok(Response::ok(json!({
+        "token": token,
+        "url": feed_url(&header, &token)
+    })))
+}
+
+/// 订阅链接泄露时重新生成，旧的链接立即失效
+async fn reset_calendar_token(header: HeaderMap) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let token = gen_token();
+    let time = TIME::now()?.format(TimeFormat::YYYYMMDD_HHMMSS);
+    conn.exec_drop(
+        "INSERT INTO calendar_token (user, token, create_time) VALUES (:user, :token, :time)
+        ON DUPLICATE KEY UPDATE token = :token, create_time = :time",
+        params! { "user" => &uid, "token" => &token, "time" => time },
+    )?;
+    log!("{user} 重新生成了日历订阅链接");
+    Ok(Response::ok(json!({
+        "token": token,
+        "url": feed_url(&header, &token)
+    })))
+}
+
+#[derive(FromRow)]
+struct CalendarEvent {
+    id: String,
+    customer: Option<String>,
+    customer_name: Option<String>,
+    salesman_name: Option<String>,
+    appointment: String,
+    finish_time: Option<String>,
+    theme: Option<String>,
+    content: Option<String>,
+    sequence: u32,
+}
+
+/// 转义TEXT类型的属性值，见RFC5545 3.3.11
+fn escape_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ';' => out.push_str("\\;"),
+            ',' => out.push_str("\\,"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// 每行不能超过75个字节，超出的部分换行并以空格开头，不能截断UTF-8字符，见RFC5545 3.1
+fn fold_line(line: &str) -> String {
+    let mut out = String::with_capacity(line.len() + 8);
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            len = 1;
+        }
+        out.push(c);
+        len += c.len_utf8();
+    }
+    out.push_str("\r\n");
+    out
+}
+
+fn ics_time(time: &NaiveDateTime) -> String {
+    time.format("%Y%m%dT%H%M%S").to_string()
+}
+
+/// `frontend`为前端页面的地址，设置后每个拜访都会链接到客户的页面
+fn render_calendar(events: &[CalendarEvent], frontend: &str) -> String {
+    let fmt = "%Y-%m-%d %H:%M:%S";
+    let now = ics_time(&chrono::Local::now().naive_local());
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_owned(),
+        "VERSION:2.0".to_owned(),
+        "PRODID:-//rust-crm//appointment//CN".to_owned(),
+        "CALSCALE:GREGORIAN".to_owned(),
+        "METHOD:PUBLISH".to_owned(),
+        "X-WR-CALNAME:客户拜访".to_owned(),
+    ];
+    for e in events {
+        let start = op::some!(NaiveDateTime::parse_from_str(&e.appointment, fmt).ok(); continue);
+        let finish = e
+            .finish_time
+            .as_ref()
+            .and_then(|t| NaiveDateTime::parse_from_str(t, fmt).ok());
+        let end = match finish {
+            Some(f) if f > start => f,
+            // 未完成的拜访按照默认的拜访时长显示
+            _ => start.checked_add_signed(visit_duration()).unwrap_or(start),
+        };
+        let customer = e.customer_name.clone().unwrap_or_default();
+        let theme = e.theme.clone().unwrap_or_default();
+        let mut summary = format!("拜访 {customer}");
+        if !theme.is_empty() {
+            summary.push_str(&format!("：{theme}"));
+        }
+        if finish.is_some() {
+            summary.push_str("（已完成）");
+        }
+        let mut description = format!(
+            "客户：{customer}\n拜访者：{}\n主题：{theme}\n内容：{}",
+            e.salesman_name.clone().unwrap_or_default(),
+            e.content.clone().unwrap_or_default()
+        );
+        lines.push("BEGIN:VEVENT".to_owned());
+        lines.push(format!("UID:{}@rust-crm", e.id));
+        lines.push(format!("DTSTAMP:{now}"));
+        lines.push(format!("SEQUENCE:{}", e.sequence));
+        lines.push(format!("DTSTART:{}", ics_time(&start)));
+        lines.push(format!("DTEND:{}", ics_time(&end)));
+        lines.push(format!("SUMMARY:{}", escape_text(&summary)));
+        if let Some(id) = &e.customer {
+            description.push_str(&format!("\n客户编号：{id}"));
+            if !frontend.is_empty() {
+                let url = format!("{frontend}/customer/{id}");
+                description.push_str(&format!("\n{url}"));
+                lines.push(format!("URL:{url}"));
+            }
+        }
+        lines.push(format!("DESCRIPTION:{}", escape_text(&description)));
+        lines.push(format!(
+            "STATUS:{}",
+            op::ternary!(finish.is_some() => "CONFIRMED", "TENTATIVE")
+        ));
+        lines.push("END:VEVENT".to_owned());
+    }
+    lines.push("END:VCALENDAR".to_owned());
+    lines.iter().map(|l| fold_line(l)).collect()
+}
+
+/// 日历客户端无法携带token，使用订阅链接中的随机token验证身份。
+/// 每次请求都重新生成，拜访的修改、删除和完成会在客户端下次刷新时同步
+async fn calendar_feed(Path(token): Path<String>) -> Result<BodyFile, Response> {
+    let token = token.trim_end_matches(".ics");
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid: Option<String> = conn.exec_first(
+        "SELECT c.user FROM calendar_token c WHERE c.token = ?
+        AND NOT EXISTS (SELECT 1 FROM leaver l WHERE l.id = c.user) LIMIT 1",
+        (token,),
+    )?;
+    let uid = op::some!(uid; ret Err(Response::permission_denied()));
+    __materialize_default(&mut conn)?;
+    let since = (chrono::Local::now() - Duration::days(PAST_DAYS))
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+    let events: Vec<CalendarEvent> = conn.exec(
+        "SELECT a.id, a.customer, c.name AS customer_name, u.name AS salesman_name,
+            a.appointment, a.finish_time, a.theme, a.content, a.sequence
+        FROM appointment a
+        LEFT JOIN customer c ON c.id = a.customer
+        LEFT JOIN user u ON u.id = a.salesman
+        WHERE (a.salesman = :uid OR a.applicant = :uid)
+        AND (a.appointment >= :since OR a.finish_time >= :since)
+        ORDER BY a.appointment",
+        params! { "uid" => &uid, "since" => since },
+    )?;
+    let body = render_calendar(&events, CONFIG.frontend());
+    Ok(BodyFile::with_name(
+        body.into_bytes(),
+        "appointment.ics",
+        "text/calendar; charset=utf-8",
+    ))
+}
+
+#[test]
+fn test_ics_escape_and_fold() {
+    assert_eq!(escape_text("a,b;c\\d\r\ne"), "a\\,b\\;c\\\\d\\ne");
+    let folded = fold_line(&format!("SUMMARY:{}", "拜".repeat(30)));
+    assert!(folded.split("\r\n").all(|l| l.len() <= 75));
+    assert_eq!(
+        folded.replace("\r\n ", ""),
+        format!("SUMMARY:{}\r\n", "拜".repeat(30))
+    );
+}
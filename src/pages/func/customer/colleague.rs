@@ -35,7 +35,7 @@ struct Colleague {
     phone: String,
     name: String,
 }
-use super::index::check_user_customer;
+use super::index::check_owner_customer;
 
 async fn insert_colleague(
     headers: HeaderMap,
@@ -53,7 +53,7 @@ async fn insert_colleague(
         params.id,
         params.name
     );
-    check_user_customer(&id, &customer, &mut conn)?;
+    check_owner_customer(&id, &customer, &mut conn)?;
     let time = TIME::now()?;
     params.id = gen_id(&time, &params.name);
     conn.query_drop(format!(
@@ -0,0 +1,205 @@
+use std::{collections::HashMap, ptr::addr_of};
+
+use axum::{http::HeaderMap, routing::post, Json, Router};
+use mysql::prelude::Queryable;
+use rust_xlsxwriter::Workbook;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    bearer,
+    database::get_db,
+    libs::{TimeFormat, TIME},
+    log,
+    pages::{account::get_user, STATIC_CUSTOM_FIELDS},
+    parse_jwt_macro,
+    perm::action::CustomerGroup,
+    response::BodyFile,
+    verify_perms, Response,
+};
+
+use super::index::{__query_customer_list_data, QueryParams};
+
+pub fn export_router() -> Router {
+    Router::new().route("/customer/export", post(export_customer))
+}
+
+/// 导出的列，与导入时的表头保持一致
+const COLUMNS: [(&str, &str); 14] = [
+    ("smartphone", "手机号"),
+    ("name", "姓名"),
+    ("company", "公司"),
+    ("sex", "性别"),
+    ("level", "级别"),
+    ("ty", "客户类型"),
+    ("status", "跟踪状态"),
+    ("address", "地址"),
+    ("salesman_name", "负责人"),
+    ("create_time", "创建时间"),
+    ("visited_count", "拜访次数"),
+    ("last_visited_time", "上次拜访时间"),
+    ("next_visit_time", "下次拜访时间"),
+    ("last_transaction_time", "上次成交时间"),
+];
+
+#[derive(Deserialize)]
+struct ExportParams {
+    /// csv 或者 xlsx
+    #[serde(default)]
+    format: String,
+}
+
+async fn export_customer(
+    header: HeaderMap,
+    Json(value): Json<Value>,
+) -> Result<BodyFile, Response> {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let export: ExportParams = serde_json::from_value(value.clone())?;
+    let mut params: QueryParams = serde_json::from_value(value)?;
+    // 导出全部符合条件的客户，忽略分页
+    params.page = 0;
+    log!("{user} 请求导出客户数据");
+    let (perm, depart, root) = verify_perms!(
+        &user.role,
+        CustomerGroup::NAME,
+        CustomerGroup::EXPORT_DATA,
+        None,
+        Some(["department"].as_slice()),
+        Some(["all"].as_slice())
+    );
+    if !perm {
+        log!("{user} 导出客户数据失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let (list, _) = __query_customer_list_data(&mut conn, &params, &user).await?;
+    // 查询条件只受查询权限限制，这里再按照导出权限的数据范围过滤
+    let scope: Option<Vec<String>> = if root {
+        None
+    } else if depart {
+        Some(conn.exec(
+            "SELECT id FROM user WHERE department = ?",
+            (&user.department,),
+        )?)
+    } else {
+        Some(vec![uid.clone()])
+    };
+    let rows: Vec<Value> = serde_json::from_value(serde_json::to_value(list)?)?;
+    let rows: Vec<Value> = rows
+        .into_iter()
+        .filter(|r| {
+            scope.as_ref().is_none_or(|s| {
+                r["salesman"]
+                    .as_str()
+                    .is_some_and(|id| s.iter().any(|u| u == id))
+            })
+        })
+        .collect();
+
+    // 只查询导出的客户的自定义字段和同事，每次最多查询1000个客户
+    let ids: Vec<&str> = rows.iter().filter_map(|r| r["id"].as_str()).collect();
+    let mut custom_map: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut colleague_map: HashMap<String, Vec<String>> = HashMap::new();
+    for chunk in ids.chunks(1000) {
+        let marks = vec!["?"; chunk.len()].join(", ");
+        let custom: Vec<(String, String, String)> = conn.exec(
+            format!(
+                "SELECT id, display, value FROM custom_field_data WHERE fields = 0 AND id IN ({marks})"
+            ),
+            chunk.to_vec(),
+        )?;
+        for (id, display, value) in custom {
+            custom_map.entry(id).or_default().insert(display, value);
+        }
+        let colleagues: Vec<(String, String, String)> = conn.exec(
+            format!(
+                "SELECT customer, name, phone FROM customer_colleague
+                WHERE customer IN ({marks}) ORDER BY create_time"
+            ),
+            chunk.to_vec(),
+        )?;
+        for (customer, name, phone) in colleagues {
+            colleague_map
+                .entry(customer)
+                .or_default()
+                .push(format!("{name}({phone})"));
+        }
+    }
+
+    let (texts, times, boxes) = unsafe { (*addr_of!(STATIC_CUSTOM_FIELDS)).get_fields(0) };
+    let custom_fields: Vec<&str> = texts.into_iter().chain(times).chain(boxes).collect();
+    let mut table: Vec<Vec<String>> = Vec::with_capacity(rows.len() + 1);
+    table.push(
+        COLUMNS
+            .iter()
+            .map(|(_, h)| h.to_string())
+            .chain(custom_fields.iter().map(|f| f.to_string()))
+            .chain(["同事".to_owned()])
+            .collect(),
+    );
+    for r in &rows {
+        let id = r["id"].as_str().unwrap_or_default();
+        let mut line: Vec<String> = COLUMNS
+            .iter()
+            .map(|(k, _)| match &r[*k] {
+                Value::String(s) => s.clone(),
+                Value::Bool(b) if k.eq(&"sex") => op::ternary!(*b => "男", "女").to_owned(),
+                Value::Null => String::new(),
+                v => v.to_string(),
+            })
+            .collect();
+        let fields = custom_map.get(id);
+        for f in &custom_fields {
+            line.push(fields.and_then(|m| m.get(*f)).cloned().unwrap_or_default());
+        }
+        line.push(
+            colleague_map
+                .get(id)
+                .map(|c| c.join("、"))
+                .unwrap_or_default(),
+        );
+        table.push(line);
+    }
+
+    let date = TIME::now()?.format(TimeFormat::YYYYMMDD);
+    let file = if export.format.eq("csv") {
+        BodyFile::with_name(to_csv(&table)?, format!("customer-{date}.csv"), "text/csv")
+    } else {
+        BodyFile::with_name(
+            to_xlsx(&table)?,
+            format!("customer-{date}.xlsx"),
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        )
+    };
+    log!("{user} 成功导出{}位客户数据", rows.len());
+    Ok(file)
+}
+
+fn to_csv(table: &[Vec<String>]) -> Result<Vec<u8>, Response> {
+    // 带上BOM，否则Excel打开时中文会乱码
+    let mut writer = csv::Writer::from_writer(b"\xEF\xBB\xBF".to_vec());
+    for line in table {
+        writer
+            .write_record(line)
+            .map_err(Response::internal_server_error)?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| Response::internal_server_error(e.error()))
+}
+
+fn to_xlsx(table: &[Vec<String>]) -> Result<Vec<u8>, Response> {
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    for (i, line) in table.iter().enumerate() {
+        sheet
+            .write_row(i as u32, 0, line)
+            .map_err(Response::internal_server_error)?;
+    }
+    workbook
+        .save_to_buffer()
+        .map_err(Response::internal_server_error)
+}
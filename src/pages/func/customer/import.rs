@@ -0,0 +1,336 @@
+use std::{collections::HashSet, io::Cursor, ptr::addr_of};
+
+use axum::{extract::Multipart, http::HeaderMap, routing::post, Router};
+use calamine::{open_workbook_auto_from_rs, Data, Reader};
+use mysql::prelude::Queryable;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::get_db,
+    libs::parse_multipart,
+    log,
+    pages::{
+        account::get_user, check_drop_down_box, STATIC_CUSTOM_BOX_OPTIONS, STATIC_CUSTOM_FIELDS,
+    },
+    parse_jwt_macro,
+    perm::action::CustomerGroup,
+    verify_perms, Response, ResponseResult,
+};
+
+use super::{
+    index::{__insert_customer, InsertParams},
+    CUSTOMER_CACHE,
+};
+
+pub fn import_router() -> Router {
+    Router::new().route("/customer/upload/excel", post(upload_excel))
+}
+
+/// 表头与客户字段的对应关系，第三项为需要校验的下拉框
+const HEADERS: [(&str, &[&str], Option<&str>); 20] = [
+    ("smartphone", &["手机号", "电话", "手机"], None),
+    ("name", &["姓名", "客户名", "客户名称"], None),
+    ("company", &["公司", "公司名称"], None),
+    ("is_share", &["是否共享", "共享"], None),
+    ("sex", &["性别"], None),
+    ("chat", &["聊天方式", "微信"], None),
+    ("need", &["需求"], None),
+    ("fax", &["传真"], None),
+    ("post", &["邮编"], None),
+    ("industry", &["行业"], Some("industry")),
+    ("birthday", &["生日"], None),
+    ("address", &["地址"], None),
+    ("remark", &["备注"], None),
+    ("status", &["跟踪状态", "状态"], Some("customer_status")),
+    ("source", &["来源", "客户来源"], Some("customer_source")),
+    ("level", &["级别", "客户级别"], Some("customer_level")),
+    ("role", &["职务"], Some("customer_role")),
+    ("ty", &["客户类型", "类型"], Some("customer_type")),
+    ("tag", &["标签", "客户标签"], Some("customer_tag")),
+    ("salesman", &["负责人"], None),
+];
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ImportParams {
+    /// 仅校验数据，不写入数据库
+    dry_run: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct RowReport {
+    /// 表格中的行号，从1开始，包含表头
+    row: usize,
+    name: String,
+    smartphone: String,
+    /// created, valid, duplicate, invalid
+    status: &'static str,
+    reason: String,
+}
+
+impl RowReport {
+    fn new(row: usize, record: &Map<String, Value>) -> Self {
+        let get = |k: &str| {
+            record
+                .get(k)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_owned()
+        };
+        Self {
+            row,
+            name: get("name"),
+            smartphone: get("smartphone"),
+            status: "valid",
+            reason: String::new(),
+        }
+    }
+    fn with(mut self, status: &'static str, reason: impl Into<String>) -> Self {
+        self.status = status;
+        self.reason = reason.into();
+        self
+    }
+}
+
+async fn upload_excel(header: HeaderMap, part: Multipart) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        CustomerGroup::NAME,
+        CustomerGroup::ENTER_CUSTOMER_DATA
+    ) {
+        log!("{user} 导入客户失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let data = parse_multipart(part).await?;
+    let params: ImportParams = if data.json.is_empty() {
+        ImportParams::default()
+    } else {
+        serde_json::from_str(&data.json)?
+    };
+    let file = op::some!(data.files.first(); ret Err(Response::invalid_value("缺少上传文件")));
+    let filename = file.filename().to_lowercase();
+    log!(
+        "{user} 请求导入客户文件 {filename}，dry_run: {}",
+        params.dry_run
+    );
+    let rows = if filename.ends_with(".csv") {
+        read_csv(&file.bytes)?
+    } else {
+        read_excel(&file.bytes)?
+    };
+    let mut rows = rows.into_iter();
+    let head = op::some!(rows.next(); ret Err(Response::invalid_value("文件内容为空")));
+    let columns = map_headers(&head);
+    if !columns
+        .iter()
+        .any(|c| matches!(c, Column::Field("smartphone")))
+    {
+        return Err(Response::invalid_value("缺少手机号列"));
+    }
+    let mut reports = Vec::new();
+    let mut phones = HashSet::new();
+    for (i, row) in rows.enumerate() {
+        if row.iter().all(|c| c.trim().is_empty()) {
+            continue;
+        }
+        let record = to_record(&columns, &row, &uid);
+        let report = RowReport::new(i + 2, &record);
+        let report = match validate(&record) {
+            Err(reason) => report.with("invalid", reason),
+            Ok(()) if !phones.insert(report.smartphone.clone()) => {
+                report.with("duplicate", "文件中存在相同的手机号")
+            }
+            Ok(()) => {
+                let exists: Option<i32> = conn.exec_first(
+                    "SELECT 1 FROM customer WHERE smartphone = ? LIMIT 1",
+                    (&report.smartphone,),
+                )?;
+                if exists.is_some() {
+                    report.with("duplicate", "手机号已存在")
+                } else {
+                    match serde_json::from_value::<InsertParams>(Value::Object(record)) {
+                        Err(e) => report.with("invalid", e.to_string()),
+                        Ok(_) if params.dry_run => report,
+                        Ok(insert) => {
+                            match commit_or_rollback!(__insert_customer, &mut conn, &insert) {
+                                Ok(()) => report.with("created", ""),
+                                Err(e) if e.status() == 3 => {
+                                    report.with("duplicate", "手机号已存在")
+                                }
+                                Err(e) => return Err(e),
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        reports.push(report);
+    }
+    let count = |s: &str| reports.iter().filter(|r| r.status == s).count();
+    let (created, valid, duplicate, invalid) = (
+        count("created"),
+        count("valid"),
+        count("duplicate"),
+        count("invalid"),
+    );
+    if created > 0 {
+        CUSTOMER_CACHE.clear();
+    }
+    log!("{user} 导入客户完成，成功{created}条，重复{duplicate}条，无效{invalid}条");
+    Ok(Response::ok(json!({
+        "dry_run": params.dry_run,
+        "total": reports.len(),
+        "created": created,
+        "valid": valid,
+        "duplicate": duplicate,
+        "invalid": invalid,
+        "rows": reports
+    })))
+}
+
+fn read_csv(bytes: &[u8]) -> Result<Vec<Vec<String>>, Response> {
+    let bytes = bytes
+        .strip_prefix(b"\xEF\xBB\xBF".as_slice())
+        .unwrap_or(bytes);
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(bytes);
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| Response::invalid_format(format!("CSV解析失败：{e}")))?;
+        rows.push(record.iter().map(|s| s.trim().to_owned()).collect());
+    }
+    Ok(rows)
+}
+
+fn read_excel(bytes: &[u8]) -> Result<Vec<Vec<String>>, Response> {
+    let mut workbook = open_workbook_auto_from_rs(Cursor::new(bytes))
+        .map_err(|e| Response::invalid_format(format!("Excel解析失败：{e}")))?;
+    let range = op::some!(workbook.worksheet_range_at(0); ret Err(Response::invalid_value("Excel中没有工作表")))
+        .map_err(|e| Response::invalid_format(format!("Excel解析失败：{e}")))?;
+    Ok(range
+        .rows()
+        .map(|row| row.iter().map(cell_to_string).collect())
+        .collect())
+}
+
+fn cell_to_string(cell: &Data) -> String {
+    match cell {
+        // 手机号等数字经常被Excel存储为浮点数
+        Data::Float(f) if f.fract() == 0.0 => format!("{}", *f as i64),
+        Data::DateTime(t) => t
+            .as_datetime()
+            .map(|t| t.format("%Y-%m-%d").to_string())
+            .unwrap_or_default(),
+        c => c.to_string().trim().to_owned(),
+    }
+}
+
+enum Column {
+    Field(&'static str),
+    /// 自定义字段，(texts|times|boxes, 字段名)
+    Custom(&'static str, String),
+    Ignore,
+}
+
+fn map_headers(head: &[String]) -> Vec<Column> {
+    let (texts, times, boxes) = unsafe { (*addr_of!(STATIC_CUSTOM_FIELDS)).get_fields(0) };
+    head.iter()
+        .map(|h| {
+            let h = h.trim();
+            if let Some((k, ..)) = HEADERS
+                .iter()
+                .find(|(k, names, _)| k.eq(&h) || names.contains(&h))
+            {
+                Column::Field(k)
+            } else if texts.contains(&h) {
+                Column::Custom("texts", h.to_owned())
+            } else if times.contains(&h) {
+                Column::Custom("times", h.to_owned())
+            } else if boxes.contains(&h) {
+                Column::Custom("boxes", h.to_owned())
+            } else {
+                Column::Ignore
+            }
+        })
+        .collect()
+}
+
+/// 将一行数据转换成`InsertParams`对应的JSON，未出现的自定义字段填充空字符串
+fn to_record(columns: &[Column], row: &[String], uid: &str) -> Map<String, Value> {
+    let mut record = Map::new();
+    for (k, ..) in HEADERS {
+        record.insert(k.to_owned(), json!(""));
+    }
+    record.insert("salesman".to_owned(), json!(uid));
+    let (texts, times, boxes) = unsafe { (*addr_of!(STATIC_CUSTOM_FIELDS)).get_fields(0) };
+    let mut custom = Map::new();
+    for (k, fields) in [("texts", texts), ("times", times), ("boxes", boxes)] {
+        let fields: Vec<Value> = fields
+            .iter()
+            .map(|f| {
+                let value = columns
+                    .iter()
+                    .zip(row)
+                    .find(|(c, _)| matches!(c, Column::Custom(t, d) if *t == k && d == f))
+                    .map_or("", |(_, v)| v.as_str());
+                json!({"display": f, "value": value})
+            })
+            .collect();
+        custom.insert(k.to_owned(), Value::Array(fields));
+    }
+    for (c, v) in columns.iter().zip(row) {
+        if let Column::Field(k) = c {
+            let value = match *k {
+                "is_share" => json!(matches!(v.as_str(), "是" | "true" | "1")),
+                "sex" => json!(matches!(v.as_str(), "男" | "true" | "1")),
+                // 表格中的生日可能是完整日期，只保留月-日
+                "birthday" if v.len() == 10 && v.is_ascii() => json!(v[5..]),
+                // 负责人固定为导入者
+                "salesman" => continue,
+                _ => json!(v),
+            };
+            record.insert(k.to_string(), value);
+        }
+    }
+    record.insert("custom_fields".to_owned(), Value::Object(custom));
+    record
+}
+
+fn validate(record: &Map<String, Value>) -> Result<(), String> {
+    let get = |k: &str| record.get(k).and_then(Value::as_str).unwrap_or_default();
+    let smartphone = get("smartphone");
+    if smartphone.is_empty() || smartphone.len() > 15 {
+        return Err("手机号为空或者格式错误".into());
+    }
+    if get("name").is_empty() {
+        return Err("客户名不能为空".into());
+    }
+    for (k, names, drop_down) in HEADERS {
+        if let Some(key) = drop_down {
+            if check_drop_down_box(key, get(k)) == Some(false) {
+                return Err(format!("{}`{}`不是可选值", names[0], get(k)));
+            }
+        }
+    }
+    if let Some(boxes) = op::catch!(record.get("custom_fields")?.get("boxes")?.as_array()) {
+        for f in boxes {
+            let display = f["display"].as_str().unwrap_or_default();
+            let value = f["value"].as_str().unwrap_or_default();
+            if !value.is_empty()
+                && unsafe { !(*addr_of!(STATIC_CUSTOM_BOX_OPTIONS)).contains(0, display, value) }
+            {
+                return Err(format!("{display}`{value}`不是可选值"));
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,9 +1,9 @@
 use std::collections::HashMap;
 
 use axum::{
-    extract::{Multipart, Path},
+    extract::Path,
     http::HeaderMap,
-    routing::post,
+    routing::{delete, post},
     Json, Router,
 };
 use chrono::{Days, TimeZone};
@@ -16,11 +16,22 @@ use crate::{
     bearer, catch, commit_or_rollback,
     database::{get_db, DB},
     get_cache,
-    libs::{gen_id, parse_multipart, TimeFormat, TIME},
+    libs::{
+        cache::{ORDER_CACHE, ORDER_CACHE_WITH_ID},
+        gen_id, TimeFormat, TIME,
+    },
     log,
     pages::{
         account::{get_user, User},
-        func::{__update_custom_fields, customer::CUSTOMER_CACHE, get_custom_fields},
+        func::{
+            __update_custom_fields,
+            customer::{
+                appointment::__remove_appointment, sea::__push_to_sea, timeline::__record_changes,
+                CUSTOMER_CACHE,
+            },
+            get_custom_fields,
+            search::{__index_customer, __remove_index},
+        },
     },
     parse_jwt_macro,
     perm::{action::CustomerGroup, roles::role_to_name},
@@ -33,7 +44,8 @@ pub fn customer_router() -> Router {
         .route("/customer/full/data/:id", post(query_full_data))
         .route("/customer/update", post(update_customer))
         .route("/customer/add", post(insert_customer))
-        .route("/customer/upload/excel", post(upload_excel))
+        .route("/customer/delete/:id", delete(delete_customer))
+        .route("/customer/release/:id", post(release_customer))
 }
 
 use crate::libs::dser::{
@@ -142,7 +154,7 @@ impl mysql::prelude::FromValue for CustomCustomerData {
     type Intermediate = String;
 }
 
-fn __insert_customer(conn: &mut PooledConn, table: &InsertParams) -> Result<(), Response> {
+pub(super) fn __insert_customer(conn: &mut PooledConn, table: &InsertParams) -> Result<(), Response> {
     let time = TIME::now()?;
     let id = gen_id(&time, &table.name);
     let create_time = time.format(TimeFormat::YYYYMMDD_HHMMSS);
@@ -190,13 +202,14 @@ fn __insert_customer(conn: &mut PooledConn, table: &InsertParams) -> Result<(),
             "added_date" => time.format(TimeFormat::YYYYMMDD)
         },
     )?;
+    __index_customer(conn, &id)?;
 
     crate::pages::func::__insert_custom_fields(conn, &table.custom_fields, 0, &id)?;
     Ok(())
 }
 
 #[derive(Deserialize, Debug)]
-struct InsertParams {
+pub(super) struct InsertParams {
     smartphone: String,
     name: String,
     company: String,
@@ -323,6 +336,8 @@ struct FullCustomerData {
     pub last_visited_time: Option<String>,
     #[serde(serialize_with = "serialize_null_to_default")]
     pub last_transaction_time: Option<String>,
+    pub push_to_sea_date: Option<String>,
+    pub pop_from_sea_date: Option<String>,
     pub custom_fields: CustomCustomerData,
 }
 fn __query_full_data(
@@ -334,13 +349,14 @@ fn __query_full_data(
     // 会出现重复数据，目前测试数据正确
     let query = format!(
         "SELECT DISTINCT c.*, ex.salesman, ex.last_transaction_time, 
+            ex.push_to_sea_date, ex.pop_from_sea_date,
             MIN(app.appointment) as next_visit_time, COUNT(cou.id) as visited_count,
             MAX(cou.appointment) as last_visited_time, 1 as custom_fields,
             uu.name as salesman_name,
             1 as colleagues
             FROM customer c 
             JOIN extra_customer_data ex ON ex.id = c.id 
-            JOIN user uu ON uu.id = ex.salesman 
+            LEFT JOIN user uu ON uu.id = ex.salesman 
             LEFT JOIN appointment app ON app.customer = c.id AND app.salesman=ex.salesman
                 AND app.appointment > '{today}' AND app.finish_time IS NULL
             LEFT JOIN appointment cou ON cou.customer = c.id AND cou.salesman=ex.salesman
@@ -362,6 +378,12 @@ async fn query_full_data(header: HeaderMap, Path(id): Path<String>) -> ResponseR
     let mut conn = db.lock().await;
     let uid = parse_jwt_macro!(&bearer, &mut conn => true);
     let user = get_user(&uid, &mut conn).await?;
+    if check_user_customer(&uid, &id, &mut conn).is_err()
+        && !__visible_customer(&mut conn, &user, &id).await?
+    {
+        log!("{user} 查询客户`{}`的信息失败，原因权限不足", id);
+        return Err(Response::permission_denied());
+    }
     if let Some(value) = get_cache!(CUSTOMER_CACHE, "full", &id) {
         log!("{user} 成功查询到客户`{}`的信息 缓存", id);
         Ok(Response::ok(value.clone()))
@@ -378,8 +400,33 @@ async fn query_full_data(header: HeaderMap, Path(id): Path<String>) -> ResponseR
     }
 }
 
+/// 公海中的客户对所有人可见，其他客户按照查询权限的数据范围判断
+pub(crate) async fn __visible_customer<'err>(
+    conn: &mut DB<'err>,
+    user: &User,
+    customer: &str,
+) -> Result<bool, Response> {
+    let owner: Option<(Option<String>, Option<String>)> = conn.exec_first(
+        "SELECT ex.salesman, u.department FROM extra_customer_data ex
+        LEFT JOIN user u ON u.id = ex.salesman WHERE ex.id = ? LIMIT 1",
+        (customer,),
+    )?;
+    let (salesman, department) = op::some!(owner; ret Ok(true));
+    if salesman.is_none() {
+        return Ok(true);
+    }
+    let (depart, root) = verify_perms!(
+        &user.role,
+        CustomerGroup::NAME,
+        CustomerGroup::QUERY,
+        None,
+        Some(["all"].as_slice())
+    );
+    Ok(root || depart && department.is_some_and(|d| d == user.department))
+}
+
 #[derive(Deserialize)]
-struct QueryParams {
+pub(super) struct QueryParams {
     status: Option<String>,
     ty: Option<String>,
     ap: i32,
@@ -389,6 +436,37 @@ struct QueryParams {
     is_share: Value,
     salesman: String,
     department: String,
+    /// 页码，从1开始，为0时不分页
+    #[serde(default)]
+    pub(super) page: usize,
+    #[serde(default)]
+    limit: usize,
+    /// 排序字段，见`SORT_FIELDS`
+    #[serde(default)]
+    sort: String,
+    /// 是否降序
+    #[serde(default)]
+    desc: bool,
+    /// 匹配客户名、公司、手机号以及同事名
+    #[serde(default)]
+    keyword: String,
+}
+
+/// 客户列表允许排序的字段
+const SORT_FIELDS: [(&str, &str); 5] = [
+    ("create_time", "c.create_time"),
+    ("last_visited_time", "last_visited_time"),
+    ("next_visit_time", "next_visit_time"),
+    ("level", "c.level"),
+    ("visited_count", "visited_count"),
+];
+
+#[derive(Serialize)]
+struct PageData {
+    page: usize,
+    limit: usize,
+    total: usize,
+    records: Vec<ListData>,
 }
 
 macro_rules! __convert {
@@ -446,7 +524,8 @@ macro_rules! __convert {
                 }
             }
         } else if $sales.eq("my") {
-            (format!("='{}'", $u.id), "IS NOT NULL".to_owned())
+            // 包括共享给自己的客户
+            (format!("='{0}' OR EXISTS (SELECT 1 FROM customer_share s WHERE s.customer=ex.id AND s.share_salesman='{0}')", $u.id), "IS NOT NULL".to_owned())
         } else {
             let sl = get_user($sales, $conn).await?;
 
@@ -460,11 +539,11 @@ macro_rules! __convert {
     }
 
 }
-async fn __query_customer_list_data<'err>(
+pub(super) async fn __query_customer_list_data<'err>(
     conn: &mut DB<'err>,
     params: &QueryParams,
     u: &User,
-) -> Result<Vec<ListData>, Response> {
+) -> Result<(Vec<ListData>, usize), Response> {
     let status = __convert!(params.status);
     let ty = __convert!(params.ty);
     let time = TIME::now()?;
@@ -480,6 +559,24 @@ async fn __query_customer_list_data<'err>(
     let (salesman, department) =
         __convert!(params.salesman.as_str(), params.department, u, conn; auto);
     let today = time.format(TimeFormat::YYYYMMDD);
+    let (keyword, like) = if params.keyword.is_empty() {
+        (String::new(), String::new())
+    } else {
+        (
+            "AND (c.name LIKE :kw OR c.company LIKE :kw OR c.smartphone LIKE :kw
+            OR EXISTS (SELECT 1 FROM customer_colleague cc WHERE cc.customer=c.id AND cc.name LIKE :kw))"
+                .to_owned(),
+            format!(
+                "%{}%",
+                params.keyword.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+            ),
+        )
+    };
+    let order = match SORT_FIELDS.iter().find(|(k, _)| params.sort.eq(k)) {
+        Some((_, field)) => format!("ORDER BY {field} {}, c.id", op::ternary!(params.desc => "DESC", "ASC")),
+        None if params.page > 0 => "ORDER BY c.create_time DESC, c.id".to_owned(),
+        None => String::new(),
+    };
 
     let query = format!(
         "SELECT c.*,
@@ -494,11 +591,31 @@ async fn __query_customer_list_data<'err>(
         LEFT JOIN appointment app ON app.customer=c.id AND app.salesman=ex.salesman AND app.appointment>'{today}' AND app.finish_time IS NULL
         LEFT JOIN appointment cou ON cou.customer=c.id AND cou.salesman=ex.salesman AND cou.finish_time IS NOT NULL
         WHERE (c.status {status}) AND (c.ty {ty}) AND NOT EXISTS (select 1 from customer_sea cs where cs.id = c.id)
+        {keyword}
         GROUP BY c.id
+        {order}
         "
     );
-    let list = conn.query(query)?;
-    Ok(list)
+    let args = if like.is_empty() {
+        mysql::Params::Empty
+    } else {
+        params! { "kw" => like }
+    };
+    if params.page == 0 {
+        let list: Vec<ListData> = conn.exec(query, args)?;
+        let total = list.len();
+        return Ok((list, total));
+    }
+    let offset = op::some!(params.page.checked_sub(1).and_then(|p| p.checked_mul(params.limit));
+        ret Err(Response::invalid_value("page或limit的值过大")));
+    let total: usize = conn
+        .exec_first(format!("SELECT COUNT(*) FROM ({query}) t"), args.clone())?
+        .unwrap_or(0);
+    let list = conn.exec(
+        format!("{query} LIMIT {} OFFSET {offset}", params.limit),
+        args,
+    )?;
+    Ok((list, total))
 }
 
 async fn query_customer(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
@@ -509,6 +626,9 @@ async fn query_customer(header: HeaderMap, Json(value): Json<Value>) -> Response
     let user = get_user(&uid, &mut conn).await?;
     let param_str = value.to_string();
     let params: QueryParams = serde_json::from_value(value)?;
+    if params.page > 0 && params.limit == 0 {
+        return Err(Response::invalid_value("limit必须大于0"));
+    }
     log!("{user} 正在查询客户信息");
     let time1 = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -516,8 +636,18 @@ async fn query_customer(header: HeaderMap, Json(value): Json<Value>) -> Response
     let value = if let Some(cache) = get_cache!(CUSTOMER_CACHE, &uid, &param_str) {
         cache.clone()
     } else {
-        let list = __query_customer_list_data(&mut conn, &params, &user).await?;
-        let value = json!(list);
+        let (list, total) = __query_customer_list_data(&mut conn, &params, &user).await?;
+        // 不分页时保持原来的数组格式
+        let value = if params.page == 0 {
+            json!(list)
+        } else {
+            json!(PageData {
+                page: params.page,
+                limit: params.limit,
+                total,
+                records: list
+            })
+        };
         CUSTOMER_CACHE
             .entry(uid)
             .or_default()
@@ -560,10 +690,29 @@ struct UpdateParams {
     tag: String,
     custom_fields: HashMap<String, Vec<Field>>,
 }
+/// 验证客户是否由该用户负责或者共享给了该用户
 pub fn check_user_customer(
     id: &str,
     customer: &str,
     conn: &mut PooledConn,
+) -> Result<(), Response> {
+    let flag: Option<String> = conn.query_first(format!(
+        "SELECT 1 FROM customer c 
+            JOIN extra_customer_data d ON d.id=c.id
+            WHERE c.id='{customer}' AND (d.salesman='{id}' OR EXISTS (
+                SELECT 1 FROM customer_share s WHERE s.customer=c.id AND s.share_salesman='{id}'))"
+    ))?;
+    if flag.is_some() {
+        Ok(())
+    } else {
+        Err(Response::permission_denied())
+    }
+}
+/// 验证客户是否由该用户负责，修改客户信息只能由负责人进行
+pub fn check_owner_customer(
+    id: &str,
+    customer: &str,
+    conn: &mut PooledConn,
 ) -> Result<(), Response> {
     let flag: Option<String> = conn.query_first(format!(
         "SELECT 1 FROM customer c 
@@ -576,6 +725,26 @@ pub fn check_user_customer(
         Err(Response::permission_denied())
     }
 }
+/// 验证用户是否可以为该客户添加拜访或者订单，`right`为customer_share中的appoint或order
+pub fn check_share_right(
+    id: &str,
+    customer: &str,
+    right: &str,
+    conn: &mut PooledConn,
+) -> Result<(), Response> {
+    let flag: Option<String> = conn.query_first(format!(
+        "SELECT 1 FROM customer c 
+            JOIN extra_customer_data d ON d.id=c.id
+            WHERE c.id='{customer}' AND (d.salesman='{id}' OR EXISTS (
+                SELECT 1 FROM customer_share s WHERE s.customer=c.id
+                AND s.share_salesman='{id}' AND s.`{right}` = 1))"
+    ))?;
+    if flag.is_some() {
+        Ok(())
+    } else {
+        Err(Response::permission_denied())
+    }
+}
 async fn update_customer(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
     let bearer = bearer!(&header);
     let db = get_db().await?;
@@ -591,7 +760,7 @@ async fn update_customer(header: HeaderMap, Json(value): Json<Value>) -> Respons
         params.company,
         params.name
     );
-    check_user_customer(&id, &params.id, &mut conn)?;
+    check_owner_customer(&id, &params.id, &mut conn)?;
     if !verify_perms!(
         &user.role,
         CustomerGroup::NAME,
@@ -606,7 +775,7 @@ async fn update_customer(header: HeaderMap, Json(value): Json<Value>) -> Respons
         );
         return Err(Response::permission_denied());
     }
-    commit_or_rollback!(__update_customer, &mut conn, &params)?;
+    commit_or_rollback!(__update_customer, &mut conn, &params, &id)?;
     CUSTOMER_CACHE.clear();
     log!(
         "{}-{} 成功更新客户 `{}-{}`的信息",
@@ -617,7 +786,33 @@ async fn update_customer(header: HeaderMap, Json(value): Json<Value>) -> Respons
     );
     Ok(Response::empty())
 }
-fn __update_customer(conn: &mut PooledConn, params: &UpdateParams) -> Result<(), Response> {
+fn __update_customer(
+    conn: &mut PooledConn,
+    params: &UpdateParams,
+    operator: &str,
+) -> Result<(), Response> {
+    let fields = [
+        ("smartphone", params.smartphone.clone()),
+        ("name", params.name.clone()),
+        ("company", params.company.clone()),
+        ("is_share", params.is_share.to_string()),
+        ("sex", params.sex.to_string()),
+        ("chat", params.chat.clone()),
+        ("level", params.level.clone()),
+        ("need", params.need.clone()),
+        ("fax", params.fax.clone()),
+        ("post", params.post.clone()),
+        ("industry", params.industry.clone()),
+        ("birthday", params.birthday.clone()),
+        ("address", params.address.clone()),
+        ("remark", params.remark.clone()),
+        ("status", params.status.clone()),
+        ("source", params.source.clone()),
+        ("role", params.role.clone()),
+        ("ty", params.ty.clone()),
+        ("tag", params.tag.clone()),
+    ];
+    __record_changes(conn, &params.id, operator, &fields, &params.custom_fields)?;
     conn.exec_drop(
         format!(
             "UPDATE customer SET smartphone=:smartphone, name=:name, company=:company,
@@ -650,13 +845,143 @@ fn __update_customer(conn: &mut PooledConn, params: &UpdateParams) -> Result<(),
         },
     )?;
     __update_custom_fields(conn, &params.custom_fields, 0, &params.id)?;
+    __index_customer(conn, &params.id)?;
     Ok(())
 }
 
-async fn upload_excel(_header: HeaderMap, part: Multipart) -> ResponseResult {
-    let data = parse_multipart(part).await?;
-    for f in &data.files {
-        println!("{:?}", f.filename())
+/// 验证用户是否可以操作该客户，负责人本人可以直接操作，
+/// 否则需要拥有该权限的本部门或者全部数据范围
+pub(super) async fn verify_customer_scope<'err>(
+    conn: &mut DB<'err>,
+    user: &User,
+    customer: &str,
+    action: &str,
+) -> Result<(), Response> {
+    let owner: Option<(Option<String>, Option<String>)> = conn.exec_first(
+        "SELECT ex.salesman, u.department FROM extra_customer_data ex
+        LEFT JOIN user u ON u.id = ex.salesman WHERE ex.id = ? LIMIT 1",
+        (customer,),
+    )?;
+    let (salesman, department) = op::some!(owner; ret Err(Response::not_exist("客户不存在")));
+    let (perm, depart, root) = verify_perms!(
+        &user.role,
+        CustomerGroup::NAME,
+        action,
+        None,
+        Some(["department"].as_slice()),
+        Some(["all"].as_slice())
+    );
+    if root
+        || perm && salesman.is_some_and(|s| s == user.id)
+        || depart && department.is_some_and(|d| d == user.department)
+    {
+        Ok(())
+    } else {
+        Err(Response::permission_denied())
+    }
+}
+
+async fn delete_customer(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    log!("{user} 请求删除客户 {id}");
+    if let Err(e) =
+        verify_customer_scope(&mut conn, &user, &id, CustomerGroup::DELETE_CUSTOMER_DATA).await
+    {
+        log!("{user} 删除客户 {id} 失败，原因权限不足或客户不存在");
+        return Err(e);
+    }
+    let ordered: Option<i32> = conn.exec_first(
+        "SELECT 1 FROM order_data WHERE customer = ? AND status != 0 LIMIT 1",
+        (&id,),
+    )?;
+    if ordered.is_some() {
+        log!("{user} 删除客户 {id} 失败，原因该客户存在非意向订单");
+        return Err(Response::dissatisfy("该客户存在非意向订单，无法删除"));
+    }
+    let files = commit_or_rollback!(__delete_customer, &mut conn, &id)?;
+    for f in files {
+        let _ = std::fs::remove_file(f);
+    }
+    CUSTOMER_CACHE.clear();
+    ORDER_CACHE.clear();
+    ORDER_CACHE_WITH_ID.clear();
+    log!("{user} 成功删除客户 {id}");
+    Ok(Response::empty())
+}
+
+/// 删除客户以及相关的数据，返回需要删除的意向订单文件、拜访附件和签到照片
+fn __delete_customer(conn: &mut PooledConn, id: &str) -> Result<Vec<String>, Response> {
+    let files: Vec<Option<String>> = conn.exec(
+        "SELECT file FROM order_data WHERE customer = ? AND status = 0",
+        (id,),
+    )?;
+    for table in ["order_product", "order_instalment", "invoice"] {
+        conn.exec_drop(
+            format!(
+                "DELETE FROM {table} WHERE order_id IN
+                (SELECT o.id FROM order_data o WHERE o.customer = ? AND o.status = 0)"
+            ),
+            (id,),
+        )?;
     }
+    conn.exec_drop("DELETE FROM order_data WHERE customer = ? AND status = 0", (id,))?;
+    let appoints: Vec<String> =
+        conn.exec("SELECT id FROM appointment WHERE customer = ?", (id,))?;
+    let mut photos = Vec::new();
+    for appoint in appoints {
+        photos.extend(__remove_appointment(conn, &appoint)?);
+    }
+    conn.exec_drop("DELETE FROM appoint_series WHERE customer = ?", (id,))?;
+    conn.exec_drop("DELETE FROM customer_colleague WHERE customer = ?", (id,))?;
+    conn.exec_drop(
+        "DELETE FROM custom_field_data WHERE fields = 0 AND id = ?",
+        (id,),
+    )?;
+    conn.exec_drop("DELETE FROM customer_share WHERE customer = ?", (id,))?;
+    conn.exec_drop(
+        "DELETE FROM price_list WHERE ty = 'customer' AND scope = ?",
+        (id,),
+    )?;
+    conn.exec_drop("DELETE FROM customer_sea WHERE id = ?", (id,))?;
+    conn.exec_drop("DELETE FROM customer_transfer WHERE customer = ?", (id,))?;
+    conn.exec_drop("DELETE FROM customer_change_log WHERE customer = ?", (id,))?;
+    conn.exec_drop("DELETE FROM extra_customer_data WHERE id = ? LIMIT 1", (id,))?;
+    conn.exec_drop("DELETE FROM customer WHERE id = ? LIMIT 1", (id,))?;
+    __remove_index(conn, "customer", id)?;
+    Ok(files
+        .into_iter()
+        .flatten()
+        .map(|f| format!("resources/order/{f}"))
+        .chain(photos)
+        .collect())
+}
+
+async fn release_customer(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    log!("{user} 请求将客户 {id} 释放到公海");
+    if let Err(e) =
+        verify_customer_scope(&mut conn, &user, &id, CustomerGroup::RELEASE_CUSTOMER).await
+    {
+        log!("{user} 释放客户 {id} 失败，原因权限不足或客户不存在");
+        return Err(e);
+    }
+    let salesman: Option<Option<String>> = conn.exec_first(
+        "SELECT salesman FROM extra_customer_data WHERE id = ? LIMIT 1",
+        (&id,),
+    )?;
+    let salesman = op::some!(salesman.flatten(); ret Err(Response::dissatisfy("该客户已经处于公海中")));
+    let time = TIME::now()?;
+    commit_or_rollback!(__push_to_sea, &mut conn, &id, Some(salesman.as_str()), &time)?;
+    CUSTOMER_CACHE.clear();
+    log!("{user} 成功将客户 {id} 释放到公海");
     Ok(Response::empty())
 }
+
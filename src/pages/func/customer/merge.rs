@@ -0,0 +1,434 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::{http::HeaderMap, routing::post, Json, Router};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::get_db,
+    libs::{
+        cache::{ORDER_CACHE, ORDER_CACHE_WITH_ID},
+        gen_id, TimeFormat, TIME,
+    },
+    log,
+    pages::{account::get_user, func::search::__remove_index},
+    parse_jwt_macro,
+    perm::action::CustomerGroup,
+    verify_perms, Response, ResponseResult,
+};
+
+use super::{index::verify_customer_scope, CUSTOMER_CACHE};
+
+pub fn merge_router() -> Router {
+    Router::new()
+        .route("/customer/duplicate/candidates", post(query_candidates))
+        .route("/customer/merge", post(merge_customer))
+}
+
+#[derive(Deserialize)]
+struct CandidateParams {
+    /// 只查找与该客户重复的客户，为空时查找全部
+    #[serde(default)]
+    customer: String,
+    #[serde(default = "default_threshold")]
+    threshold: f64,
+    #[serde(default = "default_limit")]
+    limit: usize,
+    /// 客户对按照id排序，每页计算一部分，为0时只计算第一页
+    #[serde(default)]
+    page: usize,
+}
+
+fn default_threshold() -> f64 {
+    0.6
+}
+fn default_limit() -> usize {
+    50
+}
+
+#[derive(FromRow)]
+struct Profile {
+    id: String,
+    name: String,
+    company: String,
+    smartphone: String,
+    address: String,
+}
+
+#[derive(Serialize)]
+struct Candidate {
+    customer: String,
+    customer_name: String,
+    customer_company: String,
+    other: String,
+    other_name: String,
+    other_company: String,
+    score: f64,
+    reasons: Vec<&'static str>,
+}
+
+/// 公司名称中常见的后缀，比较相似度时忽略
+const COMPANY_SUFFIX: [&str; 7] = [
+    "有限责任公司",
+    "股份有限公司",
+    "有限公司",
+    "分公司",
+    "公司",
+    "集团",
+    "工作室",
+];
+
+fn normalize(s: &str) -> Vec<char> {
+    s.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn normalize_company(s: &str) -> Vec<char> {
+    let mut s = s.trim().to_owned();
+    for suffix in COMPANY_SUFFIX {
+        if let Some(stripped) = s.strip_suffix(suffix) {
+            s = stripped.to_owned();
+        }
+    }
+    normalize(&s)
+}
+
+/// 基于编辑距离的相似度，范围0~1，任意一方为空时为0
+fn similarity(a: &[char], b: &[char]) -> f64 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = op::ternary!(ca == cb => 0, 1);
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    1.0 - prev[b.len()] as f64 / a.len().max(b.len()) as f64
+}
+
+/// 计算两位客户的重复得分，公司占0.45，姓名占0.25，
+/// 存在相同的手机号(包括同事)占0.2，地址相同占0.1
+fn score(
+    a: &Profile,
+    b: &Profile,
+    phones_a: &HashSet<String>,
+    phones_b: &HashSet<String>,
+) -> (f64, Vec<&'static str>) {
+    let mut score = 0.0;
+    let mut reasons = Vec::new();
+    let company = similarity(
+        &normalize_company(&a.company),
+        &normalize_company(&b.company),
+    );
+    if company >= 0.8 {
+        reasons.push("company");
+    }
+    score += company * 0.45;
+    let name = similarity(&normalize(&a.name), &normalize(&b.name));
+    if name >= 0.8 {
+        reasons.push("name");
+    }
+    score += name * 0.25;
+    if !phones_a.is_disjoint(phones_b) {
+        reasons.push("phone");
+        score += 0.2;
+    }
+    if similarity(&normalize(&a.address), &normalize(&b.address)) >= 0.9 {
+        reasons.push("address");
+        score += 0.1;
+    }
+    (score, reasons)
+}
+
+/// 可能重复的客户的分组键：手机号(包括同事)、姓名前两个字以及公司名前四个字，
+/// 只对分组键相同的客户计算得分，避免两两比较所有客户。
+/// 只包括自己可见的客户，公海客户对所有人可见
+const KEY_SQL: &str = "SELECT k.customer, k.k FROM (
+        SELECT id AS customer, CONCAT('p', smartphone) AS k FROM customer WHERE smartphone != ''
+        UNION SELECT customer, CONCAT('p', phone) FROM customer_colleague WHERE phone != ''
+        UNION SELECT id, CONCAT('n', LEFT(name, 2)) FROM customer WHERE name != ''
+        UNION SELECT id, CONCAT('c', LEFT(company, 4)) FROM customer WHERE company != ''
+    ) k JOIN extra_customer_data ex ON ex.id = k.customer
+    LEFT JOIN user u ON u.id = ex.salesman
+    WHERE :root OR ex.salesman IS NULL OR ex.salesman = :uid
+    OR :depart AND u.department = :department";
+
+/// 每页最多计算得分的客户对数量
+const MAX_PAIRS: usize = 5000;
+
+async fn query_candidates(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: CandidateParams = serde_json::from_value(value)?;
+    log!("{user} 请求查找重复客户");
+    let (depart, root) = verify_perms!(
+        &user.role,
+        CustomerGroup::NAME,
+        CustomerGroup::QUERY,
+        None,
+        Some(["all"].as_slice())
+    );
+    let offset = op::some!(params.page.saturating_sub(1).checked_mul(MAX_PAIRS);
+        ret Err(Response::invalid_value("page的值过大")));
+    // 多取一对用于判断是否还有下一页
+    let mut pairs: Vec<(String, String)> = conn.exec(
+        format!(
+            "SELECT DISTINCT a.customer AS a, b.customer AS b
+            FROM ({KEY_SQL}) a JOIN ({KEY_SQL}) b ON b.k = a.k
+            WHERE a.customer != b.customer
+            AND (a.customer = :customer OR :customer = '' AND a.customer < b.customer)
+            ORDER BY a, b LIMIT {} OFFSET {offset}",
+            MAX_PAIRS + 1
+        ),
+        params! {
+            "customer" => &params.customer,
+            "root" => root,
+            "uid" => &uid,
+            "depart" => depart,
+            "department" => &user.department
+        },
+    )?;
+    let more = pairs.len() > MAX_PAIRS;
+    pairs.truncate(MAX_PAIRS);
+    let ids: HashSet<&str> = pairs
+        .iter()
+        .flat_map(|(a, b)| [a.as_str(), b.as_str()])
+        .collect();
+    let ids: Vec<&str> = ids.into_iter().collect();
+    let mut profiles: HashMap<String, Profile> = HashMap::new();
+    let mut phones: HashMap<String, HashSet<String>> = HashMap::new();
+    for chunk in ids.chunks(1000) {
+        let marks = vec!["?"; chunk.len()].join(", ");
+        let list: Vec<Profile> = conn.exec(
+            format!(
+                "SELECT id, name, company, smartphone, address FROM customer WHERE id IN ({marks})"
+            ),
+            chunk.to_vec(),
+        )?;
+        for p in list {
+            phones
+                .entry(p.id.clone())
+                .or_default()
+                .insert(p.smartphone.clone());
+            profiles.insert(p.id.clone(), p);
+        }
+        let colleagues: Vec<(String, String)> = conn.exec(
+            format!("SELECT customer, phone FROM customer_colleague WHERE customer IN ({marks})"),
+            chunk.to_vec(),
+        )?;
+        for (customer, phone) in colleagues {
+            phones.entry(customer).or_default().insert(phone);
+        }
+    }
+    let empty = HashSet::new();
+    let mut candidates = Vec::new();
+    for (a, b) in &pairs {
+        let (Some(a), Some(b)) = (profiles.get(a), profiles.get(b)) else {
+            continue;
+        };
+        let (s, reasons) = score(
+            a,
+            b,
+            phones.get(&a.id).unwrap_or(&empty),
+            phones.get(&b.id).unwrap_or(&empty),
+        );
+        if s >= params.threshold {
+            candidates.push(Candidate {
+                customer: a.id.clone(),
+                customer_name: a.name.clone(),
+                customer_company: a.company.clone(),
+                other: b.id.clone(),
+                other_name: b.name.clone(),
+                other_company: b.company.clone(),
+                score: (s * 100.0).round() / 100.0,
+                reasons,
+            });
+        }
+    }
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    candidates.truncate(params.limit);
+    log!("{user} 成功查找到{}组重复客户", candidates.len());
+    // 不分页时保持原来的数组格式
+    if params.page == 0 {
+        return Ok(Response::ok(json!(candidates)));
+    }
+    Ok(Response::ok(json!({
+        "page": params.page,
+        "more": more,
+        "records": candidates
+    })))
+}
+
+#[derive(Deserialize)]
+struct MergeParams {
+    /// 保留的客户
+    target: String,
+    /// 被合并的客户，合并后删除
+    source: String,
+}
+
+async fn merge_customer(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: MergeParams = serde_json::from_value(value)?;
+    log!(
+        "{user} 请求将客户 {} 合并到 {}",
+        params.source,
+        params.target
+    );
+    if params.source == params.target {
+        return Err(Response::invalid_value("不能合并同一位客户"));
+    }
+    for (id, action) in [
+        (&params.target, CustomerGroup::UPDATE_CUSTOMER_DATA),
+        (&params.source, CustomerGroup::DELETE_CUSTOMER_DATA),
+    ] {
+        if let Err(e) = verify_customer_scope(&mut conn, &user, id, action).await {
+            log!("{user} 合并客户失败，原因没有权限操作客户 {id}");
+            return Err(e);
+        }
+    }
+    commit_or_rollback!(__merge_customer, &mut conn, &params)?;
+    CUSTOMER_CACHE.clear();
+    ORDER_CACHE.clear();
+    ORDER_CACHE_WITH_ID.clear();
+    log!(
+        "{user} 成功将客户 {} 合并到 {}",
+        params.source,
+        params.target
+    );
+    Ok(Response::empty())
+}
+
+fn __merge_customer(conn: &mut PooledConn, params: &MergeParams) -> Result<(), Response> {
+    let (target, source) = (&params.target, &params.source);
+    let time = TIME::now()?;
+    // 被合并客户的手机号作为同事保留下来
+    let info: Option<(String, String)> = conn.exec_first(
+        "SELECT name, smartphone FROM customer WHERE id = ? LIMIT 1",
+        (source,),
+    )?;
+    let (name, phone) = op::some!(info; ret Err(Response::not_exist("客户不存在")));
+    let exists: Option<i32> = conn.exec_first(
+        "SELECT 1 FROM customer_colleague WHERE customer = ? AND phone = ? LIMIT 1",
+        (target, &phone),
+    )?;
+    if exists.is_none() {
+        conn.exec_drop(
+            "INSERT INTO customer_colleague (id, customer, phone, name, create_time)
+            VALUES (?, ?, ?, ?, ?)",
+            (
+                gen_id(&time, "colleague"),
+                target,
+                &phone,
+                name.chars().take(10).collect::<String>(),
+                time.format(TimeFormat::YYYYMMDD_HHMMSS),
+            ),
+        )?;
+    }
+    conn.exec_drop(
+        "UPDATE customer_colleague SET customer = ? WHERE customer = ?",
+        (target, source),
+    )?;
+    // 拜访评论通过拜访关联，转移拜访即可
+    conn.exec_drop(
+        "UPDATE appointment SET customer = ? WHERE customer = ?",
+        (target, source),
+    )?;
+    conn.exec_drop(
+        "UPDATE appoint_series SET customer = ? WHERE customer = ?",
+        (target, source),
+    )?;
+    conn.exec_drop(
+        "UPDATE order_data SET customer = ? WHERE customer = ?",
+        (target, source),
+    )?;
+    conn.exec_drop("UPDATE report SET ac = ? WHERE ac = ?", (target, source))?;
+    conn.exec_drop(
+        "UPDATE customer_transfer SET customer = ? WHERE customer = ?",
+        (target, source),
+    )?;
+    conn.exec_drop(
+        "UPDATE customer_change_log SET customer = ? WHERE customer = ?",
+        (target, source),
+    )?;
+    // 自定义字段只补充保留客户中为空的值
+    conn.exec_drop(
+        "UPDATE custom_field_data t JOIN custom_field_data s
+        ON s.fields = 0 AND s.id = ? AND s.ty = t.ty AND s.display = t.display
+        SET t.value = s.value
+        WHERE t.fields = 0 AND t.id = ? AND t.value = ''",
+        (source, target),
+    )?;
+    conn.exec_drop(
+        "DELETE FROM custom_field_data WHERE fields = 0 AND id = ?",
+        (source,),
+    )?;
+    conn.exec_drop(
+        "UPDATE extra_customer_data t JOIN extra_customer_data s ON s.id = ?
+        SET t.last_transaction_time = s.last_transaction_time
+        WHERE t.id = ? AND s.last_transaction_time IS NOT NULL
+        AND (t.last_transaction_time IS NULL OR s.last_transaction_time > t.last_transaction_time)",
+        (source, target),
+    )?;
+    conn.exec_drop(
+        "UPDATE customer t JOIN customer s ON s.id = ?
+        SET t.latitude = s.latitude, t.longitude = s.longitude
+        WHERE t.id = ? AND t.latitude IS NULL AND s.latitude IS NOT NULL",
+        (source, target),
+    )?;
+    // 共享记录合并到保留客户，负责人自身不需要共享记录
+    conn.exec_drop(
+        "INSERT IGNORE INTO customer_share (customer, share_salesman, appoint, `order`, create_time)
+        SELECT ?, s.share_salesman, s.appoint, s.`order`, s.create_time
+        FROM customer_share s JOIN extra_customer_data ex ON ex.id = ?
+        WHERE s.customer = ? AND (ex.salesman IS NULL OR ex.salesman != s.share_salesman)",
+        (target, target, source),
+    )?;
+    conn.exec_drop("DELETE FROM customer_share WHERE customer = ?", (source,))?;
+    super::share::__sync_share_state(conn, target)?;
+    // 指定客户的价格表合并到保留客户，相同的产品和起订量以保留客户的为准
+    conn.exec_drop(
+        "INSERT IGNORE INTO price_list (product, ty, scope, min_amount, price, create_time)
+        SELECT product, ty, ?, min_amount, price, create_time FROM price_list
+        WHERE ty = 'customer' AND scope = ?",
+        (target, source),
+    )?;
+    conn.exec_drop(
+        "DELETE FROM price_list WHERE ty = 'customer' AND scope = ?",
+        (source,),
+    )?;
+    conn.exec_drop("DELETE FROM customer_sea WHERE id = ?", (source,))?;
+    conn.exec_drop(
+        "DELETE FROM extra_customer_data WHERE id = ? LIMIT 1",
+        (source,),
+    )?;
+    conn.exec_drop("DELETE FROM customer WHERE id = ? LIMIT 1", (source,))?;
+    __remove_index(conn, "customer", source)?;
+    Ok(())
+}
+
+#[test]
+fn test_similarity() {
+    let a = normalize_company("深圳市腾讯科技有限公司");
+    let b = normalize_company("深圳腾讯科技");
+    assert!(similarity(&a, &b) > 0.8);
+    assert_eq!(similarity(&normalize("张三"), &normalize("张三")), 1.0);
+    assert_eq!(similarity(&normalize(""), &normalize("张三")), 0.0);
+    assert!(similarity(&normalize("张三"), &normalize("李四")) < 0.1);
+}
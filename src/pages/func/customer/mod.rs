@@ -1,13 +1,38 @@
 mod appointment;
+mod attachment;
+mod calendar;
 mod colleague;
+mod export;
+mod import;
 pub mod index;
+mod merge;
+mod sea;
+mod series;
+mod share;
+mod sign;
+mod timeline;
+mod transfer;
 
 use axum::Router;
 use crate::libs::cache::CUSTOMER_CACHE;
-use self::{appointment::appointment_router, colleague::colleague_router};
+use self::{appointment::appointment_router, attachment::attachment_router, calendar::calendar_router, colleague::colleague_router, export::export_router, import::import_router, merge::merge_router, sea::sea_router, series::series_router, share::share_router, sign::sign_router, timeline::timeline_router,
+    transfer::transfer_router};
+pub use appointment::notify_appointments;
+pub use sea::auto_push_to_sea;
 
 pub fn customer_router() -> Router {
     index::customer_router()
         .merge(colleague_router())
         .merge(appointment_router())
+        .merge(import_router())
+        .merge(export_router())
+        .merge(sea_router())
+        .merge(share_router())
+        .merge(transfer_router())
+        .merge(merge_router())
+        .merge(timeline_router())
+        .merge(calendar_router())
+        .merge(sign_router())
+        .merge(series_router())
+        .merge(attachment_router())
 }
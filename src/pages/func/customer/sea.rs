@@ -0,0 +1,235 @@
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{Days, TimeZone};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::{__get_conn, get_db},
+    libs::{dser::serialize_i32_to_bool, TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    perm::action::{CustomerGroup, OtherGroup},
+    verify_perms, Response, ResponseResult, SEA_MAX_DAY, SEA_MIN_DAY,
+};
+
+use super::CUSTOMER_CACHE;
+
+pub fn sea_router() -> Router {
+    Router::new()
+        .route("/customer/sea/list", post(query_sea_customer))
+        .route("/customer/sea/pop/:id", post(pop_from_sea))
+        .route("/customer/sea/rule", get(get_sea_rule))
+        .route("/customer/sea/rule/set", post(set_sea_rule))
+}
+
+/// 将客户移入公海，同时清除负责人
+pub fn __push_to_sea(
+    conn: &mut PooledConn,
+    id: &str,
+    salesman: Option<&str>,
+    time: &TIME,
+) -> Result<(), Response> {
+    let date = time.format(TimeFormat::YYYYMMDD_HHMMSS);
+    conn.exec_drop(
+        "insert into customer_sea (id, salesman, push_to_sea_date) values (?, ?, ?)",
+        (id, salesman, &date),
+    )?;
+    conn.exec_drop(
+        "update extra_customer_data set salesman = NULL, push_to_sea_date = ? where id = ? limit 1",
+        (&date, id),
+    )?;
+    Ok(())
+}
+
+/// 定时任务，将超过公海规则天数仍未拜访且未成交的客户移入公海
+pub fn auto_push_to_sea() {
+    let (max_day, min_day) = unsafe { (SEA_MAX_DAY, SEA_MIN_DAY) };
+    let result = __get_conn()
+        .map_err(Response::from)
+        .and_then(|mut conn| commit_or_rollback!(__auto_push_to_sea, &mut conn, max_day, min_day));
+    match result {
+        Ok(0) => (),
+        Ok(count) => log!("已将 {count} 位长期未跟进的客户移入公海"),
+        Err(e) => log!("移入公海失败，错误信息：{:?}", e),
+    }
+}
+
+fn __auto_push_to_sea(conn: &mut PooledConn, max_day: u64, min_day: u64) -> Result<usize, Response> {
+    let time = TIME::now()?;
+    let local = chrono::Local.timestamp_nanos(time.naos() as i64);
+    let line = |days: u64| {
+        local
+            .checked_sub_days(Days::new(days))
+            .map(|t| TIME::from(t).format(TimeFormat::YYYYMMDD))
+            .ok_or(Response::invalid_value("天数错误"))
+    };
+    let visit_line = line(min_day)?;
+    let tran_line = line(max_day)?;
+    // 负责时间从添加客户或者从公海领取的时间开始计算
+    let customers: Vec<(String, String)> = conn.exec(
+        "select ex.id, ex.salesman from extra_customer_data ex
+        where ex.salesman is not null
+        and not exists (select 1 from customer_sea cs where cs.id = ex.id)
+        and greatest(ifnull(ex.added_date, ''), ifnull(ex.pop_from_sea_date, '')) < ?
+        and greatest(ifnull(ex.added_date, ''), ifnull(ex.pop_from_sea_date, '')) < ?
+        and not exists (select 1 from appointment a
+            where a.customer = ex.id and a.finish_time >= ?)
+        and not exists (select 1 from order_data o
+            where o.customer = ex.id and o.status > 0 and o.transaction_date >= ?)",
+        (&visit_line, &tran_line, &visit_line, &tran_line),
+    )?;
+    for (id, salesman) in &customers {
+        __push_to_sea(conn, id, Some(salesman), &time)?;
+    }
+    if !customers.is_empty() {
+        CUSTOMER_CACHE.clear();
+    }
+    Ok(customers.len())
+}
+
+#[derive(Deserialize)]
+struct QueryParams {
+    /// 空字符串表示全部部门，my表示本部门
+    department: String,
+}
+
+#[derive(Serialize, FromRow)]
+struct SeaCustomer {
+    id: String,
+    smartphone: String,
+    name: String,
+    company: String,
+    level: String,
+    #[serde(serialize_with = "serialize_i32_to_bool")]
+    sex: i32,
+    address: String,
+    ty: String,
+    status: String,
+    create_time: String,
+    /// 移入公海前的负责人
+    salesman: Option<String>,
+    salesman_name: Option<String>,
+    department: Option<String>,
+    push_to_sea_date: String,
+}
+
+async fn query_sea_customer(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: QueryParams = serde_json::from_value(value)?;
+    log!("{user} 正在查询公海客户");
+    // 部门为None时查询全部部门，unowned表示是否包含没有负责人的公海客户
+    let (department, unowned) =
+        if params.department.eq("my") || params.department == user.department {
+            (Some(user.department.clone()), true)
+        } else if verify_perms!(&user.role, CustomerGroup::NAME, CustomerGroup::QUERY_PUB_SEA) {
+            op::ternary!(params.department.is_empty() => (None, true);
+                (Some(params.department.clone()), false))
+        } else {
+            log!("{user} 查询其他部门的公海客户失败，原因权限不足");
+            return Err(Response::permission_denied());
+        };
+    let data: Vec<SeaCustomer> = conn.exec(
+        "select c.id, c.smartphone, c.name, c.company, c.level, c.sex, c.address, c.ty,
+            c.status, c.create_time, cs.salesman, u.name as salesman_name,
+            u.department, cs.push_to_sea_date
+        from customer c
+        join customer_sea cs on cs.id = c.id
+        left join user u on u.id = cs.salesman
+        where (:department is null or u.department = :department
+            or (:unowned and cs.salesman is null))
+        order by cs.push_to_sea_date desc",
+        params! {
+            "department" => department,
+            "unowned" => unowned
+        },
+    )?;
+    log!("{user} 成功查询到{}位公海客户", data.len());
+    Ok(Response::ok(json!(data)))
+}
+
+async fn pop_from_sea(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    log!("{user} 请求领取公海客户 {id}");
+    let department: Option<Option<String>> = conn.exec_first(
+        "select u.department from customer_sea cs
+        left join user u on u.id = cs.salesman
+        where cs.id = ? limit 1",
+        (&id,),
+    )?;
+    let department = op::some!(department; ret Err(Response::not_exist("该客户不在公海中")));
+    if department.is_some_and(|d| d != user.department)
+        && !verify_perms!(&user.role, CustomerGroup::NAME, CustomerGroup::QUERY_PUB_SEA)
+    {
+        log!("{user} 领取公海客户 {id} 失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    commit_or_rollback!(__pop_from_sea, &mut conn, &id, &uid)?;
+    CUSTOMER_CACHE.clear();
+    log!("{user} 成功领取公海客户 {id}");
+    Ok(Response::empty())
+}
+
+fn __pop_from_sea(conn: &mut PooledConn, id: &str, uid: &str) -> Result<(), Response> {
+    let time = TIME::now()?;
+    conn.exec_drop("delete from customer_sea where id = ? limit 1", (id,))?;
+    conn.exec_drop(
+        "update extra_customer_data set salesman = ?, pop_from_sea_date = ? where id = ? limit 1",
+        (uid, time.format(TimeFormat::YYYYMMDD_HHMMSS), id),
+    )?;
+    Ok(())
+}
+
+async fn get_sea_rule(header: HeaderMap) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let (max_day, min_day) = unsafe { (SEA_MAX_DAY, SEA_MIN_DAY) };
+    Ok(Response::ok(json!({
+        "max_day": max_day,
+        "min_day": min_day
+    })))
+}
+
+#[derive(Deserialize)]
+struct SeaRule {
+    max_day: u64,
+    min_day: u64,
+}
+
+async fn set_sea_rule(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let rule: SeaRule = serde_json::from_value(value)?;
+    if !verify_perms!(&user.role, OtherGroup::NAME, OtherGroup::SEA_RULE) {
+        log!("{user} 设置公海规则失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    crate::set_sea_day(rule.max_day, rule.min_day)?;
+    log!(
+        "{user} 已将公海规则设置为{}天未成交、{}天未拜访",
+        rule.max_day,
+        rule.min_day
+    );
+    Ok(Response::empty())
+}
@@ -0,0 +1,447 @@
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::{Duration, Months, NaiveDateTime};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::get_db,
+    libs::{dser::deser_yyyy_mm_dd_hh_mm_ss, gen_id, TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    perm::action::CustomerGroup,
+    verify_perms, Response, ResponseResult,
+};
+
+use super::{
+    appointment::{__find_conflicts, __remove_appointment},
+    index::{check_share_right, verify_customer_scope},
+    CUSTOMER_CACHE,
+};
+
+pub fn series_router() -> Router {
+    Router::new()
+        .route("/customer/appointment/series/add", post(add_series))
+        .route("/customer/appointment/series/update", post(update_series))
+        .route(
+            "/customer/appointment/series/delete/:id",
+            delete(delete_series),
+        )
+        .route("/customer/appointment/series/data/:id", get(query_series))
+}
+
+/// 提前生成多少天内的拜访
+pub(super) const SERIES_HORIZON_DAYS: i64 = 30;
+/// 没有结束日期时最多生成的次数
+const MAX_COUNT: u32 = 500;
+const FMT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// 第n次(从0开始)拜访的时间，按月重复时遇到较短的月份取当月最后一天
+fn nth_occurrence(
+    start: &NaiveDateTime,
+    freq: &str,
+    interval: u32,
+    n: u32,
+) -> Option<NaiveDateTime> {
+    let step = interval.checked_mul(n)?;
+    match freq {
+        "daily" => start.checked_add_signed(Duration::days(step as i64)),
+        "weekly" => start.checked_add_signed(Duration::weeks(step as i64)),
+        "monthly" => start.checked_add_months(Months::new(step)),
+        _ => None,
+    }
+}
+
+#[derive(FromRow)]
+struct SeriesRule {
+    id: String,
+    applicant: String,
+    salesman: String,
+    customer: String,
+    start_time: String,
+    freq: String,
+    ival: u32,
+    until_time: Option<String>,
+    count: Option<u32>,
+    generated: u32,
+    theme: String,
+    content: String,
+    notify: i32,
+}
+
+impl SeriesRule {
+    /// 第n次拜访的时间，超出结束日期或者次数时返回None
+    fn occurrence(&self, n: u32) -> Option<NaiveDateTime> {
+        if n >= self.count.unwrap_or(MAX_COUNT).min(MAX_COUNT) {
+            return None;
+        }
+        let start = NaiveDateTime::parse_from_str(&self.start_time, FMT).ok()?;
+        let time = nth_occurrence(&start, &self.freq, self.ival, n)?;
+        match &self.until_time {
+            Some(until) if time.format(FMT).to_string().gt(until) => None,
+            _ => Some(time),
+        }
+    }
+}
+
+/// 生成所有重复拜访中时间不晚于`horizon`的拜访，已经生成过的不会重复生成，
+/// 所以单独删除的某次拜访也不会再次出现。返回新生成的拜访数量。
+/// 需要在事务中调用，定时任务和查询可能同时生成同一个重复拜访，
+/// 所以逐个锁住规则后再读取生成进度，(series, appointment)唯一保证不会重复插入
+pub(super) fn __materialize_series(
+    conn: &mut PooledConn,
+    horizon: &NaiveDateTime,
+) -> Result<usize, Response> {
+    let horizon_str = horizon.format(FMT).to_string();
+    let ids: Vec<String> = conn.exec(
+        "SELECT id FROM appoint_series WHERE next_time IS NOT NULL AND next_time <= ?",
+        (&horizon_str,),
+    )?;
+    let time = TIME::now()?;
+    let (mut seq, mut created) = (0, 0);
+    for id in ids {
+        let rule: SeriesRule = op::some!(conn.exec_first(
+            "SELECT id, applicant, salesman, customer, start_time, freq, ival, until_time,
+                count, generated, theme, content, notify
+            FROM appoint_series WHERE id = ? AND next_time IS NOT NULL AND next_time <= ?
+            LIMIT 1 FOR UPDATE",
+            (&id, &horizon_str))?; continue);
+        let mut n = rule.generated;
+        while let Some(occ) = rule.occurrence(n).filter(|t| t <= horizon) {
+            let id = gen_id(&time, &format!("series{seq}"));
+            seq += 1;
+            let appointment = occ.format(FMT).to_string();
+            conn.exec_drop(
+                "INSERT IGNORE INTO appointment
+                (id, customer, applicant, salesman, appointment, finish_time, theme, content,
+                    notify, series)
+                VALUES (?, ?, ?, ?, ?, NULL, ?, ?, ?, ?)",
+                (
+                    &id,
+                    &rule.customer,
+                    &rule.applicant,
+                    &rule.salesman,
+                    &appointment,
+                    &rule.theme,
+                    &rule.content,
+                    rule.notify,
+                    &rule.id,
+                ),
+            )?;
+            created += conn.affected_rows() as usize;
+            n += 1;
+        }
+        let next = rule.occurrence(n).map(|t| t.format(FMT).to_string());
+        conn.exec_drop(
+            "UPDATE appoint_series SET generated = ?, next_time = ? WHERE id = ? LIMIT 1",
+            (n, next, &rule.id),
+        )?;
+    }
+    Ok(created)
+}
+
+/// 定时任务和查询时调用，保证未来一段时间内的拜访都已经生成
+pub(super) fn __materialize_default(conn: &mut PooledConn) -> Result<usize, Response> {
+    let horizon = chrono::Local::now().naive_local() + Duration::days(SERIES_HORIZON_DAYS);
+    commit_or_rollback!(__materialize_series, conn, &horizon)
+}
+
+#[derive(Deserialize)]
+struct SeriesParams {
+    #[serde(rename = "visitor")]
+    salesman: String,
+    customer: String,
+    /// 第一次拜访的时间
+    #[serde(deserialize_with = "deser_yyyy_mm_dd_hh_mm_ss")]
+    appointment: String,
+    theme: String,
+    content: String,
+    #[serde(default)]
+    notify: bool,
+    /// daily、weekly或者monthly
+    freq: String,
+    #[serde(default = "default_interval")]
+    interval: u32,
+    /// 结束日期，YYYY-MM-DD，包括当天
+    #[serde(default)]
+    until: Option<String>,
+    /// 重复次数
+    #[serde(default)]
+    count: Option<u32>,
+    /// 忽略时间冲突，需要安排拜访的权限
+    #[serde(default)]
+    force: bool,
+}
+
+fn default_interval() -> u32 {
+    1
+}
+
+async fn add_series(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: SeriesParams = serde_json::from_value(value)?;
+    if !["daily", "weekly", "monthly"].contains(&params.freq.as_str()) {
+        return Err(Response::invalid_value(
+            "freq必须是daily、weekly或者monthly",
+        ));
+    }
+    if params.interval == 0 {
+        return Err(Response::invalid_value("interval必须大于0"));
+    }
+    if params.until.is_none() && params.count.is_none() {
+        return Err(Response::invalid_value("需要设置结束日期或者重复次数"));
+    }
+    if let Some(until) = &params.until {
+        chrono::NaiveDate::parse_from_str(until, "%Y-%m-%d")
+            .map_err(|_| Response::invalid_value("until格式错误"))?;
+    }
+    let flag = verify_perms!(&user.role, CustomerGroup::NAME, CustomerGroup::ADD_APPOINT);
+    if (!params.salesman.eq(&uid) || params.force) && !flag {
+        return Err(Response::permission_denied());
+    }
+    if !flag {
+        check_share_right(&uid, &params.customer, "appoint", &mut conn)?;
+    }
+    let id = commit_or_rollback!(__add_series, &mut conn, (&params, &uid))?;
+    CUSTOMER_CACHE.clear();
+    log!("{user} 添加了重复拜访 {id}");
+    Ok(Response::ok(json!(id)))
+}
+
+fn __add_series(
+    conn: &mut PooledConn,
+    (params, uid): (&SeriesParams, &str),
+) -> Result<String, Response> {
+    let time = TIME::now()?;
+    let id = gen_id(&time, "series");
+    conn.exec_drop(
+        "INSERT INTO appoint_series
+        (id, applicant, salesman, customer, start_time, freq, ival, until_time, count,
+            generated, next_time, theme, content, notify, create_time)
+        VALUES (:id, :applicant, :salesman, :customer, :start, :freq, :ival, :until, :count,
+            0, :start, :theme, :content, :notify, :create_time)",
+        params! {
+            "id" => &id, "applicant" => uid, "salesman" => &params.salesman,
+            "customer" => &params.customer, "start" => &params.appointment,
+            "freq" => &params.freq, "ival" => params.interval,
+            "until" => params.until.as_ref().map(|d| format!("{d} 23:59:59")),
+            "count" => params.count, "theme" => &params.theme, "content" => &params.content,
+            "notify" => params.notify as i32,
+            "create_time" => time.format(TimeFormat::YYYYMMDD_HHMMSS)
+        },
+    )?;
+    let start = NaiveDateTime::parse_from_str(&params.appointment, FMT)
+        .map_err(|_| Response::invalid_value("拜访时间格式错误"))?;
+    // 至少生成第一次拜访
+    let now = chrono::Local::now().naive_local();
+    let horizon = start.max(now) + Duration::days(SERIES_HORIZON_DAYS);
+    __materialize_series(conn, &horizon)?;
+    if !params.force {
+        let created: Vec<(String, String)> = conn.exec(
+            "SELECT id, appointment FROM appointment WHERE series = ? ORDER BY appointment",
+            (&id,),
+        )?;
+        for (i, (occ, appointment)) in created.iter().enumerate() {
+            let conflicts = __find_conflicts(conn, &params.salesman, appointment, Some(occ))?;
+            if !conflicts.is_empty() {
+                return Err(Response::conflict(json!({
+                    "index": i,
+                    "conflicts": conflicts
+                })));
+            }
+        }
+    }
+    Ok(id)
+}
+
+#[derive(Deserialize)]
+struct UpdateSeriesParams {
+    id: String,
+    visitor: String,
+    theme: String,
+    content: String,
+    #[serde(default)]
+    notify: bool,
+    /// 修改之后每次拜访的时间，HH:MM:SS，为空时不修改
+    #[serde(default)]
+    time: Option<String>,
+}
+
+/// 修改整个重复拜访，只影响之后还未完成的拜访，单独修改某一次拜访使用原来的接口
+async fn update_series(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: UpdateSeriesParams = serde_json::from_value(value)?;
+    if let Some(t) = &params.time {
+        chrono::NaiveTime::parse_from_str(t, "%H:%M:%S")
+            .map_err(|_| Response::invalid_value("time格式错误"))?;
+    }
+    let _: i32 = op::some!(conn.exec_first(
+        "SELECT 1 FROM appoint_series WHERE id = ? AND applicant = ? LIMIT 1",
+        (&params.id, &uid))?;
+        ret Err(Response::permission_denied())
+    );
+    commit_or_rollback!(__update_series, &mut conn, &params)?;
+    CUSTOMER_CACHE.clear();
+    log!("{user} 修改了重复拜访 {}", params.id);
+    Ok(Response::empty())
+}
+
+fn __update_series(conn: &mut PooledConn, params: &UpdateSeriesParams) -> Result<(), Response> {
+    let now = TIME::now()?.format(TimeFormat::YYYYMMDD_HHMMSS);
+    let time = params.time.clone().unwrap_or_default();
+    conn.exec_drop(
+        "UPDATE appoint_series SET salesman = :salesman, theme = :theme, content = :content,
+            notify = :notify,
+            start_time = IF(:time = '', start_time, CONCAT(LEFT(start_time, 10), ' ', :time)),
+            next_time = IF(:time = '' OR next_time IS NULL, next_time,
+                CONCAT(LEFT(next_time, 10), ' ', :time))
+        WHERE id = :id LIMIT 1",
+        params! {
+            "salesman" => &params.visitor, "theme" => &params.theme,
+            "content" => &params.content, "notify" => params.notify as i32,
+            "time" => &time, "id" => &params.id
+        },
+    )?;
+    conn.exec_drop(
+        "DELETE FROM appoint_notify WHERE appoint IN (SELECT a.id FROM appointment a
+            WHERE a.series = ? AND a.finish_time IS NULL AND a.appointment >= ?)",
+        (&params.id, &now),
+    )?;
+    conn.exec_drop(
+        "UPDATE appointment SET salesman = :salesman, theme = :theme, content = :content,
+            notify = :notify, sequence = sequence + 1,
+            appointment = IF(:time = '', appointment, CONCAT(LEFT(appointment, 10), ' ', :time))
+        WHERE series = :id AND finish_time IS NULL AND appointment >= :now",
+        params! {
+            "salesman" => &params.visitor, "theme" => &params.theme,
+            "content" => &params.content, "notify" => params.notify as i32,
+            "time" => &time, "id" => &params.id, "now" => &now
+        },
+    )?;
+    Ok(())
+}
+
+/// 删除整个重复拜访，已经完成或者已经过去的拜访会保留
+async fn delete_series(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let _: i32 = op::some!(conn.exec_first(
+        "SELECT 1 FROM appoint_series WHERE id = ? AND applicant = ? LIMIT 1",
+        (&id, &uid))?;
+        ret Err(Response::permission_denied())
+    );
+    let photos = commit_or_rollback!(__delete_series, &mut conn, &id)?;
+    for f in photos {
+        let _ = std::fs::remove_file(f);
+    }
+    CUSTOMER_CACHE.clear();
+    log!("{user} 删除了重复拜访 {id}");
+    Ok(Response::empty())
+}
+
+fn __delete_series(conn: &mut PooledConn, id: &str) -> Result<Vec<String>, Response> {
+    let now = TIME::now()?.format(TimeFormat::YYYYMMDD_HHMMSS);
+    let list: Vec<String> = conn.exec(
+        "SELECT id FROM appointment WHERE series = ? AND finish_time IS NULL AND appointment >= ?",
+        (id, &now),
+    )?;
+    let mut photos = Vec::new();
+    for appoint in list {
+        photos.extend(__remove_appointment(conn, &appoint)?);
+    }
+    conn.exec_drop(
+        "UPDATE appointment SET series = NULL WHERE series = ?",
+        (id,),
+    )?;
+    conn.exec_drop("DELETE FROM appoint_series WHERE id = ? LIMIT 1", (id,))?;
+    Ok(photos)
+}
+
+#[derive(Serialize, FromRow)]
+struct SeriesData {
+    id: String,
+    applicant: String,
+    #[serde(rename = "visitor")]
+    salesman: String,
+    customer: String,
+    start_time: String,
+    freq: String,
+    #[serde(rename = "interval")]
+    ival: u32,
+    until_time: Option<String>,
+    count: Option<u32>,
+    generated: u32,
+    next_time: Option<String>,
+    theme: String,
+    content: String,
+    notify: i32,
+}
+
+async fn query_series(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let data: Option<SeriesData> = conn.exec_first(
+        "SELECT id, applicant, salesman, customer, start_time, freq, ival, until_time, count,
+            generated, next_time, theme, content, notify
+        FROM appoint_series WHERE id = ? LIMIT 1",
+        (&id,),
+    )?;
+    let data = op::some!(data; ret Err(Response::not_exist("重复拜访不存在")));
+    // 申请人和拜访人可以查看，其他人需要能查看该客户或者共享了拜访权限
+    if data.applicant != uid && data.salesman != uid {
+        if let Err(e) =
+            verify_customer_scope(&mut conn, &user, &data.customer, CustomerGroup::QUERY).await
+        {
+            if check_share_right(&uid, &data.customer, "appoint", &mut conn).is_err() {
+                log!("{user} 查看重复拜访 {id} 失败，原因权限不足");
+                return Err(e);
+            }
+        }
+    }
+    Ok(Response::ok(json!(data)))
+}
+
+#[test]
+fn test_nth_occurrence() {
+    let start = NaiveDateTime::parse_from_str("2024-01-31 10:00:00", FMT).unwrap();
+    let fmt = |t: Option<NaiveDateTime>| t.map(|t| t.format(FMT).to_string());
+    assert_eq!(
+        fmt(nth_occurrence(&start, "daily", 2, 3)),
+        Some("2024-02-06 10:00:00".into())
+    );
+    assert_eq!(
+        fmt(nth_occurrence(&start, "weekly", 1, 1)),
+        Some("2024-02-07 10:00:00".into())
+    );
+    assert_eq!(
+        fmt(nth_occurrence(&start, "monthly", 1, 1)),
+        Some("2024-02-29 10:00:00".into())
+    );
+    assert_eq!(
+        fmt(nth_occurrence(&start, "monthly", 1, 2)),
+        Some("2024-03-31 10:00:00".into())
+    );
+    assert_eq!(nth_occurrence(&start, "yearly", 1, 1), None);
+}
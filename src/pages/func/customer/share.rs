@@ -0,0 +1,175 @@
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{delete, post},
+    Json, Router,
+};
+use mysql::{prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::get_db,
+    libs::{TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro, Response, ResponseResult,
+};
+
+use super::{
+    index::{check_owner_customer, check_user_customer},
+    CUSTOMER_CACHE,
+};
+
+pub fn share_router() -> Router {
+    Router::new()
+        .route("/customer/share/add", post(share_customer))
+        .route(
+            "/customer/share/delete/:customer/:salesman",
+            delete(unshare_customer),
+        )
+        .route("/customer/share/list/:customer", post(query_share))
+}
+
+#[derive(Deserialize)]
+struct ShareParams {
+    customer: String,
+    salesman: Vec<String>,
+    /// 是否允许被共享者添加拜访
+    #[serde(default)]
+    appoint: bool,
+    /// 是否允许被共享者添加订单
+    #[serde(default)]
+    order: bool,
+}
+
+/// 根据共享记录同步客户的is_share字段，注意is_share为0时表示共享
+pub fn __sync_share_state(conn: &mut PooledConn, customer: &str) -> Result<(), Response> {
+    conn.exec_drop(
+        "UPDATE customer c SET c.is_share = IF(EXISTS (
+            SELECT 1 FROM customer_share s WHERE s.customer = c.id), 0, 1)
+        WHERE c.id = ? LIMIT 1",
+        (customer,),
+    )?;
+    Ok(())
+}
+
+async fn share_customer(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: ShareParams = serde_json::from_value(value)?;
+    log!(
+        "{user} 请求将客户 {} 共享给 {:?}",
+        params.customer,
+        params.salesman
+    );
+    if check_owner_customer(&uid, &params.customer, &mut conn).is_err() {
+        log!(
+            "{user} 共享客户 {} 失败，原因只有负责人可以共享客户",
+            params.customer
+        );
+        return Err(Response::permission_denied());
+    }
+    for s in &params.salesman {
+        if s.eq(&uid) {
+            return Err(Response::invalid_value("不能共享给自己"));
+        }
+        get_user(s, &mut conn).await?;
+    }
+    commit_or_rollback!(__share_customer, &mut conn, &params)?;
+    CUSTOMER_CACHE.clear();
+    log!(
+        "{user} 成功将客户 {} 共享给 {:?}",
+        params.customer,
+        params.salesman
+    );
+    Ok(Response::empty())
+}
+
+fn __share_customer(conn: &mut PooledConn, params: &ShareParams) -> Result<(), Response> {
+    let time = TIME::now()?.format(TimeFormat::YYYYMMDD_HHMMSS);
+    for s in &params.salesman {
+        conn.exec_drop(
+            "INSERT INTO customer_share (customer, share_salesman, appoint, `order`, create_time)
+            VALUES (?, ?, ?, ?, ?)
+            ON DUPLICATE KEY UPDATE appoint = VALUES(appoint), `order` = VALUES(`order`)",
+            (
+                &params.customer,
+                s,
+                params.appoint as i32,
+                params.order as i32,
+                &time,
+            ),
+        )?;
+    }
+    __sync_share_state(conn, &params.customer)
+}
+
+/// 负责人可以取消共享，被共享者也可以主动退出共享
+async fn unshare_customer(
+    header: HeaderMap,
+    Path((customer, salesman)): Path<(String, String)>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    log!("{user} 请求取消客户 {customer} 对 {salesman} 的共享");
+    if !salesman.eq(&uid) && check_owner_customer(&uid, &customer, &mut conn).is_err() {
+        log!("{user} 取消共享客户 {customer} 失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    commit_or_rollback!(__unshare_customer, &mut conn, &customer, &salesman)?;
+    CUSTOMER_CACHE.clear();
+    log!("{user} 成功取消客户 {customer} 对 {salesman} 的共享");
+    Ok(Response::empty())
+}
+
+fn __unshare_customer(
+    conn: &mut PooledConn,
+    customer: &str,
+    salesman: &str,
+) -> Result<(), Response> {
+    conn.exec_drop(
+        "DELETE FROM customer_share WHERE customer = ? AND share_salesman = ? LIMIT 1",
+        (customer, salesman),
+    )?;
+    __sync_share_state(conn, customer)
+}
+
+#[derive(Serialize, FromRow)]
+struct ShareData {
+    salesman: String,
+    salesman_name: String,
+    department: String,
+    appoint: bool,
+    order: bool,
+    create_time: String,
+}
+
+async fn query_share(header: HeaderMap, Path(customer): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    check_user_customer(&uid, &customer, &mut conn)?;
+    let data: Vec<ShareData> = conn.exec(
+        "SELECT s.share_salesman as salesman, u.name as salesman_name, u.department,
+            s.appoint, s.`order`, s.create_time
+        FROM customer_share s JOIN user u ON u.id = s.share_salesman
+        WHERE s.customer = ? ORDER BY s.create_time",
+        (&customer,),
+    )?;
+    log!(
+        "{user} 成功查询到客户 {customer} 的{}条共享记录",
+        data.len()
+    );
+    Ok(Response::ok(json!(data)))
+}
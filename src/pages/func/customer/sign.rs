@@ -0,0 +1,337 @@
+use axum::{
+    extract::{Multipart, Path},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::get_db,
+    libs::{dser::split_files, gen_file_link, gen_id, parse_multipart, FilePart, TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    perm::action::{CustomerGroup, OtherGroup},
+    response::BodyFile,
+    verify_perms, Response, ResponseResult,
+};
+
+use super::index::verify_customer_scope;
+
+pub fn sign_router() -> Router {
+    Router::new()
+        .route("/customer/appointment/sign/:id", post(sign_appointment))
+        .route(
+            "/customer/appointment/sign/data/:id",
+            get(query_appoint_sign),
+        )
+        .route("/customer/sign/query", post(query_sign))
+        .route("/customer/sign/img/:url", get(get_sign_file))
+        .route("/customer/location/set", post(set_customer_location))
+}
+
+/// 两个经纬度之间的球面距离，单位为米
+fn haversine(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    const EARTH_RADIUS: f64 = 6_371_000.0;
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lng2 - lng1).to_radians();
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+fn check_location(latitude: f64, longitude: f64) -> Result<(), Response> {
+    if (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) {
+        Ok(())
+    } else {
+        Err(Response::invalid_value("经纬度超出范围"))
+    }
+}
+
+#[derive(Deserialize)]
+struct SignParams {
+    /// 0为签到，1为签退
+    ty: i32,
+    latitude: f64,
+    longitude: f64,
+    #[serde(default)]
+    address: String,
+}
+
+async fn sign_appointment(
+    header: HeaderMap,
+    Path(id): Path<String>,
+    part: Multipart,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let data = parse_multipart(part).await?;
+    let params: SignParams = serde_json::from_str(&data.json)?;
+    if params.ty != 0 && params.ty != 1 {
+        return Err(Response::invalid_value("ty必须是0或者1"));
+    }
+    check_location(params.latitude, params.longitude)?;
+    let time = TIME::now()?;
+    let sign = commit_or_rollback!(
+        __sign_appointment,
+        &mut conn,
+        (&id, &uid, &params, &data.files),
+        &time
+    )?;
+    log!(
+        "{user} 在 {} {}了拜访 {id}",
+        params.address,
+        op::ternary!(params.ty == 0 => "签到", "签退")
+    );
+    Ok(Response::ok(json!(sign)))
+}
+
+fn __sign_appointment(
+    conn: &mut PooledConn,
+    (id, uid, params, files): (&str, &str, &SignParams, &[FilePart]),
+    time: &TIME,
+) -> Result<SignRecord, Response> {
+    let customer: Option<Option<String>> = conn.exec_first(
+        "SELECT customer FROM appointment WHERE id = ? AND salesman = ? LIMIT 1",
+        (id, uid),
+    )?;
+    let customer = op::some!(customer; ret Err(Response::permission_denied()));
+    let signed: Vec<i32> = conn.exec("SELECT ty FROM sign_in WHERE appoint = ?", (id,))?;
+    if signed.contains(&params.ty) {
+        return Err(Response::already_exist(op::ternary!(
+            params.ty == 0 => "已经签到过了", "已经签退过了"
+        )));
+    }
+    if params.ty == 1 && !signed.contains(&0) {
+        return Err(Response::dissatisfy("需要先签到才能签退"));
+    }
+    let location: Option<(Option<f64>, Option<f64>)> = conn.exec_first(
+        "SELECT latitude, longitude FROM customer WHERE id = ? LIMIT 1",
+        (&customer,),
+    )?;
+    // 客户没有设置位置时不计算距离
+    let distance = match location {
+        Some((Some(lat), Some(lng))) => {
+            Some(haversine(lat, lng, params.latitude, params.longitude).round())
+        }
+        _ => None,
+    };
+    let mut links = Vec::new();
+    for f in files {
+        let link = gen_file_link(time, f.filename());
+        std::fs::write(format!("resources/sign/{link}"), &f.bytes)?;
+        links.push(link);
+    }
+    let photos = op::ternary!(links.is_empty() => None, Some(links.join("&")));
+    let sign_time = time.format(TimeFormat::YYYYMMDD_HHMMSS);
+    let sign_id = gen_id(time, &format!("{id}{}", params.ty));
+    conn.exec_drop(
+        "INSERT INTO sign_in
+        (id, appoint, signer, ty, latitude, longitude, address, distance, photos, sign_time)
+        VALUES (:id, :appoint, :signer, :ty, :lat, :lng, :address, :distance, :photos, :time)",
+        params! {
+            "id" => &sign_id, "appoint" => id, "signer" => uid, "ty" => params.ty,
+            "lat" => params.latitude, "lng" => params.longitude, "address" => &params.address,
+            "distance" => distance, "photos" => &photos, "time" => &sign_time
+        },
+    )?;
+    Ok(SignRecord {
+        id: sign_id,
+        appoint: id.to_owned(),
+        signer: uid.to_owned(),
+        signer_name: String::new(),
+        customer,
+        customer_name: None,
+        ty: params.ty,
+        latitude: params.latitude,
+        longitude: params.longitude,
+        address: params.address.clone(),
+        distance,
+        photos,
+        sign_time,
+    })
+}
+
+/// 删除拜访的签到记录，返回需要在提交后删除的照片路径
+pub(super) fn __delete_sign(conn: &mut PooledConn, appoint: &str) -> Result<Vec<String>, Response> {
+    let photos: Vec<Option<String>> =
+        conn.exec("SELECT photos FROM sign_in WHERE appoint = ?", (appoint,))?;
+    conn.exec_drop("DELETE FROM sign_in WHERE appoint = ?", (appoint,))?;
+    Ok(photos
+        .iter()
+        .flatten()
+        .flat_map(|p| p.split('&'))
+        .map(|link| format!("resources/sign/{link}"))
+        .collect())
+}
+
+#[derive(Serialize, FromRow)]
+struct SignRecord {
+    id: String,
+    appoint: String,
+    signer: String,
+    signer_name: String,
+    customer: Option<String>,
+    customer_name: Option<String>,
+    ty: i32,
+    latitude: f64,
+    longitude: f64,
+    address: String,
+    /// 与客户位置的距离，单位为米
+    distance: Option<f64>,
+    #[serde(serialize_with = "split_files")]
+    photos: Option<String>,
+    sign_time: String,
+}
+
+const SIGN_SELECT: &str = "SELECT s.id, s.appoint, s.signer, u.name AS signer_name,
+    a.customer, c.name AS customer_name, s.ty, s.latitude, s.longitude, s.address,
+    s.distance, s.photos, s.sign_time
+    FROM sign_in s
+    JOIN user u ON u.id = s.signer
+    LEFT JOIN appointment a ON a.id = s.appoint
+    LEFT JOIN customer c ON c.id = a.customer";
+
+async fn query_appoint_sign(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let data: Vec<SignRecord> = conn.exec(
+        format!("{SIGN_SELECT} WHERE s.appoint = ? ORDER BY s.ty"),
+        (&id,),
+    )?;
+    let own = data.iter().all(|s| s.signer.eq(&uid));
+    if !own && !verify_perms!(&user.role, OtherGroup::NAME, OtherGroup::QUERY_SIGN_IN) {
+        return Err(Response::permission_denied());
+    }
+    Ok(Response::ok(json!(data)))
+}
+
+#[derive(Deserialize)]
+struct QuerySignParams {
+    /// 签到人，为空时按照部门查询，my为自己
+    #[serde(default)]
+    salesman: String,
+    /// 部门，为空时查询全部部门，my为自己的部门
+    #[serde(default)]
+    department: String,
+    /// YYYY-MM-DD
+    start: String,
+    /// YYYY-MM-DD，包括当天
+    end: String,
+}
+
+async fn query_sign(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: QuerySignParams = serde_json::from_value(value)?;
+    let salesman = op::ternary!(params.salesman.eq("my") => uid.clone(), params.salesman.clone());
+    let (depart, all) = verify_perms!(
+        &user.role,
+        OtherGroup::NAME,
+        OtherGroup::QUERY_SIGN_IN,
+        None,
+        Some(["all"].as_slice())
+    );
+    let department = if !salesman.is_empty() {
+        if salesman.eq(&uid) {
+            String::new()
+        } else {
+            let department = get_user(&salesman, &mut conn).await?.department.clone();
+            if !(all || depart && department.eq(&user.department)) {
+                log!("{user} 查询 {salesman} 的签到记录失败，原因权限不足");
+                return Err(Response::permission_denied());
+            }
+            department
+        }
+    } else if params.department.is_empty() {
+        if !all {
+            log!("{user} 查询全部签到记录失败，原因权限不足");
+            return Err(Response::permission_denied());
+        }
+        String::new()
+    } else {
+        let department = op::ternary!(params.department.eq("my")
+            => user.department.clone(), params.department.clone());
+        if !(all || depart && department.eq(&user.department)) {
+            log!("{user} 查询{department}的签到记录失败，原因权限不足");
+            return Err(Response::permission_denied());
+        }
+        department
+    };
+    let data: Vec<SignRecord> = conn.exec(
+        format!(
+            "{SIGN_SELECT}
+            WHERE (:salesman = '' OR s.signer = :salesman)
+            AND (:department = '' OR u.department = :department)
+            AND s.sign_time >= :start AND s.sign_time <= :end
+            ORDER BY s.sign_time DESC"
+        ),
+        params! {
+            "salesman" => &salesman, "department" => &department,
+            "start" => &params.start, "end" => format!("{} 24:00:00", params.end)
+        },
+    )?;
+    log!(
+        "{user} 查询了{}到{}的签到记录，共{}条",
+        params.start,
+        params.end,
+        data.len()
+    );
+    Ok(Response::ok(json!(data)))
+}
+
+async fn get_sign_file(Path(url): Path<String>) -> Result<BodyFile, (StatusCode, String)> {
+    BodyFile::new_with_base64_url("resources/sign", &url)
+}
+
+#[derive(Deserialize)]
+struct LocationParams {
+    customer: String,
+    latitude: f64,
+    longitude: f64,
+}
+
+async fn set_customer_location(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: LocationParams = serde_json::from_value(value)?;
+    check_location(params.latitude, params.longitude)?;
+    verify_customer_scope(
+        &mut conn,
+        &user,
+        &params.customer,
+        CustomerGroup::UPDATE_CUSTOMER_DATA,
+    )
+    .await?;
+    conn.exec_drop(
+        "UPDATE customer SET latitude = ?, longitude = ? WHERE id = ? LIMIT 1",
+        (params.latitude, params.longitude, &params.customer),
+    )?;
+    log!("{user} 设置了客户 {} 的位置", params.customer);
+    Ok(Response::empty())
+}
+
+#[test]
+fn test_haversine() {
+    assert_eq!(haversine(30.0, 120.0, 30.0, 120.0), 0.0);
+    // 北京天安门到上海人民广场约1067公里
+    let d = haversine(39.9087, 116.3975, 31.2304, 121.4737);
+    assert!((d - 1_067_000.0).abs() < 5_000.0, "{d}");
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+
+use axum::{extract::Path, http::HeaderMap, routing::post, Json, Router};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer,
+    database::get_db,
+    libs::{base64_decode, base64_encode, gen_id, TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro, Field, Response, ResponseResult,
+};
+
+use super::index::{__visible_customer, check_user_customer};
+
+pub fn timeline_router() -> Router {
+    Router::new().route("/customer/timeline/:id", post(query_timeline))
+}
+
+/// 记录客户字段的修改，`fields`为(字段名, 新的值)，需要在更新之前调用
+pub(super) fn __record_changes(
+    conn: &mut PooledConn,
+    customer: &str,
+    operator: &str,
+    fields: &[(&str, String)],
+    custom_fields: &HashMap<String, Vec<Field>>,
+) -> Result<(), Response> {
+    let columns: Vec<&str> = fields.iter().map(|(f, _)| *f).collect();
+    let row: Option<mysql::Row> = conn.exec_first(
+        format!(
+            "SELECT {} FROM customer WHERE id = ? LIMIT 1",
+            columns.join(", ")
+        ),
+        (customer,),
+    )?;
+    let row = op::some!(row; ret Err(Response::not_exist("客户不存在")));
+    let mut changes = Vec::new();
+    for (i, (field, new)) in fields.iter().enumerate() {
+        let old = match row.as_ref(i) {
+            Some(mysql::Value::Bytes(b)) => String::from_utf8_lossy(b).to_string(),
+            Some(mysql::Value::Int(n)) => n.to_string(),
+            _ => String::new(),
+        };
+        if old.ne(new) {
+            changes.push((field.to_string(), old, new.clone()));
+        }
+    }
+    let old_custom: Vec<(String, String)> = conn.exec(
+        "SELECT display, value FROM custom_field_data WHERE fields = 0 AND id = ?",
+        (customer,),
+    )?;
+    for f in custom_fields.values().flatten() {
+        if let Some((_, old)) = old_custom.iter().find(|(d, _)| d.eq(&f.display)) {
+            if old.ne(&f.value) {
+                changes.push((f.display.clone(), old.clone(), f.value.clone()));
+            }
+        }
+    }
+    let time = TIME::now()?;
+    let change_time = time.format(TimeFormat::YYYYMMDD_HHMMSS);
+    conn.exec_batch(
+        "INSERT INTO customer_change_log
+        (id, customer, operator, field, old_value, new_value, change_time)
+        VALUES (?, ?, ?, ?, ?, ?, ?)",
+        changes.iter().enumerate().map(|(i, (field, old, new))| {
+            (
+                gen_id(&time, &format!("timeline{i}")),
+                customer,
+                operator,
+                field,
+                old,
+                new,
+                &change_time,
+            )
+        }),
+    )?;
+    Ok(())
+}
+
+/// 时间线中的事件类型以及对应的查询，所有查询的列名保持一致，因为可能只选取其中一部分
+const EVENTS: [(&str, &str); 7] = [
+    (
+        "appointment",
+        "SELECT 'appointment' AS ty, a.id, a.appointment AS time, a.salesman AS operator,
+            u.name AS operator_name, IFNULL(a.theme, '') AS title, IFNULL(a.content, '') AS content
+        FROM appointment a LEFT JOIN user u ON u.id = a.salesman
+        WHERE a.customer = :id",
+    ),
+    (
+        "visit",
+        "SELECT 'visit' AS ty, a.id, a.finish_time AS time, a.salesman AS operator,
+            u.name AS operator_name, IFNULL(a.theme, '') AS title, IFNULL(a.content, '') AS content
+        FROM appointment a LEFT JOIN user u ON u.id = a.salesman
+        WHERE a.customer = :id AND a.finish_time IS NOT NULL",
+    ),
+    (
+        "comment",
+        "SELECT 'comment' AS ty, c.id, c.create_time AS time, c.applicant AS operator,
+            u.name AS operator_name, IFNULL(a.theme, '') AS title, IFNULL(c.comment, '') AS content
+        FROM appoint_comment c JOIN appointment a ON a.id = c.appoint
+        LEFT JOIN user u ON u.id = c.applicant
+        WHERE a.customer = :id",
+    ),
+    (
+        "order",
+        "SELECT 'order' AS ty, o.id, o.create_time AS time, o.salesman AS operator,
+            u.name AS operator_name, o.number AS title, o.comment AS content
+        FROM order_data o LEFT JOIN user u ON u.id = o.salesman
+        WHERE o.customer = :id",
+    ),
+    (
+        "report",
+        "SELECT 'report' AS ty, r.id, r.create_time AS time, r.applicant AS operator,
+            u.name AS operator_name,
+            CASE r.ty WHEN 0 THEN '日报' WHEN 1 THEN '周报' ELSE '月报' END AS title,
+            r.contents AS content
+        FROM report r LEFT JOIN user u ON u.id = r.applicant
+        WHERE r.ac = :id",
+    ),
+    (
+        "change",
+        "SELECT 'change' AS ty, l.id, l.change_time AS time, l.operator,
+            u.name AS operator_name, l.field AS title,
+            CONCAT(l.old_value, ' -> ', l.new_value) AS content
+        FROM customer_change_log l LEFT JOIN user u ON u.id = l.operator
+        WHERE l.customer = :id",
+    ),
+    (
+        "transfer",
+        "SELECT 'transfer' AS ty, t.id, t.transfer_time AS time, t.operator,
+            u.name AS operator_name,
+            CONCAT(IFNULL(f.name, t.from_salesman), ' -> ', IFNULL(tu.name, t.to_salesman)) AS title,
+            t.remark AS content
+        FROM customer_transfer t LEFT JOIN user u ON u.id = t.operator
+        LEFT JOIN user f ON f.id = t.from_salesman
+        LEFT JOIN user tu ON tu.id = t.to_salesman
+        WHERE t.customer = :id",
+    ),
+];
+
+#[derive(Deserialize)]
+struct TimelineParams {
+    /// 上一页返回的cursor，为空时从最新的事件开始
+    #[serde(default)]
+    cursor: String,
+    #[serde(default = "default_limit")]
+    limit: usize,
+    /// 需要的事件类型，为空时返回全部类型
+    #[serde(default)]
+    types: Vec<String>,
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+#[derive(Serialize, FromRow)]
+struct Event {
+    ty: String,
+    id: String,
+    time: String,
+    operator: Option<String>,
+    operator_name: Option<String>,
+    title: String,
+    content: String,
+}
+
+/// cursor由最后一个事件的(时间, 类型, id)编码而成
+fn encode_cursor(e: &Event) -> String {
+    base64_encode(format!("{}\0{}\0{}", e.time, e.ty, e.id))
+}
+
+fn decode_cursor(cursor: &str) -> Result<(String, String, String), Response> {
+    let bytes = base64_decode(cursor)?;
+    let text = String::from_utf8_lossy(&bytes);
+    let mut split = text.splitn(3, '\0').map(str::to_owned);
+    op::catch!(Some((split.next()?, split.next()?, split.next()?)))
+        .ok_or(Response::invalid_value("cursor错误"))
+}
+
+async fn query_timeline(
+    header: HeaderMap,
+    Path(id): Path<String>,
+    Json(value): Json<Value>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: TimelineParams = serde_json::from_value(value)?;
+    if check_user_customer(&uid, &id, &mut conn).is_err()
+        && !__visible_customer(&mut conn, &user, &id).await?
+    {
+        log!("{user} 查询客户 {id} 的时间线失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    if let Some(t) = params
+        .types
+        .iter()
+        .find(|t| !EVENTS.iter().any(|(ty, _)| ty == t))
+    {
+        return Err(Response::invalid_value(format!("未知的事件类型 {t}")));
+    }
+    let union: Vec<&str> = EVENTS
+        .iter()
+        .filter(|(ty, _)| params.types.is_empty() || params.types.iter().any(|t| t == ty))
+        .map(|(_, sql)| *sql)
+        .collect();
+    let (time, ty, eid) = if params.cursor.is_empty() {
+        (
+            "9999-12-31 23:59:59".to_owned(),
+            String::new(),
+            String::new(),
+        )
+    } else {
+        decode_cursor(&params.cursor)?
+    };
+    let limit = params.limit.clamp(1, 100);
+    let mut events: Vec<Event> = conn.exec(
+        format!(
+            "SELECT * FROM ({}) t
+            WHERE t.time IS NOT NULL AND (t.time, t.ty, t.id) < (:time, :ty, :eid)
+            ORDER BY t.time DESC, t.ty DESC, t.id DESC
+            LIMIT {}",
+            union.join(" UNION ALL "),
+            limit + 1
+        ),
+        params! { "id" => &id, "time" => time, "ty" => ty, "eid" => eid },
+    )?;
+    let cursor = if events.len() > limit {
+        events.truncate(limit);
+        events.last().map(encode_cursor)
+    } else {
+        None
+    };
+    log!("{user} 成功查询到客户 {id} 的{}条时间线事件", events.len());
+    Ok(Response::ok(json!({
+        "events": events,
+        "cursor": cursor
+    })))
+}
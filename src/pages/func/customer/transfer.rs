@@ -0,0 +1,189 @@
+use axum::{extract::Path, http::HeaderMap, routing::post, Json, Router};
+use mysql::{prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::get_db,
+    libs::{
+        cache::{ORDER_CACHE, ORDER_CACHE_WITH_ID},
+        gen_id, TimeFormat, TIME,
+    },
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    perm::action::CustomerGroup,
+    verify_perms, Response, ResponseResult,
+};
+
+use super::CUSTOMER_CACHE;
+
+pub fn transfer_router() -> Router {
+    Router::new()
+        .route("/customer/transfer", post(transfer_customer))
+        .route("/customer/transfer/history/:id", post(query_transfer_history))
+}
+
+#[derive(Deserialize)]
+struct TransferParams {
+    customers: Vec<String>,
+    /// 新的负责人
+    salesman: String,
+    /// 是否同时转移未完成的拜访
+    #[serde(default)]
+    appointment: bool,
+    /// 是否同时转移意向订单
+    #[serde(default)]
+    order: bool,
+    #[serde(default)]
+    remark: String,
+}
+
+async fn transfer_customer(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: TransferParams = serde_json::from_value(value)?;
+    log!("{user} 请求将{}位客户转移给 {}", params.customers.len(), params.salesman);
+    let target = get_user(&params.salesman, &mut conn).await?;
+    let (depart, root) = verify_perms!(
+        &user.role,
+        CustomerGroup::NAME,
+        CustomerGroup::TRANSFER_CUSTOMER,
+        None,
+        Some(["all"].as_slice())
+    );
+    if !(root || depart && target.department == user.department) {
+        log!("{user} 转移客户失败，原因没有权限将客户转移给其他部门的 {target}");
+        return Err(Response::permission_denied());
+    }
+    let mut owners = Vec::with_capacity(params.customers.len());
+    for id in &params.customers {
+        let owner: Option<(Option<String>, Option<String>)> = conn.exec_first(
+            "select ex.salesman, u.department from extra_customer_data ex
+            left join user u on u.id = ex.salesman
+            where ex.id = ? limit 1",
+            (id,),
+        )?;
+        let (salesman, department) =
+            op::some!(owner; ret Err(Response::not_exist(format!("客户 {id} 不存在"))));
+        let salesman = op::some!(salesman; ret Err(Response::dissatisfy(format!("客户 {id} 处于公海中，无法转移"))));
+        if !(root || depart && department.is_some_and(|d| d == user.department)) {
+            log!("{user} 转移客户 {id} 失败，原因该客户不属于本部门");
+            return Err(Response::permission_denied());
+        }
+        owners.push((id.as_str(), salesman));
+    }
+    commit_or_rollback!(__transfer_customer, &mut conn, &owners, &params, &uid)?;
+    CUSTOMER_CACHE.clear();
+    if params.order {
+        ORDER_CACHE.clear();
+        ORDER_CACHE_WITH_ID.clear();
+    }
+    log!("{user} 成功将{}位客户转移给 {target}", owners.len());
+    Ok(Response::empty())
+}
+
+fn __transfer_customer(
+    conn: &mut PooledConn,
+    owners: &[(&str, String)],
+    params: &TransferParams,
+    operator: &str,
+) -> Result<(), Response> {
+    let time = TIME::now()?;
+    let date = time.format(TimeFormat::YYYYMMDD_HHMMSS);
+    for (i, (id, from)) in owners.iter().enumerate() {
+        if params.salesman.eq(from) {
+            continue;
+        }
+        conn.exec_drop(
+            "update extra_customer_data set salesman = ? where id = ? limit 1",
+            (&params.salesman, id),
+        )?;
+        // 新负责人不再需要共享记录
+        conn.exec_drop(
+            "delete from customer_share where customer = ? and share_salesman = ?",
+            (id, &params.salesman),
+        )?;
+        super::share::__sync_share_state(conn, id)?;
+        if params.appointment {
+            conn.exec_drop(
+                "update appointment set salesman = ?
+                where customer = ? and salesman = ? and finish_time is null",
+                (&params.salesman, id, from),
+            )?;
+        }
+        if params.order {
+            conn.exec_drop(
+                "update order_data set salesman = ?
+                where customer = ? and salesman = ? and status = 0",
+                (&params.salesman, id, from),
+            )?;
+        }
+        conn.exec_drop(
+            "insert into customer_transfer
+            (id, customer, from_salesman, to_salesman, operator, transfer_time, appointment, `order`, remark)
+            values (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            (
+                gen_id(&time, &format!("transfer{i}")),
+                id,
+                from,
+                &params.salesman,
+                operator,
+                &date,
+                params.appointment as i32,
+                params.order as i32,
+                &params.remark,
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize, FromRow)]
+struct TransferHistory {
+    id: String,
+    customer: String,
+    from_salesman: String,
+    from_salesman_name: Option<String>,
+    to_salesman: String,
+    to_salesman_name: Option<String>,
+    operator: String,
+    operator_name: Option<String>,
+    transfer_time: String,
+    appointment: bool,
+    order: bool,
+    remark: String,
+}
+
+async fn query_transfer_history(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if super::index::check_user_customer(&uid, &id, &mut conn).is_err()
+        && !verify_perms!(&user.role, CustomerGroup::NAME, CustomerGroup::TRANSFER_CUSTOMER)
+    {
+        log!("{user} 查询客户 {id} 的转移记录失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let data: Vec<TransferHistory> = conn.exec(
+        "select t.id, t.customer, t.from_salesman, f.name as from_salesman_name,
+            t.to_salesman, tu.name as to_salesman_name, t.operator, o.name as operator_name,
+            t.transfer_time, t.appointment, t.`order`, t.remark
+        from customer_transfer t
+        left join user f on f.id = t.from_salesman
+        left join user tu on tu.id = t.to_salesman
+        left join user o on o.id = t.operator
+        where t.customer = ?
+        order by t.transfer_time desc",
+        (&id,),
+    )?;
+    log!("{user} 成功查询到客户 {id} 的{}条转移记录", data.len());
+    Ok(Response::ok(json!(data)))
+}
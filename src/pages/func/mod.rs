@@ -5,6 +5,7 @@ pub use order::Order;
 mod product;
 pub use product::DEFAULT_PRODUCT_COVER;
 mod report;
+pub mod search;
 use std::collections::HashMap;
 
 use axum::Router;
@@ -15,6 +16,7 @@ use crate::{log, Response};
 use self::customer::index::CustomCustomerData;
 
 mod customer;
+pub use customer::{auto_push_to_sea, notify_appointments};
 
 pub fn func_router() -> Router {
     customer::customer_router()
@@ -23,6 +25,7 @@ pub fn func_router() -> Router {
         .merge(order::order_router())
         .merge(store::store_router())
         .merge(supper::router())
+        .merge(search::search_router())
 }
 
 pub fn verify_custom_fields(ver: &[&str], data: &[crate::Field]) -> bool {
@@ -7,7 +7,9 @@ use mysql::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{common::Person, mysql_stmt, Response};
+use crate::{
+    common::Person, mysql_stmt, pages::func::store::lot::query_doc_lots, Response,
+};
 
 use super::{
     customer::Customer, invoice::Invoice, payment::Instalment, product::Product, ship::Ship,
@@ -65,6 +67,7 @@ impl Order {
         self.query_insalment(conn)?;
         self.query_invoice(conn)?;
         self.query_product(conn)?;
+        self.ship.lots = query_doc_lots(conn, &self.id)?;
         Ok(())
     }
     pub fn query_insalment(&mut self, conn: &mut PooledConn) -> mysql::Result<()> {
@@ -199,7 +202,8 @@ impl FromRow for Order {
             ship: Ship {
                 shipped: get!(map, "shipped"),
                 date: get!(map, "shipped_date"),
-                storehouse: get!(map, "shipped_storehouse")
+                storehouse: get!(map, "shipped_storehouse"),
+                lots: Vec::new()
             },
             comment: get!(map, "comment"),
         }));
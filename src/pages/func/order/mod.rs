@@ -7,6 +7,7 @@ use std::{fmt::Display, sync::Arc};
 mod customer;
 mod invoice;
 mod payment;
+mod price;
 mod product;
 mod ship;
 
@@ -28,9 +29,12 @@ use crate::{
     get_cache,
     libs::{cache::{ORDER_CACHE, ORDER_CACHE_WITH_ID}, gen_file_link, gen_id, parse_multipart, TimeFormat, TIME},
     log,
-    pages::account::{get_user, User},
+    pages::{
+        account::{get_user, User},
+        func::{customer::index::check_share_right, store::stock::ship_order_stock},
+    },
     parse_jwt_macro,
-    perm::action::OtherGroup,
+    perm::action::{FinanceGroup, OtherGroup},
     response::BodyFile,
     verify_perms, Response, ResponseResult,
 };
@@ -64,6 +68,7 @@ pub fn order_router() -> Router {
             "/order/set/commission/:value",
             post(commission::set_commission),
         )
+        .merge(price::price_router())
 }
 
 async fn upload_order_file(
@@ -149,6 +154,13 @@ async fn __add_order(
     order: &mut Order,
     user: &User,
 ) -> Result<(), Response> {
+    // 没有查询订单或者财务权限时，只能为自己负责或者共享了订单权限的客户添加订单
+    let flag = verify_perms!(&user.role, OtherGroup::NAME, OtherGroup::QUERY_ORDER)
+        || verify_perms!(&user.role, FinanceGroup::NAME, FinanceGroup::QUERY);
+    if !flag {
+        check_share_right(&user.id, &order.customer.id, "order", conn)?;
+    }
+    price::check_list_price(conn, &order.customer.id, &order.product)?;
     let time = TIME::now()?;
     order.create_time = time.format(TimeFormat::YYYYMMDD_HHMMSS);
     order.gen_number(conn)?;
@@ -156,6 +168,9 @@ async fn __add_order(
 
     match order.status {
         1 | 2 => {
+            if order.ship.shipped == 1 && order.ship.storehouse.is_none() {
+                return Err(Response::dissatisfy("ship的storehouse必须设置"));
+            }
             if order.ship.shipped == 1 && order.ship.date.is_none() {
                 order.ship.date = Some(time.format(TimeFormat::YYYYMMDD_HHMMSS));
             }
@@ -168,7 +183,18 @@ async fn __add_order(
         }
     }
     
-    order.insert(conn)
+    order.insert(conn)?;
+    if let (1, Some(storehouse)) = (order.ship.shipped, &order.ship.storehouse) {
+        ship_order_stock(
+            conn,
+            &order.id,
+            storehouse,
+            false,
+            &user.id,
+            &order.ship.lots,
+        )?;
+    }
+    Ok(())
 }
 
 fn query_order_by_id(conn: &mut PooledConn, id: &str) -> Result<Arc<Order>, Response> {
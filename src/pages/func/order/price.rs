@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{get, post},
+    Json, Router,
+};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer,
+    database::get_db,
+    libs::{TimeFormat, TIME},
+    log,
+    pages::{
+        account::get_user,
+        func::product::bundle::{allocate_price, bundle_components},
+    },
+    parse_jwt_macro,
+    perm::action::StorehouseGroup,
+    verify_perms, Response, ResponseResult, ENFORCE_LIST_PRICE, MIN_MARGIN,
+};
+
+use super::product::Product;
+
+pub fn price_router() -> Router {
+    Router::new()
+        .route("/order/price/set", post(set_price))
+        .route("/order/price/delete", post(delete_price))
+        .route("/order/price/list/:product", get(query_price_list))
+        .route("/order/price/suggest", post(suggest_price))
+        .route("/order/price/rule", get(get_price_rule))
+        .route("/order/price/rule/set", post(set_price_rule))
+        .route("/order/price/margin", post(margin_report))
+}
+
+/// 价格表的生效范围
+pub struct PriceScope;
+
+impl PriceScope {
+    /// 所有客户
+    pub const ALL: &'static str = "";
+    /// 客户级别，对应下拉框 customer_level
+    pub const LEVEL: &'static str = "level";
+    /// 指定客户
+    pub const CUSTOMER: &'static str = "customer";
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+struct PriceRule {
+    product: String,
+    #[serde(default)]
+    ty: String,
+    #[serde(default)]
+    scope: String,
+    #[serde(default = "default_min_amount")]
+    min_amount: i64,
+    #[serde(default)]
+    price: f32,
+}
+
+fn default_min_amount() -> i64 {
+    1
+}
+
+/// 指定客户优先于客户级别，客户级别优先于所有客户，同一范围内取满足数量的最大起订量
+fn pick_rule(rules: &[PriceRule]) -> Option<&PriceRule> {
+    rules.iter().max_by_key(|r| {
+        let rank = match r.ty.as_str() {
+            PriceScope::CUSTOMER => 2,
+            PriceScope::LEVEL => 1,
+            _ => 0,
+        };
+        (rank, r.min_amount)
+    })
+}
+
+/// 客户购买指定数量的产品时生效的价格
+fn __list_price(
+    conn: &mut PooledConn,
+    product: &str,
+    customer: &str,
+    level: &str,
+    amount: usize,
+) -> mysql::Result<Option<PriceRule>> {
+    let rules: Vec<PriceRule> = conn.exec(
+        "SELECT product, ty, scope, min_amount, price FROM price_list
+        WHERE product = :product AND min_amount <= :amount
+        AND (ty = '' OR (ty = 'level' AND scope = :level) OR (ty = 'customer' AND scope = :customer))",
+        params! {
+            "product" => product,
+            "amount" => amount,
+            "level" => level,
+            "customer" => customer
+        },
+    )?;
+    Ok(pick_rule(&rules).cloned())
+}
+
+fn __customer_level(conn: &mut PooledConn, customer: &str) -> mysql::Result<String> {
+    let level: Option<String> = conn.exec_first(
+        "SELECT level FROM customer WHERE id = ? LIMIT 1",
+        (customer,),
+    )?;
+    Ok(level.unwrap_or_default())
+}
+
+/// 开启强制价格表时，订单产品的折后单价不能低于价格表中的价格
+pub fn check_list_price(
+    conn: &mut PooledConn,
+    customer: &str,
+    products: &[Product],
+) -> Result<(), Response> {
+    if !unsafe { ENFORCE_LIST_PRICE } {
+        return Ok(());
+    }
+    let level = __customer_level(conn, customer)?;
+    for p in products {
+        if let Some(rule) = __list_price(conn, &p.id, customer, &level, p.amount)? {
+            let sale = p.price * (1.0 - p.discount);
+            if sale + 0.001 < rule.price {
+                log!(
+                    "产品 {} 的折后单价{sale}低于价格表中的价格{}",
+                    p.id,
+                    rule.price
+                );
+                return Err(Response::dissatisfy(format!(
+                    "产品 {} 的折后单价{sale}低于价格表中的价格{}",
+                    p.name, rule.price
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn can_set_price(role: &str) -> Result<bool, Response> {
+    Ok(verify_perms!(
+        role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::PRICE_LIST
+    ))
+}
+
+fn check_scope(conn: &mut PooledConn, rule: &PriceRule) -> Result<(), Response> {
+    let exist: Option<i32> = match rule.ty.as_str() {
+        PriceScope::ALL => {
+            op::ternary!(rule.scope.is_empty() => Some(1), None)
+        }
+        PriceScope::LEVEL => conn.exec_first(
+            "SELECT 1 FROM drop_down_box WHERE name = 'customer_level' AND value = ? LIMIT 1",
+            (&rule.scope,),
+        )?,
+        PriceScope::CUSTOMER => conn.exec_first(
+            "SELECT 1 FROM customer WHERE id = ? LIMIT 1",
+            (&rule.scope,),
+        )?,
+        _ => return Err(Response::invalid_value("ty只能为空、level或者customer")),
+    };
+    if exist.is_none() {
+        return Err(Response::not_exist(format!(
+            "价格表范围 {} 不存在",
+            rule.scope
+        )));
+    }
+    Ok(())
+}
+
+async fn set_price(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !can_set_price(&user.role).await? {
+        log!("{user} 设置价格表失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let rule: PriceRule = serde_json::from_value(value)?;
+    if rule.min_amount < 1 || rule.price < 0.0 {
+        return Err(Response::invalid_value("起订量至少为1，价格不能为负数"));
+    }
+    let _: i32 = op::some!(conn.exec_first(
+        "SELECT 1 FROM product WHERE id = ? LIMIT 1",
+        (&rule.product,))?;
+        ret Err(Response::not_exist("产品不存在")));
+    check_scope(&mut conn, &rule)?;
+    let time = TIME::now()?;
+    conn.exec_drop(
+        "INSERT INTO price_list (product, ty, scope, min_amount, price, create_time)
+        VALUES (:product, :ty, :scope, :min_amount, :price, :time)
+        ON DUPLICATE KEY UPDATE price = :price",
+        params! {
+            "product" => &rule.product,
+            "ty" => &rule.ty,
+            "scope" => &rule.scope,
+            "min_amount" => rule.min_amount,
+            "price" => rule.price,
+            "time" => time.format(TimeFormat::YYYYMMDD_HHMMSS)
+        },
+    )?;
+    log!(
+        "{user} 设置了产品 {} 的价格表，范围 {}-{}，起订量{}，价格{}",
+        rule.product,
+        rule.ty,
+        rule.scope,
+        rule.min_amount,
+        rule.price
+    );
+    Ok(Response::empty())
+}
+
+async fn delete_price(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !can_set_price(&user.role).await? {
+        log!("{user} 删除价格表失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let rule: PriceRule = serde_json::from_value(value)?;
+    conn.exec_drop(
+        "DELETE FROM price_list WHERE product = ? AND ty = ? AND scope = ? AND min_amount = ? LIMIT 1",
+        (&rule.product, &rule.ty, &rule.scope, rule.min_amount),
+    )?;
+    log!(
+        "{user} 删除了产品 {} 的价格表，范围 {}-{}，起订量{}",
+        rule.product,
+        rule.ty,
+        rule.scope,
+        rule.min_amount
+    );
+    Ok(Response::empty())
+}
+
+#[derive(Serialize, FromRow)]
+struct PriceListData {
+    ty: String,
+    scope: String,
+    /// 指定客户时为客户名称
+    scope_name: String,
+    min_amount: i64,
+    price: f32,
+    create_time: String,
+}
+
+async fn query_price_list(header: HeaderMap, Path(product): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let data: Vec<PriceListData> = conn.exec(
+        "SELECT pl.ty, pl.scope, IFNULL(c.name, pl.scope) AS scope_name, pl.min_amount,
+        pl.price, pl.create_time
+        FROM price_list pl
+        LEFT JOIN customer c ON pl.ty = 'customer' AND c.id = pl.scope
+        WHERE pl.product = ? ORDER BY pl.ty, pl.scope, pl.min_amount",
+        (&product,),
+    )?;
+    Ok(Response::ok(json!(data)))
+}
+
+#[derive(Deserialize)]
+struct SuggestItem {
+    id: String,
+    amount: usize,
+}
+
+#[derive(Deserialize)]
+struct SuggestParams {
+    customer: String,
+    products: Vec<SuggestItem>,
+}
+
+/// 下单时建议的单价，没有匹配的价格表时使用产品的售价
+async fn suggest_price(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let params: SuggestParams = serde_json::from_value(value)?;
+    let level = __customer_level(&mut conn, &params.customer)?;
+    let mut data = Vec::new();
+    for item in params.products {
+        let price: f32 = op::some!(conn.exec_first(
+            "SELECT price FROM product WHERE id = ? LIMIT 1",
+            (&item.id,))?;
+            ret Err(Response::not_exist(format!("产品 {} 不存在", item.id))));
+        let rule = __list_price(&mut conn, &item.id, &params.customer, &level, item.amount)?;
+        data.push(json!({
+            "id": item.id,
+            "amount": item.amount,
+            "price": rule.as_ref().map_or(price, |r| r.price),
+            "ty": rule.as_ref().map(|r| r.ty.as_str()),
+            "min_amount": rule.as_ref().map(|r| r.min_amount),
+        }));
+    }
+    Ok(Response::ok(json!({
+        "enforce": unsafe { ENFORCE_LIST_PRICE },
+        "products": data
+    })))
+}
+
+async fn get_price_rule() -> ResponseResult {
+    Ok(Response::ok(json!({
+        "enforce": unsafe { ENFORCE_LIST_PRICE },
+        "min_margin": unsafe { MIN_MARGIN }
+    })))
+}
+
+#[derive(Deserialize)]
+struct PriceRuleParams {
+    enforce: bool,
+    min_margin: f32,
+}
+
+async fn set_price_rule(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !can_set_price(&user.role).await? {
+        log!("{user} 设置价格规则失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let rule: PriceRuleParams = serde_json::from_value(value)?;
+    if !(-1.0..1.0).contains(&rule.min_margin) {
+        return Err(Response::invalid_value("最低毛利率必须在-1到1之间"));
+    }
+    crate::set_price_rule(rule.enforce, rule.min_margin)?;
+    log!(
+        "{user} 已将价格规则设置为{}价格表，最低毛利率{}",
+        op::ternary!(rule.enforce => "强制", "不强制"),
+        rule.min_margin
+    );
+    Ok(Response::empty())
+}
+
+/// 折后单价相对进货价的毛利率，折后单价不大于0时视为-1
+fn margin(price: f32, discount: f32, purchase_price: f32) -> f32 {
+    let sale = price * (1.0 - discount);
+    if sale <= 0.0 {
+        return -1.0;
+    }
+    (sale - purchase_price) / sale
+}
+
+#[derive(Deserialize)]
+struct MarginParams {
+    start: String,
+    end: String,
+}
+
+#[derive(FromRow, Clone)]
+struct MarginRow {
+    order_id: String,
+    number: String,
+    create_time: String,
+    customer: String,
+    customer_name: Option<String>,
+    salesman: String,
+    product: String,
+    name: String,
+    price: f32,
+    discount: f32,
+    amount: i64,
+    purchase_price: f32,
+    bundle: i32,
+}
+
+/// 组合产品按照组件售价的比例分摊价格，拆分成组件的明细，第二项为所属的组合产品
+fn __split_bundle(
+    conn: &mut PooledConn,
+    rows: Vec<MarginRow>,
+) -> mysql::Result<Vec<(MarginRow, Option<String>)>> {
+    let mut components = HashMap::new();
+    let mut lines = Vec::new();
+    for r in rows {
+        if r.bundle != 1 {
+            lines.push((r, None));
+            continue;
+        }
+        if !components.contains_key(&r.product) {
+            components.insert(r.product.clone(), bundle_components(conn, &r.product)?);
+        }
+        let list = &components[&r.product];
+        for (c, share) in list.iter().zip(allocate_price(r.price, list)) {
+            let line = MarginRow {
+                product: c.product.clone(),
+                name: c.name.clone(),
+                price: share / c.amount as f32,
+                amount: r.amount * c.amount,
+                purchase_price: c.purchase_price,
+                bundle: 0,
+                ..r.clone()
+            };
+            lines.push((line, Some(r.product.clone())));
+        }
+    }
+    Ok(lines)
+}
+
+/// 成交订单中毛利率低于最低毛利率的产品，组合产品按组件统计
+async fn margin_report(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !can_set_price(&user.role).await? {
+        log!("{user} 查询毛利报表失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let params: MarginParams = serde_json::from_value(value)?;
+    let min_margin = unsafe { MIN_MARGIN };
+    let rows: Vec<MarginRow> = conn.exec(
+        "SELECT o.id AS order_id, o.number, o.create_time, o.customer, c.name AS customer_name,
+        o.salesman, op.id AS product, p.name, op.price, op.discount, op.amount, p.purchase_price,
+        p.bundle
+        FROM order_product op
+        JOIN order_data o ON o.id = op.order_id
+        JOIN product p ON p.id = op.id
+        LEFT JOIN customer c ON c.id = o.customer
+        WHERE o.status != 0 AND o.create_time >= ? AND LEFT(o.create_time, 10) <= ?
+        ORDER BY o.create_time DESC",
+        (&params.start, &params.end),
+    )?;
+    let data: Vec<Value> = __split_bundle(&mut conn, rows)?
+        .into_iter()
+        .filter_map(|(r, bundle)| {
+            let m = margin(r.price, r.discount, r.purchase_price);
+            op::ternary!(m < min_margin => Some(json!({
+                "order_id": r.order_id,
+                "number": r.number,
+                "create_time": r.create_time,
+                "customer": r.customer,
+                "customer_name": r.customer_name,
+                "salesman": r.salesman,
+                "product": r.product,
+                "name": r.name,
+                "bundle": bundle,
+                "price": r.price,
+                "discount": r.discount,
+                "amount": r.amount,
+                "purchase_price": r.purchase_price,
+                "margin": m
+            })), None)
+        })
+        .collect();
+    Ok(Response::ok(json!({
+        "min_margin": min_margin,
+        "data": data
+    })))
+}
+
+#[test]
+fn test_pick_rule() {
+    let rule = |ty: &str, min_amount: i64, price: f32| PriceRule {
+        product: "p".into(),
+        ty: ty.into(),
+        scope: String::new(),
+        min_amount,
+        price,
+    };
+    let rules = vec![
+        rule(PriceScope::ALL, 1, 10.0),
+        rule(PriceScope::ALL, 100, 8.0),
+        rule(PriceScope::LEVEL, 1, 9.0),
+    ];
+    assert_eq!(pick_rule(&rules).unwrap().price, 9.0);
+    assert_eq!(pick_rule(&rules[..2]).unwrap().price, 8.0);
+    assert!(pick_rule(&[]).is_none());
+    assert!((margin(10.0, 0.2, 6.0) - 0.25).abs() < 0.0001);
+    assert_eq!(margin(0.0, 0.0, 6.0), -1.0);
+}
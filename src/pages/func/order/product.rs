@@ -1,4 +1,4 @@
-use crate::libs::dser::serialize_f32_to_string;
+use crate::{libs::dser::serialize_f32_to_string, Response};
 use mysql::{params, prelude::Queryable, PooledConn};
 use mysql_common::prelude::FromRow;
 use serde::{Deserialize, Deserializer, Serialize};
@@ -101,7 +101,20 @@ impl Product {
         id: &str,
         conn: &mut PooledConn,
         del: bool,
-    ) -> mysql::Result<()> {
+    ) -> Result<(), Response> {
+        // 有规格的产品只能按照具体的规格下单
+        for product in products {
+            let parent: Option<i32> = conn.exec_first(
+                "SELECT 1 FROM product WHERE parent = ? LIMIT 1",
+                (&product.id,),
+            )?;
+            if parent.is_some() {
+                return Err(Response::invalid_value(format!(
+                    "产品 {} 有多个规格，请选择具体的规格",
+                    product.name
+                )));
+            }
+        }
         if del {
             conn.exec_drop("delete from order_product where order_id = ?", (id,))?;
         }
@@ -116,7 +129,8 @@ impl Product {
                     "amount" => product.amount
                 }
             }),
-        )
+        )?;
+        Ok(())
     }
     pub fn query(order: &mut Order, conn: &mut PooledConn) -> mysql::Result<()> {
         order.product = conn.exec(
@@ -8,13 +8,13 @@ use crate::{
     database::get_db,
     libs::{cache::{ORDER_CACHE, ORDER_CACHE_WITH_ID}, TimeFormat, TIME},
     log,
-    pages::{account::get_user, User},
+    pages::{account::get_user, func::store::stock::ship_order_stock, User},
     parse_jwt_macro, Response, ResponseResult,
 };
 
 use super::{
-    customer::Customer, data::Order, invoice::Invoice, payment::Instalment, product::Product,
-    query_order_by_id, ship::Ship, verify_instalment,
+    customer::Customer, data::Order, invoice::Invoice, payment::Instalment,
+    price::check_list_price, product::Product, query_order_by_id, ship::Ship, verify_instalment,
 };
 
 #[derive(Deserialize)]
@@ -58,6 +58,7 @@ fn __order_transaction(
         return Err(Response::permission_denied());
     }
     verify_instalment(&param.product, &param.instalment)?;
+    check_list_price(conn, &param.customer.id, &param.product)?;
     if order.status == 0 {
         if param.ship.shipped == 1 && param.ship.storehouse.is_none() {
             return Err(Response::dissatisfy("ship的storehouse必须设置"));
@@ -98,6 +99,16 @@ fn __order_transaction(
                 "pu" => &param.customer.purchase_unit
             },
         )?;
+        if let (1, Some(storehouse)) = (param.ship.shipped, &param.ship.storehouse) {
+            ship_order_stock(
+                conn,
+                &param.id,
+                storehouse,
+                false,
+                &user.id,
+                &param.ship.lots,
+            )?;
+        }
         Ok(())
     } else {
         Err(Response::dissatisfy("仅支持意向订单"))
@@ -217,6 +228,7 @@ fn update_status0(conn: &mut PooledConn, param: &mut UpdateOrderParam0) -> Resul
             "purchase_unit" => &param.customer.purchase_unit,
         },
     )?;
+    check_list_price(conn, &param.customer.id, &param.product)?;
     Product::insert(&param.product, &param.id, conn, true)?;
 
     Ok(())
@@ -272,6 +284,24 @@ fn update_status1(
             "date" => &param.ship.date
         },
     )?;
+    // 取消发货或者更换库房时，先将产品放回原来的库房
+    let old = op::ternary!(order.ship.shipped == 1 => order.ship.storehouse.as_deref(), None);
+    let new = op::ternary!(param.ship.shipped == 1 => param.ship.storehouse.as_deref(), None);
+    if old != new {
+        if let Some(storehouse) = old {
+            ship_order_stock(conn, &order.id, storehouse, true, &user.id, &[])?;
+        }
+        if let Some(storehouse) = new {
+            ship_order_stock(
+                conn,
+                &order.id,
+                storehouse,
+                false,
+                &user.id,
+                &param.ship.lots,
+            )?;
+        }
+    }
 
     Ok(())
 }
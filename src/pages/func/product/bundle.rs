@@ -0,0 +1,164 @@
+use std::collections::HashSet;
+
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{get, post},
+    Json, Router,
+};
+use mysql::{prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback, database::get_db, libs::cache::PRODUCT_CACHE, log,
+    pages::account::get_user, parse_jwt_macro, perm::action::StorehouseGroup, verify_perms,
+    Response, ResponseResult,
+};
+
+pub fn bundle_router() -> Router {
+    Router::new()
+        .route("/product/bundle/set/:id", post(set_bundle))
+        .route("/product/bundle/:id", get(query_bundle))
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct BundleComponent {
+    pub product: String,
+    pub name: String,
+    /// 一个组合产品包含的数量
+    pub amount: i64,
+    pub price: f32,
+    pub purchase_price: f32,
+}
+
+pub fn bundle_components(conn: &mut PooledConn, id: &str) -> mysql::Result<Vec<BundleComponent>> {
+    conn.exec(
+        "SELECT b.product, p.name, b.amount, p.price, p.purchase_price
+        FROM product_bundle b JOIN product p ON p.id = b.product
+        WHERE b.bundle = ? ORDER BY p.num",
+        (id,),
+    )
+}
+
+/// 按照各组件售价的比例把组合产品的价格分摊到组件上，组件都没有售价时平均分摊
+pub fn allocate_price(total: f32, components: &[BundleComponent]) -> Vec<f32> {
+    let weights: Vec<f32> = components
+        .iter()
+        .map(|c| c.price.max(0.0) * c.amount as f32)
+        .collect();
+    let sum: f32 = weights.iter().sum();
+    if sum <= 0.0 {
+        let len = components.len().max(1) as f32;
+        return components.iter().map(|_| total / len).collect();
+    }
+    weights.iter().map(|w| total * w / sum).collect()
+}
+
+#[derive(Deserialize)]
+struct ComponentParams {
+    product: String,
+    amount: i64,
+}
+
+/// 设置组合产品的组件，组件为空时恢复为普通产品
+fn __set_bundle(
+    conn: &mut PooledConn,
+    id: &str,
+    components: &[ComponentParams],
+) -> Result<(), Response> {
+    let _: i32 = op::some!(conn.exec_first(
+        "SELECT 1 FROM product WHERE id = ? LIMIT 1 FOR UPDATE",
+        (id,))?;
+        ret Err(Response::not_exist("产品不存在")));
+    let stock: Option<i64> = conn.exec_first(
+        "SELECT CAST(SUM(ABS(amount)) AS SIGNED) FROM product_store WHERE product = ?",
+        (id,),
+    )?;
+    if stock.unwrap_or(0) != 0 {
+        return Err(Response::dissatisfy("该产品还有库存，请先清空库存"));
+    }
+    let used: Option<i32> = conn.exec_first(
+        "SELECT 1 FROM product_bundle WHERE product = ? LIMIT 1",
+        (id,),
+    )?;
+    if used.is_some() && !components.is_empty() {
+        return Err(Response::dissatisfy("该产品是其他组合产品的组件"));
+    }
+    let mut set = HashSet::new();
+    for c in components {
+        if c.amount < 1 || c.product.eq(id) || !set.insert(&c.product) {
+            return Err(Response::invalid_value(
+                "组件不能重复或者为自身，数量至少为1",
+            ));
+        }
+        let bundle: i32 = op::some!(conn.exec_first(
+            "SELECT bundle FROM product WHERE id = ? LIMIT 1",
+            (&c.product,))?;
+            ret Err(Response::not_exist(format!("组件 {} 不存在", c.product))));
+        if bundle == 1 {
+            return Err(Response::dissatisfy("组合产品不能作为组件"));
+        }
+    }
+    conn.exec_drop("DELETE FROM product_bundle WHERE bundle = ?", (id,))?;
+    conn.exec_batch(
+        "INSERT INTO product_bundle (bundle, product, amount) VALUES (?, ?, ?)",
+        components.iter().map(|c| (id, &c.product, c.amount)),
+    )?;
+    conn.exec_drop(
+        "UPDATE product SET bundle = ? WHERE id = ? LIMIT 1",
+        (op::ternary!(components.is_empty() => 0, 1), id),
+    )?;
+    Ok(())
+}
+
+async fn set_bundle(
+    header: HeaderMap,
+    Path(id): Path<String>,
+    Json(value): Json<Value>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::UPDATE_PRODUCT
+    ) {
+        log!("{user} 设置组合产品 {id} 失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let components: Vec<ComponentParams> = serde_json::from_value(value)?;
+    commit_or_rollback!(__set_bundle, &mut conn, &id, &components)?;
+    PRODUCT_CACHE.clear();
+    log!("{user} 设置了组合产品 {id} 的{}个组件", components.len());
+    Ok(Response::empty())
+}
+
+async fn query_bundle(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let components = bundle_components(&mut conn, &id)?;
+    Ok(Response::ok(json!(components)))
+}
+
+#[test]
+fn test_allocate_price() {
+    let component = |price: f32, amount: i64| BundleComponent {
+        product: String::new(),
+        name: String::new(),
+        amount,
+        price,
+        purchase_price: 0.0,
+    };
+    let list = allocate_price(90.0, &[component(100.0, 1), component(25.0, 2)]);
+    assert!((list[0] - 60.0).abs() < 0.001);
+    assert!((list[1] - 30.0).abs() < 0.001);
+    let list = allocate_price(10.0, &[component(0.0, 1), component(0.0, 3)]);
+    assert_eq!(list, vec![5.0, 5.0]);
+}
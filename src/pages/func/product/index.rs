@@ -12,8 +12,10 @@ use crate::{
         func::{
             __insert_custom_fields, __update_custom_fields, customer::index::CustomCustomerData,
             get_custom_fields,
+            search::{__index_product, __remove_index},
+            store::stock::{set_stock, Movement, StockReason},
         },
-        DROP_DOWN_BOX,
+        User, DROP_DOWN_BOX,
     },
     parse_jwt_macro,
     perm::action::StorehouseGroup,
@@ -30,6 +32,8 @@ use mysql::{params, prelude::Queryable, PooledConn};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
+use super::bundle::bundle_components;
+
 pub static DEFAULT: (&str, &[u8]) = ("default_product_cover", include_bytes!("default.png"));
 pub fn product_router() -> Router {
     Router::new()
@@ -121,6 +125,15 @@ struct ProductParams {
     custom_fields: CustomCustomerData,
     #[serde(default)]
     inventory: WrapperInventory,
+    /// 规格产品所属的产品
+    #[serde(skip_deserializing)]
+    parent: Option<String>,
+    /// 规格产品的属性
+    #[serde(skip_deserializing)]
+    attrs: Option<String>,
+    /// 1 组合产品
+    #[serde(skip_deserializing)]
+    bundle: i32,
 }
 
 async fn add_product(header: HeaderMap, part: Multipart) -> ResponseResult {
@@ -143,7 +156,7 @@ async fn add_product(header: HeaderMap, part: Multipart) -> ResponseResult {
     let name = data.name.clone();
     log!("{user} 请求添加产品 {} -- 带封面", name);
     let file = op::some!(part.files.first(); ret Err(Response::dissatisfy("缺少封面")));
-    commit_or_rollback!(async __insert, &mut conn, data, Some(file), &user.role)?;
+    commit_or_rollback!(async __insert, &mut conn, data, Some(file), &user)?;
     PRODUCT_CACHE.clear();
     log!("{user} 成功添加产品 {} -- 带封面", name);
     Ok(Response::empty())
@@ -167,7 +180,7 @@ async fn add_product_json(header: HeaderMap, Json(value): Json<Value>) -> Respon
     let data: ProductParams = serde_json::from_value(value)?;
     let name = data.name.clone();
     log!("{user} 请求添加产品 {} -- 默认封面", name);
-    commit_or_rollback!(async __insert, &mut conn, data, None, &user.role)?;
+    commit_or_rollback!(async __insert, &mut conn, data, None, &user)?;
     log!("{user} 成功添加产品 {} -- 默认封面", name);
     PRODUCT_CACHE.clear();
     Ok(Response::empty())
@@ -177,24 +190,14 @@ async fn __insert(
     conn: &mut PooledConn,
     mut data: ProductParams,
     part: Option<&FilePart>,
-    role: &str,
+    user: &User,
 ) -> Result<(), Response> {
     let time = TIME::now()?;
     data.id = gen_id(&time, &data.name);
-    let pinyin = rust_pinyin::get_pinyin(&data.name);
-    let n: Option<i32> = conn.query_first(format!(
-        "select num from product_num where name='{}'",
-        pinyin
-    ))?;
-
-    let n = n.unwrap_or(0) + 1;
+    let num = __next_num(conn, &data.name)?;
     if data.num.is_empty() {
-        data.num = format!("NO.{}{:0>7}", pinyin, n)
+        data.num = num
     }
-    conn.query_drop(format!(
-        "INSERT INTO product_num (name, num) VALUES ('{pinyin}', {n})
-    ON DUPLICATE KEY UPDATE num = {n}"
-    ))?;
     data.create_time = time.format(TimeFormat::YYYYMMDD_HHMMSS);
     let link = if let Some(part) = part {
         gen_file_link(&time, part.filename())
@@ -226,38 +229,56 @@ async fn __insert(
 
         },
     )?;
-    first_update_store(conn, &data.id, &data.inventory.inner, role).await?;
+    first_update_store(conn, &data.id, &data.inventory.inner, user).await?;
     __insert_custom_fields(conn, &data.custom_fields.inner, 1, &data.id)?;
+    __index_product(conn, &data.id)?;
     if let Some(part) = part {
         std::fs::write(format!("resources/product/cover/{link}"), &part.bytes)?;
     }
     Ok(())
 }
 
+/// 按照产品名称的拼音生成下一个编号
+pub(super) fn __next_num(conn: &mut PooledConn, name: &str) -> Result<String, Response> {
+    let pinyin = rust_pinyin::get_pinyin(name);
+    let n: Option<i32> = conn.query_first(format!(
+        "select num from product_num where name='{}'",
+        pinyin
+    ))?;
+
+    let n = n.unwrap_or(0) + 1;
+    conn.query_drop(format!(
+        "INSERT INTO product_num (name, num) VALUES ('{pinyin}', {n})
+    ON DUPLICATE KEY UPDATE num = {n}"
+    ))?;
+    Ok(format!("NO.{}{:0>7}", pinyin, n))
+}
+
 async fn first_update_store(
     conn: &mut PooledConn,
     id: &str,
     store: &[Inventory],
-    role: &str,
+    user: &User,
 ) -> Result<(), Response> {
     if !store.is_empty()
         && !verify_perms!(
-            role,
+            &user.role,
             StorehouseGroup::NAME,
             StorehouseGroup::ADJUSTING_PRODUCT_INVENTORY
         )
     {
         return Err(Response::permission_denied());
     }
+    let movement = Movement {
+        reason: StockReason::MANUAL,
+        source: id,
+        operator: &user.id,
+    };
     unsafe {
         let map = DROP_DOWN_BOX.get("storehouse");
         for s in store {
             if map.contains(&s.storehouse.as_str()) {
-                conn.query_drop(format!(
-                    "insert into product_store (product, storehouse, amount) 
-                        values ('{id}', '{}', {})",
-                    s.storehouse, s.amount
-                ))?;
+                set_stock(conn, id, &s.storehouse, s.amount as i64, &movement)?;
             }
         }
 
@@ -269,22 +290,22 @@ async fn update_store(
     conn: &mut PooledConn,
     id: &str,
     store: &[Inventory],
-    role: &str,
+    user: &User,
 ) -> Result<(), Response> {
     if !verify_perms!(
-        role,
+        &user.role,
         StorehouseGroup::NAME,
         StorehouseGroup::ADJUSTING_PRODUCT_INVENTORY
     ) {
         return Err(Response::permission_denied());
     }
+    let movement = Movement {
+        reason: StockReason::MANUAL,
+        source: id,
+        operator: &user.id,
+    };
     for s in store {
-        conn.query_drop(format!(
-            "insert into product_store (product, storehouse, amount) 
-                values ('{}', '{}', {}) 
-                on duplicate key update amount = {}",
-            id, s.storehouse, s.amount, s.amount
-        ))?;
+        set_stock(conn, id, &s.storehouse, s.amount as i64, &movement)?;
     }
 
     Ok(())
@@ -302,7 +323,7 @@ async fn update_product_store(
     let user = get_user(&uid, &mut conn).await?;
     log!("{user} 请求更新产品 {} 的库存", id);
     let inventory: Vec<Inventory> = serde_json::from_value(value)?;
-    update_store(&mut conn, &id, &inventory, &user.role).await?;
+    commit_or_rollback!(async update_store, &mut conn, &id, &inventory, &user)?;
 
     log!("{user} 成功更新产品 {} 的库存", id);
     PRODUCT_CACHE.clear();
@@ -404,6 +425,12 @@ fn __update(
     )?;
 
     __update_custom_fields(conn, &data.custom_fields.inner, 1, &data.id)?;
+    __index_product(conn, &data.id)?;
+    // 规格产品默认使用所属产品的封面
+    conn.exec_drop(
+        "UPDATE product SET cover = ? WHERE parent = ? AND cover = ?",
+        (&link, &data.id, &cover),
+    )?;
     if let Some(f) = part {
         std::fs::write(format!("resources/product/cover/{link}"), &f.bytes)?;
         println!("remove -- {}", cover);
@@ -476,7 +503,33 @@ async fn query_product(Json(value): Json<Value>) -> ResponseResult {
     }
 
     log!("共查询到 {} 条产品信息", products.len());
-    let value = json!(products);
+    // 规格产品放在所属产品的variants中
+    let (variants, mut products): (Vec<_>, Vec<_>) =
+        products.into_iter().partition(|p| p.parent.is_some());
+    for v in &variants {
+        let parent = v.parent.as_deref().unwrap_or_default();
+        if !products.iter().any(|p| p.id.eq(parent)) {
+            let p: Option<ProductParams> = conn.exec_first(
+                "SELECT *, 1 as custom_fields, 1 as inventory FROM product WHERE id = ? LIMIT 1",
+                (parent,),
+            )?;
+            products.extend(p);
+        }
+    }
+    let mut values = Vec::new();
+    for p in products {
+        let list: Vec<&ProductParams> = variants
+            .iter()
+            .filter(|v| v.parent.as_deref() == Some(p.id.as_str()))
+            .collect();
+        let mut value = json!(p);
+        value["variants"] = json!(list);
+        if p.bundle == 1 {
+            value["components"] = json!(bundle_components(&mut conn, &p.id)?);
+        }
+        values.push(value);
+    }
+    let value = json!(values);
     PRODUCT_CACHE.insert(param_str, value.clone());
     Ok(Response::ok(value))
 }
@@ -500,7 +553,10 @@ async fn query_by(Path(id): Path<String>) -> ResponseResult {
         ))?;
         d.custom_fields = get_custom_fields(&mut conn, &d.id, 1)?;
     }
-    let value = json!(data);
+    let mut value = json!(data);
+    if let Some(d) = data.as_ref().filter(|d| d.bundle == 1) {
+        value["components"] = json!(bundle_components(&mut conn, &d.id)?);
+    }
     PRODUCT_CACHE.insert(id, value.clone());
     Ok(Response::ok(value))
 }
@@ -521,14 +577,32 @@ async fn delete_storehouse(
     ) {
         return Err(Response::permission_denied());
     }
-    conn.exec_batch(
-        "delete from product_store where product = ? and storehouse = ?",
-        value.iter().map(|v| (&id, v)),
-    )?;
+    commit_or_rollback!(__delete_storehouse, &mut conn, &id, &value, &user.id)?;
 
     PRODUCT_CACHE.clear();
     Ok(Response::empty())
 }
+/// 移除产品在库房中的库存前先记录一条清零的流水
+fn __delete_storehouse(
+    conn: &mut PooledConn,
+    id: &str,
+    storehouse: &[String],
+    operator: &str,
+) -> Result<(), Response> {
+    let movement = Movement {
+        reason: StockReason::MANUAL,
+        source: id,
+        operator,
+    };
+    for s in storehouse {
+        set_stock(conn, id, s, 0, &movement)?;
+    }
+    conn.exec_batch(
+        "delete from product_store where product = ? and storehouse = ?",
+        storehouse.iter().map(|v| (id, v)),
+    )?;
+    Ok(())
+}
 async fn delete_product(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
     let bearer = bearer!(&header);
     let db = get_db().await?;
@@ -547,14 +621,39 @@ async fn delete_product(header: HeaderMap, Path(id): Path<String>) -> ResponseRe
     Ok(Response::empty())
 }
 fn __delete_product(conn: &mut PooledConn, id: &str) -> Result<(), Response> {
+    let variant: Option<i32> = conn.exec_first(
+        "SELECT 1 FROM product WHERE parent = ? LIMIT 1",
+        (id,),
+    )?;
+    if variant.is_some() {
+        return Err(Response::dissatisfy("请先删除该产品的所有规格"));
+    }
+    let component: Option<i32> = conn.exec_first(
+        "SELECT 1 FROM product_bundle WHERE product = ? LIMIT 1",
+        (id,),
+    )?;
+    if component.is_some() {
+        return Err(Response::dissatisfy("该产品是组合产品的组件，请先从组合产品中移除"));
+    }
     let cover: Option<String> =
         conn.query_first(format!("select cover from product where id = '{id}'"))?;
     conn.query_drop(format!("DELETE FROM custom_field_data WHERE id = '{id}'"))?;
     conn.query_drop(format!("DELETE FROM product WHERE id = '{id}' LIMIT 1"))?;
     conn.query_drop(format!("DELETE FROM product_store WHERE product = '{id}'"))?;
+    conn.query_drop(format!("DELETE FROM stock_level WHERE product = '{id}'"))?;
+    conn.query_drop(format!("DELETE FROM stock_alert WHERE product = '{id}'"))?;
+    conn.query_drop(format!("DELETE FROM product_supplier WHERE product = '{id}'"))?;
+    conn.query_drop(format!("DELETE FROM stock_lot WHERE product = '{id}'"))?;
+    conn.query_drop(format!("DELETE FROM product_attr WHERE product = '{id}'"))?;
+    conn.query_drop(format!("DELETE FROM price_list WHERE product = '{id}'"))?;
+    conn.query_drop(format!("DELETE FROM product_bundle WHERE bundle = '{id}'"))?;
+    __remove_index(conn, "product", id)?;
 
     if let Some(cover) = cover {
-        if !cover.eq(DEFAULT.0) {
+        // 规格产品与所属产品共用封面
+        let shared: Option<i32> =
+            conn.exec_first("SELECT 1 FROM product WHERE cover = ? LIMIT 1", (&cover,))?;
+        if !cover.eq(DEFAULT.0) && shared.is_none() {
             std::fs::remove_file(format!("resources/product/cover/{cover}"))?;
         }
     }
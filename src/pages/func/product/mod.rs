@@ -1,6 +1,11 @@
+pub mod bundle;
 mod index;
+mod variant;
 use axum::Router;
 pub use index::DEFAULT as DEFAULT_PRODUCT_COVER;
 pub fn product_router() -> Router {
-    Router::new().merge(index::product_router())
+    Router::new()
+        .merge(index::product_router())
+        .merge(variant::variant_router())
+        .merge(bundle::bundle_router())
 }
@@ -0,0 +1,330 @@
+use std::collections::HashSet;
+
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{get, post},
+    Json, Router,
+};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::get_db,
+    libs::{cache::PRODUCT_CACHE, gen_id, TimeFormat, TIME},
+    log,
+    pages::{account::get_user, func::search::__index_product},
+    parse_jwt_macro,
+    perm::action::StorehouseGroup,
+    verify_perms, Response, ResponseResult,
+};
+
+use super::index::__next_num;
+
+pub fn variant_router() -> Router {
+    Router::new()
+        .route("/product/variant/generate/:id", post(generate_variants))
+        .route("/product/variant/update", post(update_variant))
+        .route("/product/variant/list/:id", get(query_variants))
+}
+
+/// 一次最多生成的规格数量
+const MAX_VARIANTS: usize = 200;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct VariantAttr {
+    name: String,
+    values: Vec<String>,
+}
+
+/// 所有属性值的组合，每个组合按照属性的顺序排列
+fn combinations(attrs: &[VariantAttr]) -> Vec<Vec<(String, String)>> {
+    attrs.iter().fold(vec![Vec::new()], |acc, attr| {
+        acc.into_iter()
+            .flat_map(|c| {
+                attr.values.iter().map(move |v| {
+                    let mut c = c.clone();
+                    c.push((attr.name.clone(), v.clone()));
+                    c
+                })
+            })
+            .collect()
+    })
+}
+
+/// 规格产品的属性按照属性名排序后保存，属性顺序改变时不会重复生成
+fn attrs_key(combination: &[(String, String)]) -> Result<String, Response> {
+    let mut sorted = combination.to_vec();
+    sorted.sort();
+    Ok(serde_json::to_string(&sorted)?)
+}
+
+#[derive(Deserialize)]
+struct GenerateParams {
+    attrs: Vec<VariantAttr>,
+    /// 为空时使用所属产品的价格
+    price: Option<f32>,
+    purchase_price: Option<f32>,
+}
+
+impl GenerateParams {
+    fn check(&self) -> Result<(), Response> {
+        if self.attrs.is_empty() {
+            return Err(Response::invalid_value("至少需要一个属性"));
+        }
+        let mut names = HashSet::new();
+        for attr in &self.attrs {
+            let values: HashSet<&String> = attr.values.iter().collect();
+            if attr.name.is_empty() || !names.insert(&attr.name) {
+                return Err(Response::invalid_value("属性名不能为空或者重复"));
+            }
+            if attr.values.is_empty() || values.len() != attr.values.len() {
+                return Err(Response::invalid_value(format!(
+                    "属性 {} 的值不能为空或者重复",
+                    attr.name
+                )));
+            }
+        }
+        let count = self.attrs.iter().map(|a| a.values.len()).product::<usize>();
+        if count > MAX_VARIANTS {
+            return Err(Response::invalid_value(format!(
+                "规格数量不能超过{MAX_VARIANTS}个"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// 保存属性并生成缺少的规格产品，已经存在的规格保持不变，返回新生成的规格id
+fn __generate_variants(
+    conn: &mut PooledConn,
+    id: &str,
+    params: &GenerateParams,
+) -> Result<Vec<String>, Response> {
+    let parent: Option<(String, Option<String>)> = conn.exec_first(
+        "SELECT name, parent FROM product WHERE id = ? LIMIT 1 FOR UPDATE",
+        (id,),
+    )?;
+    let (name, _) = match parent {
+        Some((_, Some(_))) => return Err(Response::dissatisfy("规格产品不能再生成规格")),
+        Some(p) => p,
+        None => return Err(Response::not_exist("产品不存在")),
+    };
+    conn.exec_drop("DELETE FROM product_attr WHERE product = ?", (id,))?;
+    for (i, attr) in params.attrs.iter().enumerate() {
+        conn.exec_drop(
+            "INSERT INTO product_attr (product, name, vals, sort) VALUES (?, ?, ?, ?)",
+            (id, &attr.name, serde_json::to_string(&attr.values)?, i),
+        )?;
+    }
+    let exist: HashSet<String> = conn
+        .exec::<Option<String>, _, _>("SELECT attrs FROM product WHERE parent = ?", (id,))?
+        .into_iter()
+        .flatten()
+        .collect();
+    let time = TIME::now()?;
+    let mut generated = Vec::new();
+    for (i, c) in combinations(&params.attrs).into_iter().enumerate() {
+        let key = attrs_key(&c)?;
+        if exist.contains(&key) {
+            continue;
+        }
+        let values: Vec<&str> = c.iter().map(|(_, v)| v.as_str()).collect();
+        let child_name: String = format!("{name} {}", values.join("/"))
+            .chars()
+            .take(50)
+            .collect();
+        // 同一批次的规格使用相同的时间，用组合的序号区分
+        let child = gen_id(&time, &format!("variant{i}"));
+        let num = __next_num(conn, &name)?;
+        conn.exec_drop(
+            "INSERT INTO product (id, num, name, specification, cover, model, unit,
+                product_type, price, create_time, barcode, explanation, purchase_price,
+                tracking, parent, attrs)
+            SELECT :child, :num, :name, specification, cover, model, unit,
+                product_type, IFNULL(:price, price), :time, '', explanation,
+                IFNULL(:purchase_price, purchase_price), tracking, id, :attrs
+            FROM product WHERE id = :parent",
+            params! {
+                "child" => &child,
+                "num" => num,
+                "name" => &child_name,
+                "price" => params.price,
+                "time" => time.format(TimeFormat::YYYYMMDD_HHMMSS),
+                "purchase_price" => params.purchase_price,
+                "attrs" => &key,
+                "parent" => id
+            },
+        )?;
+        __index_product(conn, &child)?;
+        generated.push(child);
+    }
+    Ok(generated)
+}
+
+async fn generate_variants(
+    header: HeaderMap,
+    Path(id): Path<String>,
+    Json(value): Json<Value>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::ADD_PRODUCT
+    ) {
+        log!("{user} 生成产品 {id} 的规格失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let params: GenerateParams = serde_json::from_value(value)?;
+    params.check()?;
+    let generated = commit_or_rollback!(__generate_variants, &mut conn, &id, &params)?;
+    PRODUCT_CACHE.clear();
+    log!("{user} 为产品 {id} 生成了{}个规格", generated.len());
+    Ok(Response::ok(json!(generated)))
+}
+
+#[derive(Deserialize)]
+struct UpdateVariantParams {
+    id: String,
+    num: String,
+    barcode: String,
+    price: f32,
+    purchase_price: f32,
+}
+
+/// 修改规格产品自己的编号、条形码和价格
+async fn update_variant(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::UPDATE_PRODUCT
+    ) {
+        log!("{user} 修改规格产品失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let params: UpdateVariantParams = serde_json::from_value(value)?;
+    if params.num.is_empty() {
+        return Err(Response::invalid_value("编号不能为空"));
+    }
+    let _: i32 = op::some!(conn.exec_first(
+        "SELECT 1 FROM product WHERE id = ? AND parent IS NOT NULL LIMIT 1",
+        (&params.id,))?;
+        ret Err(Response::not_exist("规格产品不存在")));
+    let duplicate: Option<i32> = conn.exec_first(
+        "SELECT 1 FROM product WHERE num = ? AND id != ? LIMIT 1",
+        (&params.num, &params.id),
+    )?;
+    if duplicate.is_some() {
+        return Err(Response::invalid_value(format!(
+            "编号 {} 已存在",
+            params.num
+        )));
+    }
+    conn.exec_drop(
+        "UPDATE product SET num = :num, barcode = :barcode, price = :price,
+        purchase_price = :purchase_price WHERE id = :id LIMIT 1",
+        params! {
+            "num" => &params.num,
+            "barcode" => &params.barcode,
+            "price" => params.price,
+            "purchase_price" => params.purchase_price,
+            "id" => &params.id
+        },
+    )?;
+    PRODUCT_CACHE.clear();
+    log!("{user} 修改了规格产品 {}", params.id);
+    Ok(Response::empty())
+}
+
+#[derive(FromRow)]
+struct VariantRow {
+    id: String,
+    num: String,
+    name: String,
+    barcode: String,
+    price: f32,
+    purchase_price: f32,
+    attrs: Option<String>,
+}
+
+async fn query_variants(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let attrs: Vec<(String, String)> = conn.exec(
+        "SELECT name, vals FROM product_attr WHERE product = ? ORDER BY sort",
+        (&id,),
+    )?;
+    let mut attr_list = Vec::new();
+    for (name, vals) in attrs {
+        let values: Vec<String> = serde_json::from_str(&vals)?;
+        attr_list.push(VariantAttr { name, values });
+    }
+    let rows: Vec<VariantRow> = conn.exec(
+        "SELECT id, num, name, barcode, price, purchase_price, attrs
+        FROM product WHERE parent = ? ORDER BY create_time, num",
+        (&id,),
+    )?;
+    let mut variants = Vec::new();
+    for r in rows {
+        let inventory: Vec<(String, i64)> = conn.exec(
+            "SELECT storehouse, amount FROM product_store WHERE product = ? ORDER BY storehouse",
+            (&r.id,),
+        )?;
+        let attrs: Vec<(String, String)> =
+            serde_json::from_str(r.attrs.as_deref().unwrap_or("[]"))?;
+        variants.push(json!({
+            "id": r.id,
+            "num": r.num,
+            "name": r.name,
+            "barcode": r.barcode,
+            "price": r.price,
+            "purchase_price": r.purchase_price,
+            "attrs": attrs,
+            "inventory": inventory
+                .into_iter()
+                .map(|(storehouse, amount)| json!({"storehouse": storehouse, "amount": amount}))
+                .collect::<Vec<_>>()
+        }));
+    }
+    Ok(Response::ok(json!({
+        "attrs": attr_list,
+        "variants": variants
+    })))
+}
+
+#[test]
+fn test_variant_combinations() {
+    let attrs = vec![
+        VariantAttr {
+            name: "尺码".into(),
+            values: vec!["S".into(), "M".into()],
+        },
+        VariantAttr {
+            name: "颜色".into(),
+            values: vec!["红".into(), "蓝".into(), "白".into()],
+        },
+    ];
+    let list = combinations(&attrs);
+    assert_eq!(list.len(), 6);
+    assert_eq!(
+        list[1],
+        vec![("尺码".into(), "S".into()), ("颜色".into(), "蓝".into())]
+    );
+    let reversed = vec![list[1][1].clone(), list[1][0].clone()];
+    assert_eq!(attrs_key(&list[1]).unwrap(), attrs_key(&reversed).unwrap());
+}
@@ -0,0 +1,202 @@
+use axum::{http::HeaderMap, routing::post, Json, Router};
+use mysql::{params, prelude::Queryable, PooledConn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, database::get_db, libs::pinyin::to_pinyin, log, pages::account::get_user,
+    parse_jwt_macro, Response, ResponseResult,
+};
+
+use super::customer::index::{__visible_customer, check_user_customer};
+
+pub fn search_router() -> Router {
+    Router::new().route("/search", post(search))
+}
+
+/// 重新写入某条数据的索引，`fields`为(字段名, 内容)
+fn __index(
+    conn: &mut PooledConn,
+    ty: &str,
+    id: &str,
+    fields: &[(&str, &str)],
+) -> Result<(), Response> {
+    __remove_index(conn, ty, id)?;
+    conn.exec_batch(
+        "INSERT INTO search_index (ty, id, field, text, pinyin, initials)
+        VALUES (:ty, :id, :field, :text, :pinyin, :initials)",
+        fields
+            .iter()
+            .filter(|(_, text)| !text.trim().is_empty())
+            .map(|(field, text)| {
+                let (pinyin, initials) = to_pinyin(text);
+                params! {
+                    "ty" => ty, "id" => id, "field" => field,
+                    "text" => text.trim(), "pinyin" => pinyin, "initials" => initials
+                }
+            }),
+    )?;
+    Ok(())
+}
+
+pub fn __remove_index(conn: &mut PooledConn, ty: &str, id: &str) -> Result<(), Response> {
+    conn.exec_drop("DELETE FROM search_index WHERE ty = ? AND id = ?", (ty, id))?;
+    Ok(())
+}
+
+/// 根据客户当前的姓名和公司更新索引，客户不存在时删除索引
+pub fn __index_customer(conn: &mut PooledConn, id: &str) -> Result<(), Response> {
+    let data: Option<(String, String)> = conn.exec_first(
+        "SELECT name, company FROM customer WHERE id = ? LIMIT 1",
+        (id,),
+    )?;
+    match data {
+        Some((name, company)) => __index(
+            conn,
+            "customer",
+            id,
+            &[("name", &name), ("company", &company)],
+        ),
+        None => __remove_index(conn, "customer", id),
+    }
+}
+
+pub fn __index_product(conn: &mut PooledConn, id: &str) -> Result<(), Response> {
+    let name: Option<String> =
+        conn.exec_first("SELECT name FROM product WHERE id = ? LIMIT 1", (id,))?;
+    match name {
+        Some(name) => __index(conn, "product", id, &[("name", &name)]),
+        None => __remove_index(conn, "product", id),
+    }
+}
+
+pub fn __index_user(conn: &mut PooledConn, id: &str) -> Result<(), Response> {
+    let name: Option<String> =
+        conn.exec_first("SELECT name FROM user WHERE id = ? LIMIT 1", (id,))?;
+    match name {
+        Some(name) => __index(conn, "user", id, &[("name", &name)]),
+        None => __remove_index(conn, "user", id),
+    }
+}
+
+/// 启动时重建全部索引，保证与已有数据一致
+pub fn rebuild_search_index(conn: &mut PooledConn) -> Result<(), Response> {
+    conn.query_drop("DELETE FROM search_index")?;
+    let customers: Vec<String> = conn.query("SELECT id FROM customer")?;
+    for id in customers {
+        __index_customer(conn, &id)?;
+    }
+    let products: Vec<String> = conn.query("SELECT id FROM product")?;
+    for id in products {
+        __index_product(conn, &id)?;
+    }
+    let users: Vec<String> = conn.query("SELECT id FROM user")?;
+    for id in users {
+        __index_user(conn, &id)?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    keyword: String,
+    /// customer、product或user，为空时搜索全部
+    #[serde(default)]
+    ty: String,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    ty: String,
+    id: String,
+    /// 姓名或者产品名称
+    name: String,
+    /// 客户为公司，产品为编号，员工为部门
+    extra: String,
+    /// 命中的字段
+    fields: Vec<String>,
+}
+
+async fn search(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: SearchParams = serde_json::from_value(value)?;
+    let keyword = params.keyword.trim().to_lowercase();
+    if keyword.is_empty() {
+        return Err(Response::invalid_value("关键词不能为空"));
+    }
+    if !["", "customer", "product", "user"].contains(&params.ty.as_str()) {
+        return Err(Response::invalid_value("ty必须是customer、product或user"));
+    }
+    log!("{user} 请求搜索 `{keyword}`");
+    let like = format!(
+        "%{}%",
+        keyword
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+    // 完全匹配的排在前面，客户需要再按照权限过滤，所以多查询一些
+    let rows: Vec<(String, String, String)> = conn.exec(
+        "SELECT s.ty, s.id, s.field FROM search_index s
+        WHERE (:ty = '' OR s.ty = :ty)
+        AND (s.text LIKE :kw OR s.pinyin LIKE :kw OR s.initials LIKE :kw)
+        AND NOT (s.ty = 'user' AND EXISTS (SELECT 1 FROM leaver l WHERE l.id = s.id))
+        ORDER BY (s.text = :word OR s.pinyin = :word OR s.initials = :word) DESC,
+            CHAR_LENGTH(s.text), s.ty, s.id
+        LIMIT :limit",
+        params! {
+            "ty" => &params.ty, "kw" => like, "word" => &keyword,
+            "limit" => params.limit * 5
+        },
+    )?;
+    let mut hits: Vec<SearchHit> = Vec::new();
+    for (ty, id, field) in rows {
+        if let Some(hit) = hits.iter_mut().find(|h| h.ty == ty && h.id == id) {
+            hit.fields.push(field);
+            continue;
+        }
+        if hits.len() >= params.limit {
+            continue;
+        }
+        let data: Option<(String, String)> = match ty.as_str() {
+            "customer" => {
+                if check_user_customer(&uid, &id, &mut conn).is_err()
+                    && !__visible_customer(&mut conn, &user, &id).await?
+                {
+                    continue;
+                }
+                conn.exec_first(
+                    "SELECT name, company FROM customer WHERE id = ? LIMIT 1",
+                    (&id,),
+                )?
+            }
+            "product" => {
+                conn.exec_first("SELECT name, num FROM product WHERE id = ? LIMIT 1", (&id,))?
+            }
+            _ => conn.exec_first(
+                "SELECT name, department FROM user WHERE id = ? LIMIT 1",
+                (&id,),
+            )?,
+        };
+        let (name, extra) = op::some!(data; continue);
+        hits.push(SearchHit {
+            ty,
+            id,
+            name,
+            extra,
+            fields: vec![field],
+        });
+    }
+    log!("{user} 成功搜索 `{keyword}`，共{}条结果", hits.len());
+    Ok(Response::ok(json!(hits)))
+}
@@ -0,0 +1,393 @@
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{get, post},
+    Json, Router,
+};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer,
+    database::get_db,
+    libs::{cache::PRODUCT_CACHE, TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    perm::action::StorehouseGroup,
+    verify_perms, Response, ResponseResult,
+};
+
+pub fn lot_router() -> Router {
+    Router::new()
+        .route("/store/lot/tracking/set/:product", post(set_tracking))
+        .route("/store/lot/query/:product", post(query_lots))
+        .route("/store/lot/serial/:serial", get(query_serial))
+}
+
+/// 产品的追踪方式
+pub struct Tracking;
+impl Tracking {
+    pub const NONE: i32 = 0;
+    /// 按批次追踪，可以记录过期时间
+    pub const BATCH: i32 = 1;
+    /// 按序列号追踪，每个序列号的数量为1
+    pub const SERIAL: i32 = 2;
+}
+
+/// 入库时填写的批次或者序列号，出库时选择的批次或者序列号
+#[derive(Debug, Deserialize, Serialize, FromRow, Clone)]
+pub struct LotPick {
+    /// 订单中有多个产品时用来区分
+    #[serde(default)]
+    pub product: String,
+    pub lot: String,
+    #[serde(default = "default_amount")]
+    pub amount: i64,
+    /// 批次的过期时间，只在入库时使用
+    #[serde(default)]
+    pub expiry: Option<String>,
+}
+
+fn default_amount() -> i64 {
+    1
+}
+
+/// 调拨发货、盘点审核时选择出库的批次或者序列号，请求体可以为空
+#[derive(Deserialize, Default)]
+pub struct PickParams {
+    #[serde(default)]
+    pub lots: Vec<LotPick>,
+}
+
+fn __tracking(conn: &mut PooledConn, product: &str) -> Result<i32, Response> {
+    let tracking: Option<i32> = conn.exec_first(
+        "SELECT tracking FROM product WHERE id = ? LIMIT 1",
+        (product,),
+    )?;
+    tracking.ok_or_else(|| Response::not_exist(format!("产品 {product} 不存在")))
+}
+
+fn __add_lot(
+    conn: &mut PooledConn,
+    product: &str,
+    storehouse: &str,
+    lot: &str,
+    amount: i64,
+    expiry: &Option<String>,
+) -> Result<(), Response> {
+    conn.exec_drop(
+        "INSERT INTO stock_lot (product, storehouse, lot, amount, expiry, receive_time)
+        VALUES (:product, :storehouse, :lot, :amount, :expiry, :time)
+        ON DUPLICATE KEY UPDATE amount = amount + :amount, expiry = IFNULL(:expiry, expiry)",
+        params! {
+            "product" => product,
+            "storehouse" => storehouse,
+            "lot" => lot,
+            "amount" => amount,
+            "expiry" => expiry,
+            "time" => TIME::now()?.format(TimeFormat::YYYYMMDD_HHMMSS)
+        },
+    )?;
+    Ok(())
+}
+
+/// 入库时记录批次或者序列号，不需要追踪的产品会忽略`lots`
+pub fn put_lots(
+    conn: &mut PooledConn,
+    product: &str,
+    storehouse: &str,
+    amount: i64,
+    lots: &[LotPick],
+) -> Result<(), Response> {
+    let tracking = __tracking(conn, product)?;
+    if tracking == Tracking::NONE {
+        return Ok(());
+    }
+    if lots.iter().map(|l| l.amount).sum::<i64>() != amount || lots.iter().any(|l| l.amount <= 0) {
+        return Err(Response::invalid_value(
+            "批次或者序列号的数量与入库数量不一致",
+        ));
+    }
+    for l in lots {
+        if tracking == Tracking::SERIAL {
+            let exist: Option<i32> = conn.exec_first(
+                "SELECT 1 FROM stock_lot WHERE product = ? AND lot = ? AND amount > 0 LIMIT 1",
+                (product, &l.lot),
+            )?;
+            if l.amount != 1 || exist.is_some() {
+                return Err(Response::invalid_value(format!("序列号 {} 重复", l.lot)));
+            }
+        }
+        __add_lot(conn, product, storehouse, &l.lot, l.amount, &l.expiry)?;
+    }
+    Ok(())
+}
+
+/// 出库时扣除批次或者序列号，并记录在单据上。
+/// 序列号必须全部选择；批次没有选择时按照过期时间和入库时间先进先出，记录不足时只扣除已有的部分
+pub fn take_lots(
+    conn: &mut PooledConn,
+    doc: &str,
+    product: &str,
+    storehouse: &str,
+    amount: i64,
+    picks: &[LotPick],
+) -> Result<(), Response> {
+    let tracking = __tracking(conn, product)?;
+    if amount <= 0 || tracking == Tracking::NONE {
+        return Ok(());
+    }
+    let picks: Vec<&LotPick> = picks.iter().filter(|p| p.product.eq(product)).collect();
+    if tracking == Tracking::SERIAL && picks.iter().map(|p| p.amount).sum::<i64>() != amount {
+        return Err(Response::dissatisfy(format!(
+            "产品 {product} 按序列号追踪，必须选择全部{amount}个序列号"
+        )));
+    }
+    let lots: Vec<(String, i64, Option<String>)> = if picks.is_empty() {
+        let stock: Vec<(String, i64, Option<String>)> = conn.exec(
+            "SELECT lot, amount, expiry FROM stock_lot
+            WHERE product = ? AND storehouse = ? AND amount > 0
+            ORDER BY expiry IS NULL, expiry, receive_time FOR UPDATE",
+            (product, storehouse),
+        )?;
+        let mut left = amount;
+        let mut lots = Vec::new();
+        for (lot, n, expiry) in stock {
+            if left == 0 {
+                break;
+            }
+            let n = n.min(left);
+            left -= n;
+            lots.push((lot, n, expiry));
+        }
+        lots
+    } else {
+        if picks.iter().map(|p| p.amount).sum::<i64>() != amount {
+            return Err(Response::invalid_value(
+                "选择的批次或者序列号数量与出库数量不一致",
+            ));
+        }
+        let mut lots = Vec::new();
+        for p in picks {
+            let lot: Option<(i64, Option<String>)> = conn.exec_first(
+                "SELECT amount, expiry FROM stock_lot
+                WHERE product = ? AND storehouse = ? AND lot = ? LIMIT 1 FOR UPDATE",
+                (product, storehouse, &p.lot),
+            )?;
+            match lot {
+                Some((n, expiry)) if n >= p.amount && p.amount > 0 => {
+                    lots.push((p.lot.clone(), p.amount, expiry))
+                }
+                _ => {
+                    return Err(Response::dissatisfy(format!(
+                        "{storehouse} 中批次或者序列号 {} 的数量不足",
+                        p.lot
+                    )))
+                }
+            }
+        }
+        lots
+    };
+    for (lot, n, expiry) in lots {
+        conn.exec_drop(
+            "UPDATE stock_lot SET amount = amount - ? WHERE product = ? AND storehouse = ? AND lot = ? LIMIT 1",
+            (n, product, storehouse, &lot),
+        )?;
+        conn.exec_drop(
+            "INSERT INTO document_lot (doc, product, storehouse, lot, amount, expiry)
+            VALUES (:doc, :product, :storehouse, :lot, :amount, :expiry)
+            ON DUPLICATE KEY UPDATE amount = amount + :amount",
+            params! {
+                "doc" => doc,
+                "product" => product,
+                "storehouse" => storehouse,
+                "lot" => &lot,
+                "amount" => n,
+                "expiry" => expiry
+            },
+        )?;
+    }
+    conn.exec_drop(
+        "DELETE FROM stock_lot WHERE product = ? AND storehouse = ? AND amount <= 0",
+        (product, storehouse),
+    )?;
+    Ok(())
+}
+
+#[derive(FromRow)]
+struct DocLot {
+    product: String,
+    storehouse: String,
+    lot: String,
+    amount: i64,
+    expiry: Option<String>,
+}
+
+/// 撤销单据的出库，将记录在单据上的批次或者序列号放回原来的库房
+pub fn restore_lots(conn: &mut PooledConn, doc: &str) -> Result<(), Response> {
+    let lots: Vec<DocLot> = conn.exec(
+        "SELECT product, storehouse, lot, amount, expiry FROM document_lot WHERE doc = ?",
+        (doc,),
+    )?;
+    for l in lots {
+        __add_lot(conn, &l.product, &l.storehouse, &l.lot, l.amount, &l.expiry)?;
+    }
+    conn.exec_drop("DELETE FROM document_lot WHERE doc = ?", (doc,))?;
+    Ok(())
+}
+
+/// 调拨单收货时，将发出时记录的批次或者序列号放入调入库房，单据上的记录保留
+pub fn receive_lots(conn: &mut PooledConn, doc: &str, storehouse: &str) -> Result<(), Response> {
+    let lots: Vec<LotPick> = conn.exec(
+        "SELECT product, lot, amount, expiry FROM document_lot WHERE doc = ?",
+        (doc,),
+    )?;
+    for l in lots {
+        __add_lot(conn, &l.product, storehouse, &l.lot, l.amount, &l.expiry)?;
+    }
+    Ok(())
+}
+
+/// 单据上记录的批次或者序列号
+pub fn query_doc_lots(conn: &mut PooledConn, doc: &str) -> mysql::Result<Vec<LotPick>> {
+    conn.exec(
+        "SELECT product, lot, amount, expiry FROM document_lot WHERE doc = ? ORDER BY product, lot",
+        (doc,),
+    )
+}
+
+#[derive(Deserialize)]
+struct TrackingParams {
+    tracking: i32,
+}
+
+async fn set_tracking(
+    header: HeaderMap,
+    Path(product): Path<String>,
+    Json(value): Json<Value>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::UPDATE_PRODUCT
+    ) {
+        log!("{user} 设置产品 {product} 的追踪方式失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let params: TrackingParams = serde_json::from_value(value)?;
+    if ![Tracking::NONE, Tracking::BATCH, Tracking::SERIAL].contains(&params.tracking) {
+        return Err(Response::invalid_value("tracking必须为0、1或者2"));
+    }
+    if __tracking(&mut conn, &product)? == params.tracking {
+        return Ok(Response::empty());
+    }
+    // 已有的库存没有对应的批次或者序列号，切换后无法出库
+    let stock: Option<i64> = conn.exec_first(
+        "SELECT CAST(SUM(ABS(amount)) AS SIGNED) FROM product_store WHERE product = ?",
+        (&product,),
+    )?;
+    if stock.unwrap_or(0) != 0 {
+        return Err(Response::dissatisfy("该产品还有库存，请先清空库存"));
+    }
+    conn.exec_drop(
+        "UPDATE product SET tracking = ? WHERE id = ? LIMIT 1",
+        (params.tracking, &product),
+    )?;
+    PRODUCT_CACHE.clear();
+    log!(
+        "{user} 将产品 {product} 的追踪方式设置为 {}",
+        params.tracking
+    );
+    Ok(Response::empty())
+}
+
+#[derive(Deserialize)]
+struct QueryLotParams {
+    /// 为空时查询全部库房
+    #[serde(default)]
+    storehouse: String,
+}
+
+#[derive(Serialize, FromRow)]
+struct LotData {
+    storehouse: String,
+    lot: String,
+    amount: i64,
+    expiry: Option<String>,
+    receive_time: String,
+}
+
+/// 查询产品在库的批次或者序列号，按照过期时间排序
+async fn query_lots(
+    header: HeaderMap,
+    Path(product): Path<String>,
+    Json(value): Json<Value>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let params: QueryLotParams = serde_json::from_value(value)?;
+    let data: Vec<LotData> = conn.exec(
+        "SELECT storehouse, lot, amount, expiry, receive_time FROM stock_lot
+        WHERE product = :product AND (:storehouse = '' OR storehouse = :storehouse)
+        ORDER BY expiry IS NULL, expiry, receive_time",
+        params! {
+            "product" => &product,
+            "storehouse" => &params.storehouse
+        },
+    )?;
+    Ok(Response::ok(json!(data)))
+}
+
+#[derive(Serialize, FromRow)]
+struct SerialStock {
+    product: String,
+    name: Option<String>,
+    storehouse: String,
+    receive_time: String,
+}
+
+#[derive(Serialize, FromRow)]
+struct SerialOrder {
+    order_id: String,
+    number: String,
+    product: String,
+    customer: String,
+    customer_name: Option<String>,
+    shipped_date: Option<String>,
+}
+
+/// 查询序列号的去向，在库时返回所在的库房，已发货时返回对应的订单和客户
+async fn query_serial(header: HeaderMap, Path(serial): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let stock: Vec<SerialStock> = conn.exec(
+        "SELECT sl.product, p.name, sl.storehouse, sl.receive_time
+        FROM stock_lot sl LEFT JOIN product p ON p.id = sl.product
+        WHERE sl.lot = ? AND sl.amount > 0",
+        (&serial,),
+    )?;
+    let orders: Vec<SerialOrder> = conn.exec(
+        "SELECT o.id AS order_id, o.number, dl.product, o.customer,
+        c.name AS customer_name, o.shipped_date
+        FROM document_lot dl JOIN order_data o ON o.id = dl.doc
+        LEFT JOIN customer c ON c.id = o.customer
+        WHERE dl.lot = ?",
+        (&serial,),
+    )?;
+    Ok(Response::ok(json!({
+        "stock": stock,
+        "orders": orders
+    })))
+}
@@ -1,4 +1,9 @@
+pub mod lot;
+pub mod replenish;
+pub mod stock;
+mod stocktake;
 mod storehouse;
+mod transfer;
 use axum::Router;
 use mysql_common::prelude::FromRow;
 use serde::{Deserialize, Serialize};
@@ -19,4 +24,9 @@ impl PartialEq for Storehouse {
 }
 pub fn store_router() -> Router {
     storehouse::storehouse_router()
+        .merge(stock::stock_router())
+        .merge(transfer::transfer_router())
+        .merge(stocktake::stocktake_router())
+        .merge(replenish::replenish_router())
+        .merge(lot::lot_router())
 }
@@ -0,0 +1,375 @@
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{get, post},
+    Json, Router,
+};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::{__get_conn, get_db},
+    log,
+    pages::{account::get_user, notify::__send_notification},
+    parse_jwt_macro,
+    perm::{action::StorehouseGroup, ROLES_GROUP_MAP},
+    verify_perms, Response, ResponseResult,
+};
+
+use super::stock::StockReason;
+
+pub fn replenish_router() -> Router {
+    Router::new()
+        .route("/store/level/set", post(set_stock_level))
+        .route("/store/level/:product", get(query_stock_level))
+        .route("/store/replenish/report", post(replenish_report))
+        .route(
+            "/store/product/supplier/set/:product",
+            post(set_product_supplier),
+        )
+        .route(
+            "/store/product/supplier/:product",
+            get(query_product_supplier),
+        )
+}
+
+/// 每个产品在每个库房中的库存以及生效的上下限，库房为空的上下限作为该产品在所有库房中的默认值
+const LEVEL_SQL: &str = "SELECT k.product, k.storehouse, p.name, IFNULL(ps.amount, 0) AS amount,
+    COALESCE(s.min_amount, d.min_amount) AS min_amount,
+    COALESCE(s.max_amount, d.max_amount) AS max_amount
+    FROM (SELECT product, storehouse FROM product_store
+        UNION SELECT product, storehouse FROM stock_level WHERE storehouse != '') k
+    JOIN product p ON p.id = k.product
+    LEFT JOIN product_store ps ON ps.product = k.product AND ps.storehouse = k.storehouse
+    LEFT JOIN stock_level s ON s.product = k.product AND s.storehouse = k.storehouse
+    LEFT JOIN stock_level d ON d.product = k.product AND d.storehouse = ''";
+
+#[derive(Serialize, FromRow)]
+struct StockLevel {
+    product: String,
+    storehouse: String,
+    name: String,
+    amount: i64,
+    min_amount: Option<i64>,
+    max_amount: Option<i64>,
+}
+
+/// 库存低于下限的产品
+fn __low_stock(conn: &mut PooledConn, storehouse: &str) -> mysql::Result<Vec<StockLevel>> {
+    conn.exec(
+        format!(
+            "SELECT * FROM ({LEVEL_SQL}) t WHERE t.min_amount IS NOT NULL AND t.amount < t.min_amount
+            AND (:storehouse = '' OR t.storehouse = :storehouse) ORDER BY t.storehouse, t.name"
+        ),
+        params! { "storehouse" => storehouse },
+    )
+}
+
+/// 定时检查库存下限，向拥有调整库存权限的用户发送提醒。
+/// 同一个产品在库房中低于下限时只提醒一次，恢复后再次低于下限时重新提醒
+pub fn check_stock_levels() {
+    let result = __get_conn()
+        .map_err(Response::from)
+        .and_then(|mut conn| __check_stock_levels(&mut conn));
+    match result {
+        Ok(0) => (),
+        Ok(count) => log!("已发送 {count} 条库存不足提醒"),
+        Err(e) => log!("检查库存下限失败，错误信息：{:?}", e),
+    }
+}
+
+/// 拥有调整库存权限的角色。定时任务运行在tokio的运行时中，不能使用blocking_lock，
+/// 权限表被占用时稍后重试
+fn __inventory_roles() -> Result<Vec<String>, Response> {
+    for _ in 0..50 {
+        if let Ok(map) = ROLES_GROUP_MAP.try_lock() {
+            return Ok(map
+                .iter()
+                .filter(|(_, groups)| {
+                    op::catch!(groups
+                        .get(StorehouseGroup::NAME)?
+                        .get(StorehouseGroup::ADJUSTING_PRODUCT_INVENTORY))
+                    .is_some()
+                })
+                .map(|(role, _)| role.clone())
+                .chain(Some("root".to_owned()))
+                .collect());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    Err(Response::internal_server_error(
+        "权限表被占用，无法获取接收提醒的角色",
+    ))
+}
+
+fn __check_stock_levels(conn: &mut PooledConn) -> Result<usize, Response> {
+    let low = __low_stock(conn, "")?;
+    conn.exec_drop(
+        format!(
+            "DELETE a FROM stock_alert a LEFT JOIN ({LEVEL_SQL}) t
+            ON t.product = a.product AND t.storehouse = a.storehouse
+            WHERE t.min_amount IS NULL OR t.amount >= t.min_amount"
+        ),
+        (),
+    )?;
+    if low.is_empty() {
+        return Ok(0);
+    }
+    let roles = __inventory_roles()?;
+    let receivers: Vec<String> = conn.query(format!(
+        "SELECT id FROM user WHERE role IN ({})",
+        roles
+            .iter()
+            .map(|r| format!("'{r}'"))
+            .collect::<Vec<_>>()
+            .join(",")
+    ))?;
+    let mut count = 0;
+    for s in low {
+        conn.exec_drop(
+            "INSERT IGNORE INTO stock_alert (product, storehouse) VALUES (?, ?)",
+            (&s.product, &s.storehouse),
+        )?;
+        if conn.affected_rows() == 0 {
+            continue;
+        }
+        let content = format!(
+            "产品 {} 在 {} 的库存为 {}，低于下限 {}",
+            s.name,
+            s.storehouse,
+            s.amount,
+            s.min_amount.unwrap_or_default()
+        );
+        for r in &receivers {
+            __send_notification(conn, r, "stock", "库存不足", &content, &s.product)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[derive(Deserialize)]
+struct LevelParams {
+    product: String,
+    /// 为空时表示该产品在所有库房中的默认上下限
+    #[serde(default)]
+    storehouse: String,
+    min_amount: Option<i64>,
+    max_amount: Option<i64>,
+}
+
+fn __set_stock_level(conn: &mut PooledConn, levels: &[LevelParams]) -> Result<(), Response> {
+    for l in levels {
+        if let (Some(min), Some(max)) = (l.min_amount, l.max_amount) {
+            if min > max {
+                return Err(Response::invalid_value("库存下限不能大于上限"));
+            }
+        }
+        if l.min_amount.is_none() && l.max_amount.is_none() {
+            conn.exec_drop(
+                "DELETE FROM stock_level WHERE product = ? AND storehouse = ? LIMIT 1",
+                (&l.product, &l.storehouse),
+            )?;
+        } else {
+            conn.exec_drop(
+                "INSERT INTO stock_level (product, storehouse, min_amount, max_amount)
+                VALUES (:product, :storehouse, :min, :max)
+                ON DUPLICATE KEY UPDATE min_amount = :min, max_amount = :max",
+                params! {
+                    "product" => &l.product,
+                    "storehouse" => &l.storehouse,
+                    "min" => l.min_amount,
+                    "max" => l.max_amount
+                },
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// 设置库存上下限，上下限都为空时删除该设置
+async fn set_stock_level(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::ADJUSTING_PRODUCT_INVENTORY
+    ) {
+        log!("{user} 设置库存上下限失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let levels: Vec<LevelParams> = serde_json::from_value(value)?;
+    commit_or_rollback!(__set_stock_level, &mut conn, &levels)?;
+    log!("{user} 设置了{}条库存上下限", levels.len());
+    Ok(Response::empty())
+}
+
+#[derive(Serialize, FromRow)]
+struct LevelData {
+    storehouse: String,
+    min_amount: Option<i64>,
+    max_amount: Option<i64>,
+}
+
+async fn query_stock_level(header: HeaderMap, Path(product): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let data: Vec<LevelData> = conn.exec(
+        "SELECT storehouse, min_amount, max_amount FROM stock_level
+        WHERE product = ? ORDER BY storehouse",
+        (&product,),
+    )?;
+    Ok(Response::ok(json!(data)))
+}
+
+#[derive(Serialize, FromRow)]
+struct SupplierData {
+    id: String,
+    company: String,
+    contact: String,
+    phone: String,
+    mobile_phone: String,
+}
+
+fn __product_suppliers(conn: &mut PooledConn, product: &str) -> mysql::Result<Vec<SupplierData>> {
+    conn.exec(
+        "SELECT s.id, s.company, s.contact, s.phone, s.mobile_phone
+        FROM product_supplier ps JOIN supper s ON s.id = ps.supper
+        WHERE ps.product = ? ORDER BY s.company",
+        (product,),
+    )
+}
+
+fn __set_product_supplier(
+    conn: &mut PooledConn,
+    product: &str,
+    suppliers: &[String],
+) -> Result<(), Response> {
+    conn.exec_drop("DELETE FROM product_supplier WHERE product = ?", (product,))?;
+    conn.exec_batch(
+        "INSERT IGNORE INTO product_supplier (product, supper) VALUES (?, ?)",
+        suppliers.iter().map(|s| (product, s)),
+    )?;
+    Ok(())
+}
+
+/// 设置产品的供应商，会覆盖之前的设置
+async fn set_product_supplier(
+    header: HeaderMap,
+    Path(product): Path<String>,
+    Json(value): Json<Value>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::UPDATE_PRODUCT
+    ) {
+        log!("{user} 设置产品 {product} 的供应商失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let suppliers: Vec<String> = serde_json::from_value(value)?;
+    commit_or_rollback!(__set_product_supplier, &mut conn, &product, &suppliers)?;
+    log!("{user} 将产品 {product} 的供应商设置为 {:?}", suppliers);
+    Ok(Response::empty())
+}
+
+async fn query_product_supplier(header: HeaderMap, Path(product): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let data = __product_suppliers(&mut conn, &product)?;
+    Ok(Response::ok(json!(data)))
+}
+
+#[derive(Deserialize)]
+struct ReportParams {
+    /// 为空时查询全部库房
+    #[serde(default)]
+    storehouse: String,
+    /// 统计最近多少天的出库量
+    #[serde(default = "default_days")]
+    days: i64,
+}
+
+fn default_days() -> i64 {
+    30
+}
+
+/// 统计出库量的最大天数，十年
+const MAX_DAYS: i64 = 3650;
+
+/// 建议补货数量，有上限时补到上限，否则补到下限加上统计周期内的出库量
+fn suggest_amount(amount: i64, min: i64, max: Option<i64>, consumption: i64) -> i64 {
+    let target = max.unwrap_or(min + consumption);
+    (target - amount).max(0)
+}
+
+/// 待补货报表，列出低于下限的产品、最近的出库量、建议补货数量以及供应商
+async fn replenish_report(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let params: ReportParams = serde_json::from_value(value)?;
+    if !(1..=MAX_DAYS).contains(&params.days) {
+        return Err(Response::invalid_value(format!(
+            "days必须在1到{MAX_DAYS}之间"
+        )));
+    }
+    let since = (chrono::Local::now() - chrono::Duration::days(params.days))
+        .format("%Y-%m-%d")
+        .to_string();
+    let low = __low_stock(&mut conn, &params.storehouse)?;
+    let mut data = Vec::new();
+    for s in low {
+        // 发货流水中组合产品已经展开为组件，取消发货的流水会抵消之前的出库
+        let consumption: Option<i64> = conn.exec_first(
+            "SELECT CAST(-SUM(delta) AS SIGNED) FROM stock_movement
+            WHERE product = ? AND storehouse = ? AND reason = ? AND create_time >= ?",
+            (&s.product, &s.storehouse, StockReason::SHIPMENT, &since),
+        )?;
+        let consumption = consumption.unwrap_or(0);
+        let suggest = suggest_amount(
+            s.amount,
+            s.min_amount.unwrap_or_default(),
+            s.max_amount,
+            consumption,
+        );
+        let suppliers = __product_suppliers(&mut conn, &s.product)?;
+        data.push(json!({
+            "product": s.product,
+            "name": s.name,
+            "storehouse": s.storehouse,
+            "amount": s.amount,
+            "min_amount": s.min_amount,
+            "max_amount": s.max_amount,
+            "consumption": consumption,
+            "suggest": suggest,
+            "suppliers": suppliers
+        }));
+    }
+    Ok(Response::ok(json!(data)))
+}
+
+#[test]
+fn test_suggest_amount() {
+    assert_eq!(suggest_amount(3, 10, Some(50), 100), 47);
+    assert_eq!(suggest_amount(3, 10, None, 20), 27);
+    assert_eq!(suggest_amount(-5, 10, None, 0), 15);
+    assert_eq!(suggest_amount(60, 10, Some(50), 0), 0);
+}
@@ -0,0 +1,462 @@
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{get, post},
+    Json, Router,
+};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::lot::{put_lots, restore_lots, take_lots, LotPick};
+use crate::{
+    bearer, commit_or_rollback,
+    database::get_db,
+    libs::{cache::PRODUCT_CACHE, gen_id, TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    perm::action::StorehouseGroup,
+    verify_perms, Response, ResponseResult, ALLOW_NEGATIVE_STOCK,
+};
+
+pub fn stock_router() -> Router {
+    Router::new()
+        .route("/store/stock/rule", get(get_stock_rule))
+        .route("/store/stock/rule/set", post(set_stock_rule))
+        .route("/store/stock/receipt", post(purchase_receipt))
+        .route(
+            "/store/stock/movement/product/:id",
+            post(query_product_movement),
+        )
+        .route(
+            "/store/stock/movement/storehouse/:name",
+            post(query_storehouse_movement),
+        )
+}
+
+/// 库存变动的原因
+pub struct StockReason;
+impl StockReason {
+    /// 手动调整
+    pub const MANUAL: &'static str = "manual";
+    /// 订单发货或者取消发货
+    pub const SHIPMENT: &'static str = "shipment";
+    /// 采购入库
+    pub const RECEIPT: &'static str = "receipt";
+    /// 库房调拨
+    pub const TRANSFER: &'static str = "transfer";
+    /// 盘点
+    pub const STOCKTAKE: &'static str = "stocktake";
+    /// 启用台账前已有的库存
+    pub const OPENING: &'static str = "opening";
+}
+
+/// 库存变动的来源，source为对应单据的id
+pub struct Movement<'a> {
+    pub reason: &'a str,
+    pub source: &'a str,
+    pub operator: &'a str,
+}
+
+fn current_stock(conn: &mut PooledConn, product: &str, storehouse: &str) -> mysql::Result<i64> {
+    let amount: Option<i64> = conn.exec_first(
+        "SELECT amount FROM product_store WHERE product = ? AND storehouse = ? LIMIT 1 FOR UPDATE",
+        (product, storehouse),
+    )?;
+    Ok(amount.unwrap_or(0))
+}
+
+/// 写入一条库存流水并更新结余，product_store中的amount始终等于流水之和
+/// 组合产品和有规格的产品没有自己的库存
+fn check_stock_product(conn: &mut PooledConn, product: &str) -> Result<(), Response> {
+    let bundle: Option<i32> = conn.exec_first(
+        "SELECT 1 FROM product WHERE id = ? AND bundle = 1 LIMIT 1",
+        (product,),
+    )?;
+    if bundle.is_some() {
+        return Err(Response::dissatisfy(format!(
+            "组合产品 {product} 没有自己的库存，请调整组件的库存"
+        )));
+    }
+    let parent: Option<i32> = conn.exec_first(
+        "SELECT 1 FROM product WHERE parent = ? LIMIT 1",
+        (product,),
+    )?;
+    if parent.is_some() {
+        return Err(Response::dissatisfy(format!(
+            "产品 {product} 有多个规格，请调整具体规格的库存"
+        )));
+    }
+    Ok(())
+}
+
+fn __record_movement(
+    conn: &mut PooledConn,
+    product: &str,
+    storehouse: &str,
+    delta: i64,
+    balance: i64,
+    movement: &Movement,
+) -> Result<(), Response> {
+    check_stock_product(conn, product)?;
+    let time = TIME::now()?;
+    conn.exec_drop(
+        "INSERT INTO stock_movement (id, product, storehouse, delta, balance, reason, source, operator, create_time)
+        VALUES (:id, :product, :storehouse, :delta, :balance, :reason, :source, :operator, :time)",
+        params! {
+            "id" => gen_id(&time, "stock"),
+            "product" => product,
+            "storehouse" => storehouse,
+            "delta" => delta,
+            "balance" => balance,
+            "reason" => movement.reason,
+            "source" => movement.source,
+            "operator" => movement.operator,
+            "time" => time.format(TimeFormat::YYYYMMDD_HHMMSS)
+        },
+    )?;
+    conn.exec_drop(
+        "INSERT INTO product_store (product, storehouse, amount) VALUES (:product, :storehouse, :delta)
+        ON DUPLICATE KEY UPDATE amount = amount + :delta",
+        params! { "product" => product, "storehouse" => storehouse, "delta" => delta },
+    )?;
+    Ok(())
+}
+
+/// 修改产品在库房中的库存，`delta`为负数时出库。
+/// 库存不足时返回错误，除非设置了允许负库存，需要在事务中调用
+pub fn change_stock(
+    conn: &mut PooledConn,
+    product: &str,
+    storehouse: &str,
+    delta: i64,
+    movement: &Movement,
+) -> Result<(), Response> {
+    if delta == 0 {
+        return Ok(());
+    }
+    let amount = current_stock(conn, product, storehouse)?;
+    if amount + delta < 0 && !unsafe { ALLOW_NEGATIVE_STOCK } {
+        let name: Option<String> =
+            conn.exec_first("SELECT name FROM product WHERE id = ? LIMIT 1", (product,))?;
+        return Err(Response::dissatisfy(format!(
+            "产品 {} 在 {storehouse} 的库存不足，当前库存为{amount}，需要{}",
+            name.unwrap_or_else(|| product.to_owned()),
+            -delta
+        )));
+    }
+    __record_movement(conn, product, storehouse, delta, amount + delta, movement)
+}
+
+/// 将库存直接设置为`amount`，按照差值记录流水，用于手动调整和盘点
+pub fn set_stock(
+    conn: &mut PooledConn,
+    product: &str,
+    storehouse: &str,
+    amount: i64,
+    movement: &Movement,
+) -> Result<(), Response> {
+    let current = current_stock(conn, product, storehouse)?;
+    if current == amount {
+        check_stock_product(conn, product)?;
+        // 库存为0时也需要保留产品与库房的关系
+        conn.exec_drop(
+            "INSERT IGNORE INTO product_store (product, storehouse, amount) VALUES (?, ?, ?)",
+            (product, storehouse, amount),
+        )?;
+        return Ok(());
+    }
+    __record_movement(
+        conn,
+        product,
+        storehouse,
+        amount - current,
+        amount,
+        movement,
+    )
+}
+
+/// 订单发货时从库房中扣除订单中的产品，`restore`为true时表示取消发货，按照发货的流水将产品放回库房。
+/// 需要追踪的产品按照`picks`扣除批次或者序列号并记录在订单上，组合产品扣除的是组件的库存
+pub fn ship_order_stock(
+    conn: &mut PooledConn,
+    order: &str,
+    storehouse: &str,
+    restore: bool,
+    operator: &str,
+    picks: &[LotPick],
+) -> Result<(), Response> {
+    let movement = Movement {
+        reason: StockReason::SHIPMENT,
+        source: order,
+        operator,
+    };
+    if restore {
+        // 按照发货时记录的流水放回，组合产品的组件之后被修改也不影响
+        let recorded: Option<i32> = conn.exec_first(
+            "SELECT 1 FROM stock_movement WHERE source = ? AND reason = ? LIMIT 1",
+            (order, StockReason::SHIPMENT),
+        )?;
+        if recorded.is_some() {
+            let shipped: Vec<(String, String, i64)> = conn.exec(
+                "SELECT product, storehouse, CAST(-SUM(delta) AS SIGNED) AS amount
+                FROM stock_movement WHERE source = ? AND reason = ?
+                GROUP BY product, storehouse HAVING amount != 0",
+                (order, StockReason::SHIPMENT),
+            )?;
+            for (product, storehouse, amount) in shipped {
+                change_stock(conn, &product, &storehouse, amount, &movement)?;
+            }
+            restore_lots(conn, order)?;
+            return Ok(());
+        }
+        // 没有库存流水之前发货的订单只能按照订单中的产品放回
+    }
+    // 组合产品按照组件扣除库存
+    let products: Vec<(String, i64)> = conn.exec(
+        "SELECT IFNULL(b.product, op.id) AS id, CAST(SUM(op.amount * IFNULL(b.amount, 1)) AS SIGNED)
+        FROM order_product op
+        LEFT JOIN product_bundle b ON b.bundle = op.id
+        WHERE op.order_id = ? GROUP BY IFNULL(b.product, op.id)",
+        (order,),
+    )?;
+    for (product, amount) in products {
+        change_stock(
+            conn,
+            &product,
+            storehouse,
+            op::ternary!(restore => amount, -amount),
+            &movement,
+        )?;
+        if !restore {
+            take_lots(conn, order, &product, storehouse, amount, picks)?;
+        }
+    }
+    if restore {
+        restore_lots(conn, order)?;
+    }
+    Ok(())
+}
+
+async fn get_stock_rule() -> ResponseResult {
+    Ok(Response::ok(json!({
+        "allow_negative": unsafe { ALLOW_NEGATIVE_STOCK }
+    })))
+}
+
+#[derive(Deserialize)]
+struct StockRule {
+    allow_negative: bool,
+}
+
+async fn set_stock_rule(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let rule: StockRule = serde_json::from_value(value)?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::STOCK_RULE
+    ) {
+        log!("{user} 设置库存规则失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    crate::set_allow_negative_stock(rule.allow_negative)?;
+    log!(
+        "{user} 已将库存规则设置为{}负库存",
+        op::ternary!(rule.allow_negative => "允许", "不允许")
+    );
+    Ok(Response::empty())
+}
+
+/// 启动时为没有流水的库存补上期初记录，保证库存结余等于流水之和
+pub fn sync_stock_ledger(conn: &mut PooledConn) -> Result<(), Response> {
+    let diff: Vec<(String, String, i64, i64)> = conn.query(
+        "SELECT ps.product, ps.storehouse, ps.amount, ps.amount - IFNULL(SUM(sm.delta), 0) AS diff
+        FROM product_store ps
+        LEFT JOIN stock_movement sm ON sm.product = ps.product AND sm.storehouse = ps.storehouse
+        GROUP BY ps.product, ps.storehouse, ps.amount HAVING diff != 0",
+    )?;
+    let time = TIME::now()?;
+    let create_time = time.format(TimeFormat::YYYYMMDD_HHMMSS);
+    for (i, (product, storehouse, amount, delta)) in diff.into_iter().enumerate() {
+        conn.exec_drop(
+            "INSERT INTO stock_movement (id, product, storehouse, delta, balance, reason, source, operator, create_time)
+            VALUES (?, ?, ?, ?, ?, ?, '', '', ?)",
+            (
+                gen_id(&time, &format!("stock{i}")),
+                &product,
+                &storehouse,
+                delta,
+                amount,
+                StockReason::OPENING,
+                &create_time,
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ReceiptParams {
+    product: String,
+    storehouse: String,
+    amount: i64,
+    /// 采购单号
+    #[serde(default)]
+    source: String,
+    /// 需要追踪的产品必须填写批次或者序列号
+    #[serde(default)]
+    lots: Vec<LotPick>,
+}
+
+fn __purchase_receipt(
+    conn: &mut PooledConn,
+    params: &ReceiptParams,
+    uid: &str,
+) -> Result<(), Response> {
+    let movement = Movement {
+        reason: StockReason::RECEIPT,
+        source: &params.source,
+        operator: uid,
+    };
+    change_stock(
+        conn,
+        &params.product,
+        &params.storehouse,
+        params.amount,
+        &movement,
+    )?;
+    put_lots(
+        conn,
+        &params.product,
+        &params.storehouse,
+        params.amount,
+        &params.lots,
+    )
+}
+
+/// 采购入库
+async fn purchase_receipt(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let params: ReceiptParams = serde_json::from_value(value)?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::ADJUSTING_PRODUCT_INVENTORY
+    ) {
+        log!("{user} 采购入库失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    if params.amount <= 0 {
+        return Err(Response::invalid_value("入库数量必须大于0"));
+    }
+    let _: i32 = op::some!(conn.exec_first(
+        "SELECT 1 FROM drop_down_box WHERE name = 'storehouse' AND value = ? LIMIT 1",
+        (&params.storehouse,))?;
+        ret Err(Response::not_exist(format!("库房 {} 不存在", params.storehouse))));
+    let _: i32 = op::some!(conn.exec_first("SELECT 1 FROM product WHERE id = ? LIMIT 1", (&params.product,))?;
+        ret Err(Response::not_exist("产品不存在")));
+    commit_or_rollback!(__purchase_receipt, &mut conn, &params, &uid)?;
+    PRODUCT_CACHE.clear();
+    log!(
+        "{user} 将 {} 个产品 {} 入库到 {}",
+        params.amount,
+        params.product,
+        params.storehouse
+    );
+    Ok(Response::empty())
+}
+
+#[derive(Deserialize)]
+struct MovementFilter {
+    /// YYYY-MM-DD，为空时不限制
+    #[serde(default)]
+    start: String,
+    /// YYYY-MM-DD，包括当天，为空时不限制
+    #[serde(default)]
+    end: String,
+    /// 为空时查询全部原因
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Serialize, FromRow)]
+struct MovementData {
+    id: String,
+    product: String,
+    product_name: Option<String>,
+    storehouse: String,
+    delta: i64,
+    balance: i64,
+    reason: String,
+    source: String,
+    operator: String,
+    operator_name: Option<String>,
+    create_time: String,
+}
+
+fn __query_movement(
+    conn: &mut PooledConn,
+    column: &str,
+    value: &str,
+    filter: &MovementFilter,
+) -> Result<Vec<MovementData>, Response> {
+    let data = conn.exec(
+        format!(
+            "SELECT sm.*, p.name AS product_name, u.name AS operator_name
+            FROM stock_movement sm
+            LEFT JOIN product p ON p.id = sm.product
+            LEFT JOIN user u ON u.id = sm.operator
+            WHERE sm.{column} = :value
+            AND (:start = '' OR LEFT(sm.create_time, 10) >= :start)
+            AND (:end = '' OR LEFT(sm.create_time, 10) <= :end)
+            AND (:reason = '' OR sm.reason = :reason)
+            ORDER BY sm.create_time DESC"
+        ),
+        params! {
+            "value" => value,
+            "start" => &filter.start,
+            "end" => &filter.end,
+            "reason" => &filter.reason
+        },
+    )?;
+    Ok(data)
+}
+
+async fn query_product_movement(
+    header: HeaderMap,
+    Path(id): Path<String>,
+    Json(value): Json<Value>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let filter: MovementFilter = serde_json::from_value(value)?;
+    let data = __query_movement(&mut conn, "product", &id, &filter)?;
+    Ok(Response::ok(json!(data)))
+}
+
+async fn query_storehouse_movement(
+    header: HeaderMap,
+    Path(name): Path<String>,
+    Json(value): Json<Value>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let filter: MovementFilter = serde_json::from_value(value)?;
+    let data = __query_movement(&mut conn, "storehouse", &name, &filter)?;
+    Ok(Response::ok(json!(data)))
+}
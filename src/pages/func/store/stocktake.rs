@@ -0,0 +1,374 @@
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::get_db,
+    libs::{cache::PRODUCT_CACHE, dser::deserialize_storehouse, gen_id, TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    perm::action::StorehouseGroup,
+    verify_perms, Response, ResponseResult,
+};
+
+use super::{
+    lot::{take_lots, LotPick, PickParams},
+    stock::{change_stock, Movement, StockReason},
+};
+
+pub fn stocktake_router() -> Router {
+    Router::new()
+        .route("/store/stocktake/add", post(add_stocktake))
+        .route("/store/stocktake/count/:id", post(count_stocktake))
+        .route("/store/stocktake/approve/:id", post(approve_stocktake))
+        .route("/store/stocktake/delete/:id", delete(delete_stocktake))
+        .route("/store/stocktake/query", post(query_stocktake))
+        .route("/store/stocktake/variance/:id", get(query_variance))
+}
+
+/// 盘点单的状态
+struct StocktakeStatus;
+impl StocktakeStatus {
+    const COUNTING: i32 = 0;
+    const APPROVED: i32 = 1;
+}
+
+#[derive(Deserialize)]
+struct StocktakeParams {
+    #[serde(deserialize_with = "deserialize_storehouse")]
+    storehouse: String,
+    #[serde(default)]
+    remark: String,
+}
+
+/// 创建盘点单并保存库房当前库存的快照，同一个库房同时只能有一个盘点中的盘点单
+fn __add_stocktake(
+    conn: &mut PooledConn,
+    params: &StocktakeParams,
+    uid: &str,
+) -> Result<String, Response> {
+    let counting: Option<String> = conn.exec_first(
+        "SELECT id FROM stocktake WHERE storehouse = ? AND status = ? LIMIT 1 FOR UPDATE",
+        (&params.storehouse, StocktakeStatus::COUNTING),
+    )?;
+    if counting.is_some() {
+        return Err(Response::dissatisfy(format!(
+            "库房 {} 已经有正在进行的盘点",
+            params.storehouse
+        )));
+    }
+    let time = TIME::now()?;
+    let id = gen_id(&time, "stocktake");
+    conn.exec_drop(
+        "INSERT INTO stocktake (id, storehouse, status, applicant, remark, create_time)
+        VALUES (:id, :storehouse, :status, :applicant, :remark, :time)",
+        params! {
+            "id" => &id,
+            "storehouse" => &params.storehouse,
+            "status" => StocktakeStatus::COUNTING,
+            "applicant" => uid,
+            "remark" => &params.remark,
+            "time" => time.format(TimeFormat::YYYYMMDD_HHMMSS)
+        },
+    )?;
+    conn.exec_drop(
+        "INSERT INTO stocktake_item (stocktake, product, expected, counted)
+        SELECT ?, product, amount, NULL FROM product_store WHERE storehouse = ?",
+        (&id, &params.storehouse),
+    )?;
+    Ok(id)
+}
+
+async fn add_stocktake(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::STOCKTAKE
+    ) {
+        log!("{user} 创建盘点单失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let params: StocktakeParams = serde_json::from_value(value)?;
+    let id = commit_or_rollback!(__add_stocktake, &mut conn, &params, &uid)?;
+    log!("{user} 创建了库房 {} 的盘点单 {id}", params.storehouse);
+    Ok(Response::ok(json!(id)))
+}
+
+/// 查询盘点单的状态并加锁，不存在时返回错误
+fn __stocktake_status(conn: &mut PooledConn, id: &str) -> Result<i32, Response> {
+    let status: Option<i32> = conn.exec_first(
+        "SELECT status FROM stocktake WHERE id = ? LIMIT 1 FOR UPDATE",
+        (id,),
+    )?;
+    status.ok_or_else(|| Response::not_exist("盘点单不存在"))
+}
+
+#[derive(Deserialize)]
+struct CountParams {
+    /// 产品id和条形码二选一
+    #[serde(default)]
+    product: String,
+    #[serde(default)]
+    barcode: String,
+    counted: i64,
+}
+
+/// 录入实盘数量，重复录入时覆盖之前的数量。快照中没有的产品按照账面库存为0处理
+fn __count_stocktake(
+    conn: &mut PooledConn,
+    id: &str,
+    counts: &[CountParams],
+) -> Result<(), Response> {
+    if __stocktake_status(conn, id)? != StocktakeStatus::COUNTING {
+        return Err(Response::dissatisfy("盘点单已审核，不能继续录入"));
+    }
+    for c in counts {
+        if c.counted < 0 {
+            return Err(Response::invalid_value("实盘数量不能小于0"));
+        }
+        if c.product.is_empty() && c.barcode.is_empty() {
+            return Err(Response::invalid_value("产品id和条形码不能同时为空"));
+        }
+        let product: Option<String> = if c.product.is_empty() {
+            conn.exec_first(
+                "SELECT id FROM product WHERE barcode = ? LIMIT 1",
+                (&c.barcode,),
+            )?
+        } else {
+            conn.exec_first("SELECT id FROM product WHERE id = ? LIMIT 1", (&c.product,))?
+        };
+        let product = op::some!(product; ret Err(Response::not_exist(format!(
+            "产品 {}{} 不存在",
+            c.product, c.barcode
+        ))));
+        conn.exec_drop(
+            "INSERT INTO stocktake_item (stocktake, product, expected, counted) VALUES (?, ?, 0, ?)
+            ON DUPLICATE KEY UPDATE counted = VALUES(counted)",
+            (id, &product, c.counted),
+        )?;
+    }
+    Ok(())
+}
+
+async fn count_stocktake(
+    header: HeaderMap,
+    Path(id): Path<String>,
+    Json(value): Json<Value>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::STOCKTAKE
+    ) {
+        log!("{user} 录入盘点数量失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let counts: Vec<CountParams> = serde_json::from_value(value)?;
+    commit_or_rollback!(__count_stocktake, &mut conn, &id, &counts)?;
+    log!("{user} 为盘点单 {id} 录入了{}个产品的数量", counts.len());
+    Ok(Response::empty())
+}
+
+/// 审核盘点单，按照实盘数量与快照的差异记录库存流水，
+/// 盘点期间发生的出入库不会被覆盖，没有盘点的产品保持不变
+fn __approve_stocktake(
+    conn: &mut PooledConn,
+    id: &str,
+    uid: &str,
+    picks: &[LotPick],
+) -> Result<(), Response> {
+    if __stocktake_status(conn, id)? != StocktakeStatus::COUNTING {
+        return Err(Response::dissatisfy("盘点单已审核"));
+    }
+    let storehouse: String = op::some!(conn.exec_first(
+        "SELECT storehouse FROM stocktake WHERE id = ? LIMIT 1", (id,))?;
+        ret Err(Response::not_exist("盘点单不存在")));
+    let variance: Vec<(String, i64)> = conn.exec(
+        "SELECT product, counted - expected FROM stocktake_item
+        WHERE stocktake = ? AND counted IS NOT NULL AND counted != expected",
+        (id,),
+    )?;
+    let movement = Movement {
+        reason: StockReason::STOCKTAKE,
+        source: id,
+        operator: uid,
+    };
+    for (product, delta) in variance {
+        change_stock(conn, &product, &storehouse, delta, &movement)?;
+        // 盘亏的批次没有选择时按照先进先出扣除，序列号必须选择，盘盈的部分没有批次信息
+        take_lots(conn, id, &product, &storehouse, -delta, picks)?;
+    }
+    conn.exec_drop(
+        "UPDATE stocktake SET status = ?, approver = ?, approve_time = ? WHERE id = ? LIMIT 1",
+        (
+            StocktakeStatus::APPROVED,
+            uid,
+            TIME::now()?.format(TimeFormat::YYYYMMDD_HHMMSS),
+            id,
+        ),
+    )?;
+    Ok(())
+}
+
+async fn approve_stocktake(
+    header: HeaderMap,
+    Path(id): Path<String>,
+    body: Option<Json<PickParams>>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::ADJUSTING_PRODUCT_INVENTORY
+    ) {
+        log!("{user} 审核盘点单失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let Json(params) = body.unwrap_or_default();
+    commit_or_rollback!(__approve_stocktake, &mut conn, &id, &uid, &params.lots)?;
+    PRODUCT_CACHE.clear();
+    log!("{user} 审核了盘点单 {id}");
+    Ok(Response::empty())
+}
+
+fn __delete_stocktake(conn: &mut PooledConn, id: &str) -> Result<(), Response> {
+    if __stocktake_status(conn, id)? != StocktakeStatus::COUNTING {
+        return Err(Response::dissatisfy("已审核的盘点单不能删除"));
+    }
+    conn.exec_drop("DELETE FROM stocktake WHERE id = ? LIMIT 1", (id,))?;
+    conn.exec_drop("DELETE FROM stocktake_item WHERE stocktake = ?", (id,))?;
+    Ok(())
+}
+
+async fn delete_stocktake(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !verify_perms!(
+        &user.role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::STOCKTAKE
+    ) {
+        log!("{user} 删除盘点单失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    commit_or_rollback!(__delete_stocktake, &mut conn, &id)?;
+    log!("{user} 删除了盘点单 {id}");
+    Ok(Response::empty())
+}
+
+#[derive(Serialize, FromRow)]
+struct StocktakeData {
+    id: String,
+    storehouse: String,
+    status: i32,
+    applicant: String,
+    applicant_name: Option<String>,
+    remark: String,
+    create_time: String,
+    approver: Option<String>,
+    approve_time: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct QueryStocktakeParams {
+    /// 为空时查询全部状态
+    status: Option<i32>,
+    /// 为空时查询全部库房
+    #[serde(default)]
+    storehouse: String,
+}
+
+async fn query_stocktake(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let params: QueryStocktakeParams = serde_json::from_value(value)?;
+    let data: Vec<StocktakeData> = conn.exec(
+        "SELECT s.*, u.name AS applicant_name FROM stocktake s
+        LEFT JOIN user u ON u.id = s.applicant
+        WHERE (:status IS NULL OR s.status = :status)
+        AND (:storehouse = '' OR s.storehouse = :storehouse)
+        ORDER BY s.create_time DESC",
+        params! {
+            "status" => params.status,
+            "storehouse" => &params.storehouse
+        },
+    )?;
+    Ok(Response::ok(json!(data)))
+}
+
+#[derive(Serialize, FromRow)]
+struct VarianceItem {
+    product: String,
+    name: Option<String>,
+    barcode: Option<String>,
+    expected: i64,
+    counted: Option<i64>,
+    /// 实盘减去账面，没有盘点时为NULL
+    variance: Option<i64>,
+}
+
+/// 盘点差异报表
+async fn query_variance(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let data: StocktakeData = op::some!(conn.exec_first(
+        "SELECT s.*, u.name AS applicant_name FROM stocktake s
+        LEFT JOIN user u ON u.id = s.applicant WHERE s.id = ? LIMIT 1",
+        (&id,))?;
+        ret Err(Response::not_exist("盘点单不存在")));
+    let items: Vec<VarianceItem> = conn.exec(
+        "SELECT si.product, p.name, p.barcode, si.expected, si.counted,
+        si.counted - si.expected AS variance
+        FROM stocktake_item si LEFT JOIN product p ON p.id = si.product
+        WHERE si.stocktake = ? ORDER BY p.name",
+        (&id,),
+    )?;
+    let uncounted = items.iter().filter(|i| i.counted.is_none()).count();
+    let surplus: i64 = items
+        .iter()
+        .filter_map(|i| i.variance)
+        .filter(|v| *v > 0)
+        .sum();
+    let shortage: i64 = items
+        .iter()
+        .filter_map(|i| i.variance)
+        .filter(|v| *v < 0)
+        .sum();
+    Ok(Response::ok(json!({
+        "data": data,
+        "items": items,
+        "uncounted": uncounted,
+        "surplus": surplus,
+        "shortage": -shortage
+    })))
+}
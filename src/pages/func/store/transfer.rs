@@ -0,0 +1,386 @@
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use mysql::{params, prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer, commit_or_rollback,
+    database::get_db,
+    libs::{cache::PRODUCT_CACHE, dser::deserialize_storehouse, gen_id, TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    perm::action::StorehouseGroup,
+    verify_perms, Response, ResponseResult,
+};
+
+use super::{
+    lot::{receive_lots, take_lots, LotPick, PickParams},
+    stock::{change_stock, Movement, StockReason},
+};
+
+pub fn transfer_router() -> Router {
+    Router::new()
+        .route("/store/transfer/add", post(add_transfer))
+        .route("/store/transfer/update", post(update_transfer))
+        .route("/store/transfer/delete/:id", delete(delete_transfer))
+        .route("/store/transfer/ship/:id", post(ship_transfer))
+        .route("/store/transfer/receive/:id", post(receive_transfer))
+        .route("/store/transfer/query", post(query_transfer))
+        .route("/store/transfer/data/:id", get(query_transfer_data))
+}
+
+/// 调拨单的状态
+struct TransferStatus;
+impl TransferStatus {
+    const DRAFT: i32 = 0;
+    const SHIPPED: i32 = 1;
+    const RECEIVED: i32 = 2;
+}
+
+async fn can_transfer(role: &str) -> bool {
+    verify_perms!(
+        role,
+        StorehouseGroup::NAME,
+        StorehouseGroup::ADJUSTING_PRODUCT_INVENTORY
+    ) || verify_perms!(role, StorehouseGroup::NAME, StorehouseGroup::TRANSFER_STOCK)
+}
+
+#[derive(Deserialize, Serialize, FromRow)]
+struct TransferProduct {
+    product: String,
+    #[serde(skip_deserializing)]
+    name: Option<String>,
+    amount: i64,
+}
+
+#[derive(Deserialize)]
+struct TransferParams {
+    #[serde(default)]
+    id: String,
+    #[serde(deserialize_with = "deserialize_storehouse")]
+    source: String,
+    #[serde(deserialize_with = "deserialize_storehouse")]
+    target: String,
+    #[serde(default)]
+    remark: String,
+    products: Vec<TransferProduct>,
+}
+
+impl TransferParams {
+    fn check(&self) -> Result<(), Response> {
+        if self.source.eq(&self.target) {
+            return Err(Response::invalid_value("调出库房和调入库房不能相同"));
+        }
+        if self.products.is_empty() {
+            return Err(Response::invalid_value("调拨单中没有产品"));
+        }
+        if self.products.iter().any(|p| p.amount <= 0) {
+            return Err(Response::invalid_value("调拨数量必须大于0"));
+        }
+        Ok(())
+    }
+}
+
+fn __insert_products(
+    conn: &mut PooledConn,
+    id: &str,
+    products: &[TransferProduct],
+) -> Result<(), Response> {
+    conn.exec_drop(
+        "DELETE FROM stock_transfer_product WHERE transfer = ?",
+        (id,),
+    )?;
+    for p in products {
+        let _: i32 = op::some!(conn.exec_first("SELECT 1 FROM product WHERE id = ? LIMIT 1", (&p.product,))?;
+            ret Err(Response::not_exist(format!("产品 {} 不存在", p.product))));
+        conn.exec_drop(
+            "INSERT INTO stock_transfer_product (transfer, product, amount) VALUES (?, ?, ?)
+            ON DUPLICATE KEY UPDATE amount = amount + VALUES(amount)",
+            (id, &p.product, p.amount),
+        )?;
+    }
+    Ok(())
+}
+
+fn __add_transfer(
+    conn: &mut PooledConn,
+    params: &mut TransferParams,
+    uid: &str,
+) -> Result<(), Response> {
+    let time = TIME::now()?;
+    params.id = gen_id(&time, "transfer");
+    conn.exec_drop(
+        "INSERT INTO stock_transfer (id, source, target, status, applicant, remark, create_time)
+        VALUES (:id, :source, :target, :status, :applicant, :remark, :time)",
+        params! {
+            "id" => &params.id,
+            "source" => &params.source,
+            "target" => &params.target,
+            "status" => TransferStatus::DRAFT,
+            "applicant" => uid,
+            "remark" => &params.remark,
+            "time" => time.format(TimeFormat::YYYYMMDD_HHMMSS)
+        },
+    )?;
+    __insert_products(conn, &params.id, &params.products)
+}
+
+async fn add_transfer(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !can_transfer(&user.role).await {
+        log!("{user} 创建调拨单失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let mut params: TransferParams = serde_json::from_value(value)?;
+    params.check()?;
+    commit_or_rollback!(__add_transfer, &mut conn, &mut params, &uid)?;
+    log!(
+        "{user} 创建了调拨单 {}，从 {} 调拨到 {}",
+        params.id,
+        params.source,
+        params.target
+    );
+    Ok(Response::ok(json!(params.id)))
+}
+
+/// 查询调拨单的状态并加锁，不存在时返回错误
+fn __transfer_status(conn: &mut PooledConn, id: &str) -> Result<i32, Response> {
+    let status: Option<i32> = conn.exec_first(
+        "SELECT status FROM stock_transfer WHERE id = ? LIMIT 1 FOR UPDATE",
+        (id,),
+    )?;
+    status.ok_or_else(|| Response::not_exist("调拨单不存在"))
+}
+
+fn __update_transfer(conn: &mut PooledConn, params: &TransferParams) -> Result<(), Response> {
+    if __transfer_status(conn, &params.id)? != TransferStatus::DRAFT {
+        return Err(Response::dissatisfy("只能修改草稿状态的调拨单"));
+    }
+    conn.exec_drop(
+        "UPDATE stock_transfer SET source = ?, target = ?, remark = ? WHERE id = ? LIMIT 1",
+        (&params.source, &params.target, &params.remark, &params.id),
+    )?;
+    __insert_products(conn, &params.id, &params.products)
+}
+
+async fn update_transfer(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !can_transfer(&user.role).await {
+        log!("{user} 修改调拨单失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let params: TransferParams = serde_json::from_value(value)?;
+    params.check()?;
+    commit_or_rollback!(__update_transfer, &mut conn, &params)?;
+    log!("{user} 修改了调拨单 {}", params.id);
+    Ok(Response::empty())
+}
+
+fn __delete_transfer(conn: &mut PooledConn, id: &str) -> Result<(), Response> {
+    if __transfer_status(conn, id)? != TransferStatus::DRAFT {
+        return Err(Response::dissatisfy("只能删除草稿状态的调拨单"));
+    }
+    conn.exec_drop("DELETE FROM stock_transfer WHERE id = ? LIMIT 1", (id,))?;
+    conn.exec_drop(
+        "DELETE FROM stock_transfer_product WHERE transfer = ?",
+        (id,),
+    )?;
+    Ok(())
+}
+
+async fn delete_transfer(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !can_transfer(&user.role).await {
+        log!("{user} 删除调拨单失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    commit_or_rollback!(__delete_transfer, &mut conn, &id)?;
+    log!("{user} 删除了调拨单 {id}");
+    Ok(Response::empty())
+}
+
+/// 发出或者收货，调拨单中所有产品的库存变动在同一个事务中完成。
+/// 发出时从调出库房扣除，收货时加入调入库房，运输途中的库存不属于任何库房。
+/// 需要追踪的产品发出时按照先进先出扣除批次或者序列号，收货时放入调入库房
+fn __move_transfer(
+    conn: &mut PooledConn,
+    id: &str,
+    uid: &str,
+    receive: bool,
+    picks: &[LotPick],
+) -> Result<(), Response> {
+    let status = __transfer_status(conn, id)?;
+    let (expect, next) = op::ternary!(receive =>
+        (TransferStatus::SHIPPED, TransferStatus::RECEIVED),
+        (TransferStatus::DRAFT, TransferStatus::SHIPPED)
+    );
+    if status != expect {
+        return Err(Response::dissatisfy(op::ternary!(receive =>
+            "只能对已发出的调拨单收货",
+            "只能发出草稿状态的调拨单"
+        )));
+    }
+    let storehouse: String = op::some!(conn.exec_first(
+        format!(
+            "SELECT {} FROM stock_transfer WHERE id = ? LIMIT 1",
+            op::ternary!(receive => "target", "source")
+        ),
+        (id,))?;
+        ret Err(Response::not_exist("调拨单不存在")));
+    let products: Vec<(String, i64)> = conn.exec(
+        "SELECT product, amount FROM stock_transfer_product WHERE transfer = ?",
+        (id,),
+    )?;
+    let movement = Movement {
+        reason: StockReason::TRANSFER,
+        source: id,
+        operator: uid,
+    };
+    for (product, amount) in products {
+        change_stock(
+            conn,
+            &product,
+            &storehouse,
+            op::ternary!(receive => amount, -amount),
+            &movement,
+        )?;
+        if !receive {
+            take_lots(conn, id, &product, &storehouse, amount, picks)?;
+        }
+    }
+    if receive {
+        receive_lots(conn, id, &storehouse)?;
+    }
+    let time = TIME::now()?.format(TimeFormat::YYYYMMDD_HHMMSS);
+    conn.exec_drop(
+        format!(
+            "UPDATE stock_transfer SET status = ?, {} = ?, {} = ? WHERE id = ? LIMIT 1",
+            op::ternary!(receive => "receiver", "shipper"),
+            op::ternary!(receive => "receive_time", "ship_time")
+        ),
+        (next, uid, time, id),
+    )?;
+    Ok(())
+}
+
+async fn ship_transfer(
+    header: HeaderMap,
+    Path(id): Path<String>,
+    body: Option<Json<PickParams>>,
+) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !can_transfer(&user.role).await {
+        log!("{user} 发出调拨单失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    let Json(params) = body.unwrap_or_default();
+    commit_or_rollback!(__move_transfer, &mut conn, &id, &uid, false, &params.lots)?;
+    PRODUCT_CACHE.clear();
+    log!("{user} 发出了调拨单 {id}");
+    Ok(Response::empty())
+}
+
+async fn receive_transfer(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    if !can_transfer(&user.role).await {
+        log!("{user} 调拨单收货失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    commit_or_rollback!(__move_transfer, &mut conn, &id, &uid, true, &[])?;
+    PRODUCT_CACHE.clear();
+    log!("{user} 确认调拨单 {id} 已收货");
+    Ok(Response::empty())
+}
+
+#[derive(Serialize, FromRow)]
+struct TransferData {
+    id: String,
+    source: String,
+    target: String,
+    status: i32,
+    applicant: String,
+    applicant_name: Option<String>,
+    remark: String,
+    create_time: String,
+    shipper: Option<String>,
+    ship_time: Option<String>,
+    receiver: Option<String>,
+    receive_time: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct QueryTransferParams {
+    /// 为空时查询全部状态
+    status: Option<i32>,
+    /// 调出或者调入的库房，为空时查询全部
+    #[serde(default)]
+    storehouse: String,
+}
+
+async fn query_transfer(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let params: QueryTransferParams = serde_json::from_value(value)?;
+    let data: Vec<TransferData> = conn.exec(
+        "SELECT st.*, u.name AS applicant_name FROM stock_transfer st
+        LEFT JOIN user u ON u.id = st.applicant
+        WHERE (:status IS NULL OR st.status = :status)
+        AND (:storehouse = '' OR st.source = :storehouse OR st.target = :storehouse)
+        ORDER BY st.create_time DESC",
+        params! {
+            "status" => params.status,
+            "storehouse" => &params.storehouse
+        },
+    )?;
+    Ok(Response::ok(json!(data)))
+}
+
+async fn query_transfer_data(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    parse_jwt_macro!(&bearer, &mut conn => true);
+    let data: TransferData = op::some!(conn.exec_first(
+        "SELECT st.*, u.name AS applicant_name FROM stock_transfer st
+        LEFT JOIN user u ON u.id = st.applicant WHERE st.id = ? LIMIT 1",
+        (&id,))?;
+        ret Err(Response::not_exist("调拨单不存在")));
+    let products: Vec<TransferProduct> = conn.exec(
+        "SELECT tp.product, p.name, tp.amount FROM stock_transfer_product tp
+        LEFT JOIN product p ON p.id = tp.product WHERE tp.transfer = ?",
+        (&id,),
+    )?;
+    Ok(Response::ok(json!({
+        "data": data,
+        "products": products
+    })))
+}
@@ -2,6 +2,7 @@ mod user;
 use axum::Router;
 
 mod account;
+mod notify;
 pub use account::User;
 pub mod func;
 mod setting;
@@ -14,4 +15,5 @@ pub fn pages_router() -> Router {
         .merge(setting::setting_router())
         .merge(func::func_router())
         .merge(user::user_router())
+        .merge(notify::notify_router())
 }
@@ -0,0 +1,193 @@
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use mysql::{prelude::Queryable, PooledConn};
+use mysql_common::prelude::FromRow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    bearer,
+    database::get_db,
+    libs::{gen_id, TimeFormat, TIME},
+    log,
+    pages::account::get_user,
+    parse_jwt_macro,
+    perm::action::OtherGroup,
+    verify_perms, Response, ResponseResult,
+};
+
+pub fn notify_router() -> Router {
+    Router::new()
+        .route("/notify/list", post(query_notification))
+        .route("/notify/unread/count", get(query_unread_count))
+        .route("/notify/read/:id", post(read_notification))
+        .route("/notify/all/read", post(read_all_notification))
+        .route("/notify/delete/:id", delete(delete_notification))
+        .route("/notify/rule", get(get_notify_rule))
+        .route("/notify/rule/set", post(set_notify_rule))
+}
+
+/// 向用户的站内信箱发送一条通知
+pub fn __send_notification(
+    conn: &mut PooledConn,
+    receiver: &str,
+    ty: &str,
+    title: &str,
+    content: &str,
+    link: &str,
+) -> Result<(), Response> {
+    let time = TIME::now()?;
+    conn.exec_drop(
+        "INSERT INTO notification (id, receiver, ty, title, content, link, create_time, read_time)
+        VALUES (?, ?, ?, ?, ?, ?, ?, NULL)",
+        (
+            gen_id(&time, "notify"),
+            receiver,
+            ty,
+            title,
+            content,
+            link,
+            time.format(TimeFormat::YYYYMMDD_HHMMSS),
+        ),
+    )?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct QueryParams {
+    /// 只返回未读的通知
+    #[serde(default)]
+    unread: bool,
+    /// 轮询时传入上次收到的最新通知的时间，只返回之后的通知
+    #[serde(default)]
+    after: String,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    50
+}
+
+#[derive(Serialize, FromRow)]
+struct Notification {
+    id: String,
+    ty: String,
+    title: String,
+    content: String,
+    link: String,
+    create_time: String,
+    read_time: Option<String>,
+}
+
+async fn query_notification(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let params: QueryParams = serde_json::from_value(value)?;
+    let data: Vec<Notification> = conn.exec(
+        format!(
+            "SELECT id, ty, title, content, link, create_time, read_time FROM notification
+            WHERE receiver = ? AND create_time > ? {}
+            ORDER BY create_time DESC LIMIT {}",
+            op::ternary!(params.unread => "AND read_time IS NULL", ""),
+            params.limit
+        ),
+        (&uid, &params.after),
+    )?;
+    let unread: Option<usize> = conn.exec_first(
+        "SELECT COUNT(*) FROM notification WHERE receiver = ? AND read_time IS NULL",
+        (&uid,),
+    )?;
+    Ok(Response::ok(json!({
+        "unread": unread.unwrap_or(0),
+        "data": data
+    })))
+}
+
+async fn query_unread_count(header: HeaderMap) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let unread: Option<usize> = conn.exec_first(
+        "SELECT COUNT(*) FROM notification WHERE receiver = ? AND read_time IS NULL",
+        (&uid,),
+    )?;
+    Ok(Response::ok(json!(unread.unwrap_or(0))))
+}
+
+async fn read_notification(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let time = TIME::now()?.format(TimeFormat::YYYYMMDD_HHMMSS);
+    conn.exec_drop(
+        "UPDATE notification SET read_time = ?
+        WHERE id = ? AND receiver = ? AND read_time IS NULL LIMIT 1",
+        (&time, &id, &uid),
+    )?;
+    Ok(Response::empty())
+}
+
+async fn read_all_notification(header: HeaderMap) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let time = TIME::now()?.format(TimeFormat::YYYYMMDD_HHMMSS);
+    conn.exec_drop(
+        "UPDATE notification SET read_time = ? WHERE receiver = ? AND read_time IS NULL",
+        (&time, &uid),
+    )?;
+    Ok(Response::empty())
+}
+
+async fn delete_notification(header: HeaderMap, Path(id): Path<String>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    conn.exec_drop(
+        "DELETE FROM notification WHERE id = ? AND receiver = ? LIMIT 1",
+        (&id, &uid),
+    )?;
+    Ok(Response::empty())
+}
+
+async fn get_notify_rule() -> ResponseResult {
+    Ok(Response::ok(json!({
+        "offsets": crate::get_notify_offsets()
+    })))
+}
+
+#[derive(Deserialize)]
+struct NotifyRule {
+    /// 在拜访开始前多少分钟提醒
+    offsets: Vec<u64>,
+}
+
+async fn set_notify_rule(header: HeaderMap, Json(value): Json<Value>) -> ResponseResult {
+    let bearer = bearer!(&header);
+    let db = get_db().await?;
+    let mut conn = db.lock().await;
+    let uid = parse_jwt_macro!(&bearer, &mut conn => true);
+    let user = get_user(&uid, &mut conn).await?;
+    let rule: NotifyRule = serde_json::from_value(value)?;
+    if !verify_perms!(&user.role, OtherGroup::NAME, OtherGroup::NOTIFY_RULE) {
+        log!("{user} 设置拜访提醒失败，原因权限不足");
+        return Err(Response::permission_denied());
+    }
+    crate::set_notify_offsets(rule.offsets)?;
+    log!(
+        "{user} 已将拜访提醒设置为提前{:?}分钟",
+        crate::get_notify_offsets()
+    );
+    Ok(Response::empty())
+}
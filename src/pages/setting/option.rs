@@ -319,6 +319,14 @@ fn update_storehouse(conn: &mut PooledConn, old: &str, new: &str) -> mysql::Resu
         "update product_store set storehouse = ? where storehouse = ?",
         (new, old),
     )?;
+    conn.exec_drop(
+        "update stock_movement set storehouse = ? where storehouse = ?",
+        (new, old),
+    )?;
+    conn.exec_drop(
+        "update stock_lot set storehouse = ? where storehouse = ?",
+        (new, old),
+    )?;
     conn.exec_drop(
         "update order_data set shipped_storehouse = ? where shipped_storehouse = ?",
         (new, old),
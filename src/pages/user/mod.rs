@@ -42,9 +42,9 @@ async fn query_limit_user(
     let filter = if data.customer.is_empty() {
         String::new()
     } else {
-        format!("and exists (select 1 from extra_customer_data ex where ex.id='{}' and ex.salesman=u.id)", data.customer)
+        format!("and (exists (select 1 from extra_customer_data ex where ex.id='{0}' and ex.salesman=u.id)
+            or exists (select 1 from customer_share s where s.customer='{0}' and s.share_salesman=u.id))", data.customer)
     };
-    // TODO 后面需要考虑共享情况
     let users: Vec<User> = conn.query(format!(
         "select * from user u
         where NOT EXISTS (SELECT 1 FROM leaver l WHERE l.id=u.id) {filter}"
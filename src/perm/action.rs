@@ -72,7 +72,7 @@ impl CustomerGroup {
     pub const ADD_APPOINT: &str = "add_appoint";
 }
 #[forbid(unused)]
-pub static STOREHOUSE: [&str; 8] = [
+pub static STOREHOUSE: [&str; 12] = [
     StorehouseGroup::ACTIVATION,
     StorehouseGroup::ADD_PRODUCT,
     StorehouseGroup::UPDATE_PRODUCT,
@@ -81,6 +81,10 @@ pub static STOREHOUSE: [&str; 8] = [
     StorehouseGroup::ADD_STOREHOUSE,
     StorehouseGroup::DELETE_STOREHOUSE,
     StorehouseGroup::UPDATE_STOREHOUSE,
+    StorehouseGroup::STOCK_RULE,
+    StorehouseGroup::TRANSFER_STOCK,
+    StorehouseGroup::STOCKTAKE,
+    StorehouseGroup::PRICE_LIST,
 ];
 
 pub struct StorehouseGroup;
@@ -95,6 +99,14 @@ impl StorehouseGroup {
     pub const ADD_STOREHOUSE: &str = "add_storehouse";
     pub const DELETE_STOREHOUSE: &str = "delete_storehouse";
     pub const UPDATE_STOREHOUSE: &str = "update_storehouse";
+    /// 设置是否允许负库存
+    pub const STOCK_RULE: &str = "stock_rule";
+    /// 库房之间调拨库存
+    pub const TRANSFER_STOCK: &str = "transfer_stock";
+    /// 盘点库存
+    pub const STOCKTAKE: &str = "stocktake";
+    /// 设置价格表和价格规则
+    pub const PRICE_LIST: &str = "price_list";
     // TODO:
 }
 
@@ -119,13 +131,15 @@ impl FinanceGroup {
 }
 
 #[forbid(unused)]
-pub static OTHER_GROUP: [&str; 6] = [
+pub static OTHER_GROUP: [&str; 8] = [
     OtherGroup::QUERY_SIGN_IN,
     OtherGroup::CUSTOM_FIELD,
     OtherGroup::DROP_DOWN_BOX,
     OtherGroup::SEA_RULE,
     OtherGroup::COMPANY_STAFF_DATA,
     OtherGroup::QUERY_ORDER,
+    OtherGroup::NOTIFY_RULE,
+    OtherGroup::VISIT_DURATION,
 ];
 pub struct OtherGroup;
 
@@ -137,4 +151,8 @@ impl OtherGroup {
     pub const SEA_RULE: &str = "sea_rule";
     pub const COMPANY_STAFF_DATA: &str = "company_staff_data";
     pub const QUERY_ORDER: &str = "query_order";
+    /// 设置拜访提醒的时间
+    pub const NOTIFY_RULE: &str = "notify_rule";
+    /// 设置默认的拜访时长
+    pub const VISIT_DURATION: &str = "visit_duration";
 }
@@ -140,6 +140,8 @@ unsafe fn role_manager() -> PermissionGroupMap {
                 (OtherGroup::SEA_RULE, vec!["all".to_owned()]),
                 (OtherGroup::COMPANY_STAFF_DATA, vec!["all".to_owned()]),
                 (OtherGroup::QUERY_ORDER, vec!["all".to_owned()]),
+                (OtherGroup::NOTIFY_RULE, Vec::new()),
+                (OtherGroup::VISIT_DURATION, Vec::new()),
                 (OtherGroup::CUSTOM_FIELD, Vec::new()),
                 (OtherGroup::DROP_DOWN_BOX, Vec::new()),
             ]
@@ -103,6 +103,10 @@ impl Response {
     pub fn unknown_err(e: impl Display) -> Self {
         Self::new(StatusCode::OK, 9, json!(e.to_string()))
     }
+    /// 与已有的数据冲突，data为冲突的数据
+    pub fn conflict(data: Value) -> Self {
+        Self::new(StatusCode::OK, 10, data)
+    }
     pub fn code(&self) -> StatusCode {
         self.code
     }
@@ -184,6 +188,14 @@ impl BodyFile {
     pub fn new(body: Vec<u8>) -> Self {
         Self { body, ..Default::default() }
     }
+    /// 用于导出等直接在内存中生成的文件，filename需要为ASCII字符
+    pub fn with_name(body: Vec<u8>, filename: impl Into<String>, mime: &'static str) -> Self {
+        Self {
+            body,
+            filename: filename.into(),
+            mime,
+        }
+    }
     pub fn new_with_base64_url(
         parent: impl AsRef<Path>,
         url: &str,
@@ -216,6 +228,7 @@ impl BodyFile {
             match ext.to_string_lossy().as_ref() {
                 "jpeg" | "jpg" => "image/jpeg",
                 "png" => "image/png",
+                "pdf" => "application/pdf",
                 // 待定
                 _ => "image/png",
             }